@@ -107,8 +107,13 @@ class _AuthenticationPageState extends State<AuthenticationPage>
         throw Exception("Proof generation failed intentionally for demonstration.");
       }
 
-      final risc0ProofResult =
-          await _moproFlutterPlugin.generateRisc0Proof(combinedMessage);
+      // The demo does not talk to the KDC yet; a real client sends
+      // `kdcChallengeRequest()` and proves with the nonce it gets back, and
+      // with the public half of a fresh X25519 key it sends the proof with.
+      final nonce = Uint8List(32);
+      final replyKey = Uint8List(32);
+      final risc0ProofResult = await _moproFlutterPlugin.generateRisc0Proof(
+          combinedMessage, nonce, replyKey);
 
       if (!mounted) return;
       
@@ -319,9 +319,19 @@ class MoproFlutterPlugin : FlutterPlugin, MethodCallHandler {
                 "Missing message",
                 null
             )
+            val nonce = call.argument<ByteArray>("nonce") ?: return result.error(
+                "ARGUMENT_ERROR",
+                "Missing nonce",
+                null
+            )
+            val replyKey = call.argument<ByteArray>("replyKey") ?: return result.error(
+                "ARGUMENT_ERROR",
+                "Missing replyKey",
+                null
+            )
 
             try {
-                val res = risc0Prove(message)
+                val res = risc0Prove(message, nonce, replyKey)
                 val resultMap = mapOf(
                     "receipt" to res.receipt
                 )
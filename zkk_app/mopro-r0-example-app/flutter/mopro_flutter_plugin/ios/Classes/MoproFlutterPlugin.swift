@@ -312,14 +312,16 @@ public class MoproFlutterPlugin: NSObject, FlutterPlugin {
       }
     case "generateRisc0Proof":
       guard let args = call.arguments as? [String: Any],
-        let message = args["message"] as? String
+        let message = args["message"] as? String,
+        let nonce = args["nonce"] as? FlutterStandardTypedData,
+        let replyKey = args["replyKey"] as? FlutterStandardTypedData
       else {
         result(FlutterError(code: "ARGUMENT_ERROR", message: "Missing arguments", details: nil))
         return
       }
 
       do {
-        let proofResult = try risc0Prove(message: message)
+        let proofResult = try risc0Prove(message: message, nonce: nonce.data, replyKey: replyKey.data)
         let resultMap: [String: Any] = [
           "receipt": proofResult.receipt,
         ]
@@ -88,8 +88,11 @@ class MoproFlutter {
     });
   }
 
-  Future<Risc0ProofOutput> generateRisc0Proof(String message) async {
-    return await MoproFlutterPlatform.instance.generateRisc0Proof(message);
+  /// Proves [message] for the 32 byte [nonce] from a KDC challenge. The KDC
+  /// only seals its reply to [replyKey], the 32 byte X25519 public key sent
+  /// with the proof.
+  Future<Risc0ProofOutput> generateRisc0Proof(String message, Uint8List nonce, Uint8List replyKey) async {
+    return await MoproFlutterPlatform.instance.generateRisc0Proof(message, nonce, replyKey);
   }
 
   Future<Risc0VerifyOutput> verifyRisc0Proof(Uint8List receiptBytes) async {
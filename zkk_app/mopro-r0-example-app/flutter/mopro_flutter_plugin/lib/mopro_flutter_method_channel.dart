@@ -111,10 +111,13 @@ class MethodChannelMoproFlutter extends MoproFlutterPlatform {
   }
 
   @override
-  Future<Risc0ProofOutput> generateRisc0Proof(String message) async {
+  Future<Risc0ProofOutput> generateRisc0Proof(
+      String message, Uint8List nonce, Uint8List replyKey) async {
     final proofResult = await methodChannel
         .invokeMethod<Map<Object?, Object?>>('generateRisc0Proof', {
       'message': message,
+      'nonce': nonce,
+      'replyKey': replyKey,
     });
 
     if (proofResult == null) {
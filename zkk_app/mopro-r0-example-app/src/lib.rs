@@ -1,7 +1,9 @@
 #![allow(unexpected_cfgs)]
 
 use methods::{RISC0_CIRCUIT_ELF, RISC0_CIRCUIT_ID};
-use risc0_zkvm::{default_prover, ExecutorEnv, Receipt};
+use risc0_zkvm::{default_prover, ExecutorEnv};
+use zkk_protocol::frame::MAX_FRAME_LEN;
+use zkk_protocol::{AuthJournal, Challenge, ErrorReply, Frame, KdcError, MessageKind, MessageReceived, PowStamp};
 
 mopro_ffi::app!();
 
@@ -15,6 +17,17 @@ pub enum Risc0Error {
     VerifyError(String),
     #[error("Failed to decode journal: {0}")]
     DecodeError(String),
+    #[error("KDC rejected request (code {code}): {message}")]
+    KdcError { code: u16, message: String },
+}
+
+impl From<KdcError> for Risc0Error {
+    fn from(error: KdcError) -> Self {
+        Risc0Error::KdcError {
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
 }
 
 #[derive(uniffi::Record, Clone)]
@@ -22,20 +35,42 @@ pub struct Risc0ProofOutput {
     pub receipt: Vec<u8>,
 }
 
+/// A KDC challenge: the nonce to prove with and the proof-of-work difficulty
+/// the `AsReq` must meet.
+#[derive(uniffi::Record, Clone)]
+pub struct KdcChallenge {
+    pub nonce: Vec<u8>,
+    pub difficulty: u8,
+}
+
 #[derive(uniffi::Record, Clone)]
 pub struct Risc0VerifyOutput {
     pub is_valid: bool,
     pub verified_message: String,
 }
 
+/// Proves `message` for the `nonce` from a KDC challenge (see `kdc_read_challenge`).
+/// `reply_key` is the X25519 public key the KDC is to seal its reply to; the
+/// proof commits to it, so the `AsReq` must carry the same key.
 #[uniffi::export]
-pub fn risc0_prove(message: String) -> Result<Risc0ProofOutput, Risc0Error> {
+pub fn risc0_prove(message: String, nonce: Vec<u8>, reply_key: Vec<u8>) -> Result<Risc0ProofOutput, Risc0Error> {
 
     let message_bytes = message.as_bytes();
+    let nonce: [u8; 32] = nonce
+        .try_into()
+        .map_err(|n: Vec<u8>| Risc0Error::ProveError(format!("Nonce must be 32 bytes, got {}", n.len())))?;
+    let reply_key: [u8; 32] = reply_key
+        .try_into()
+        .map_err(|k: Vec<u8>| Risc0Error::ProveError(format!("Reply key must be 32 bytes, got {}", k.len())))?;
+    let reply_key_hash = zkk_protocol::crypto::reply_key_hash(&reply_key);
 
     let env = ExecutorEnv::builder()
         .write(&message_bytes)
         .map_err(|e| Risc0Error::ProveError(format!("Failed to write input: {}", e)))?
+        .write(&nonce)
+        .map_err(|e| Risc0Error::ProveError(format!("Failed to write nonce: {}", e)))?
+        .write(&reply_key_hash)
+        .map_err(|e| Risc0Error::ProveError(format!("Failed to write reply key hash: {}", e)))?
         .build()
         .map_err(|e| {
             Risc0Error::ProveError(format!("Failed to build executor environment: {}", e))
@@ -49,7 +84,7 @@ pub fn risc0_prove(message: String) -> Result<Risc0ProofOutput, Risc0Error> {
 
     let receipt = prove_info.receipt;
 
-    let receipt_bytes = bincode::serialize(&receipt)
+    let receipt_bytes = zkk_protocol::encode_receipt(&receipt)
         .map_err(|e| Risc0Error::SerializeError(format!("Failed to serialize receipt: {}", e)))?;
 
     Ok(Risc0ProofOutput {
@@ -61,20 +96,17 @@ pub fn risc0_prove(message: String) -> Result<Risc0ProofOutput, Risc0Error> {
 #[uniffi::export]
 pub fn risc0_verify(receipt_bytes: Vec<u8>) -> Result<Risc0VerifyOutput, Risc0Error> {
 
-    let receipt: Receipt = bincode::deserialize(&receipt_bytes)
+    let receipt = zkk_protocol::decode_receipt(&receipt_bytes)
         .map_err(|e| Risc0Error::SerializeError(format!("Failed to deserialize receipt: {}", e)))?;
 
     receipt
         .verify(RISC0_CIRCUIT_ID)
         .map_err(|e| Risc0Error::VerifyError(format!("Failed to verify receipt: {}", e)))?;
 
-    let output_value: Vec<u8> = receipt
-        .journal
-        .decode()
+    let journal = AuthJournal::from_receipt(&receipt)
         .map_err(|e| Risc0Error::DecodeError(format!("Failed to decode journal: {}", e)))?;
 
-    let verified_message = String::from_utf8(output_value)
-        .map_err(|e| Risc0Error::DecodeError(format!("Invalid UTF-8 in journal: {}", e)))?;
+    let verified_message = format!("{:?}", journal);
 
     Ok(Risc0VerifyOutput {
         is_valid: true,
@@ -83,3 +115,76 @@ pub fn risc0_verify(receipt_bytes: Vec<u8>) -> Result<Risc0VerifyOutput, Risc0Er
 }
 
 
+/// Request frame asking the KDC for a challenge nonce.
+#[uniffi::export]
+pub fn kdc_challenge_request() -> Result<Vec<u8>, Risc0Error> {
+    Frame::new(zkk_protocol::PROTOCOL_VERSION, MessageKind::ChallengeReq, &())
+        .map(|frame| frame.to_bytes())
+        .map_err(|e| Risc0Error::SerializeError(format!("Failed to encode request: {}", e)))
+}
+
+/// Unwraps the KDC's reply to `kdc_challenge_request`.
+#[uniffi::export]
+pub fn kdc_read_challenge(frame_bytes: Vec<u8>) -> Result<KdcChallenge, Risc0Error> {
+    let frame = Frame::read_from(&mut frame_bytes.as_slice(), MAX_FRAME_LEN)
+        .map_err(|e| Risc0Error::SerializeError(format!("Failed to read frame: {}", e)))?;
+
+    match frame.kind {
+        MessageKind::Challenge => {
+            let challenge: Challenge = frame
+                .body()
+                .map_err(|e| Risc0Error::SerializeError(format!("Failed to decode challenge: {}", e)))?;
+            Ok(KdcChallenge {
+                nonce: challenge.nonce.to_vec(),
+                difficulty: challenge.difficulty,
+            })
+        }
+        MessageKind::Error => Err(read_error(&frame)),
+        other => Err(Risc0Error::SerializeError(format!("Unexpected reply kind: {:?}", other))),
+    }
+}
+
+/// `AsReq` frame for a receipt from `risc0_prove`. Solves the challenge's
+/// proof of work first, which can take a few seconds at high difficulty.
+#[uniffi::export]
+pub fn kdc_as_request(receipt: Vec<u8>, reply_key: Vec<u8>, challenge: KdcChallenge) -> Result<Vec<u8>, Risc0Error> {
+    let proof = zkk_protocol::decode_receipt(&receipt)
+        .map_err(|e| Risc0Error::SerializeError(format!("Failed to deserialize receipt: {}", e)))?;
+    let reply_key: [u8; 32] = reply_key
+        .try_into()
+        .map_err(|k: Vec<u8>| Risc0Error::SerializeError(format!("Reply key must be 32 bytes, got {}", k.len())))?;
+    let nonce: [u8; 32] = challenge
+        .nonce
+        .try_into()
+        .map_err(|n: Vec<u8>| Risc0Error::SerializeError(format!("Nonce must be 32 bytes, got {}", n.len())))?;
+    let stamp = PowStamp::solve(&nonce, &reply_key, challenge.difficulty).ok_or_else(|| {
+        Risc0Error::SerializeError(format!("Refusing proof-of-work difficulty {}", challenge.difficulty))
+    })?;
+    let request = MessageReceived { reply_key, stamp, proof };
+    Frame::new(zkk_protocol::PROTOCOL_VERSION, MessageKind::AsReq, &request)
+        .map(|frame| frame.to_bytes())
+        .map_err(|e| Risc0Error::SerializeError(format!("Failed to encode request: {}", e)))
+}
+
+/// Unwraps a raw reply frame from the KDC into the encrypted ticket, or the
+/// server's error code.
+#[uniffi::export]
+pub fn kdc_read_reply(frame_bytes: Vec<u8>) -> Result<Vec<u8>, Risc0Error> {
+    let frame = Frame::read_from(&mut frame_bytes.as_slice(), MAX_FRAME_LEN)
+        .map_err(|e| Risc0Error::SerializeError(format!("Failed to read frame: {}", e)))?;
+
+    match frame.kind {
+        MessageKind::AsRep => frame
+            .body()
+            .map_err(|e| Risc0Error::SerializeError(format!("Failed to decode reply: {}", e))),
+        MessageKind::Error => Err(read_error(&frame)),
+        other => Err(Risc0Error::SerializeError(format!("Unexpected reply kind: {:?}", other))),
+    }
+}
+
+fn read_error(frame: &Frame) -> Risc0Error {
+    match frame.body::<ErrorReply>() {
+        Ok(reply) => reply.signature_data.error.into(),
+        Err(e) => Risc0Error::SerializeError(format!("Failed to decode error: {}", e)),
+    }
+}
@@ -1,11 +1,17 @@
 #![no_main]
 use risc0_zkvm::guest::env;
 use sha2::{Sha256, Digest};
+use zkk_protocol::AuthJournal;
 
 #[no_mangle]
 fn main() {
 
     let input : Vec<u8> = env::read();
+    // Server challenge; committing it makes the receipt single-use.
+    let nonce: [u8; 32] = env::read();
+    // Hash of the key the KDC is to seal its reply to; committing it stops
+    // anyone who sees the receipt from pairing it with a key of their own.
+    let reply_key_hash: [u8; 32] = env::read();
     
     if input.len() < 29 {
         eprintln!("Invalid input! Needs at least 29 bytes.");
@@ -41,8 +47,6 @@ fn main() {
         .windows(target_hash_hex.len())
         .any(|window| window == target_hash_hex);
 
-    let hash_exists_bytes = if hash_exists { [1u8; 1] } else { [0u8; 1] };
-
 
     let mut hasher = Sha256::new();
     hasher.update(&file_data);
@@ -53,6 +57,13 @@ fn main() {
     hasher.update(service_id.as_bytes());
     let id_hash: [u8; 32] = hasher.finalize().into();
 
-    env::commit(&(hash_exists_bytes, file_data_hash, id_hash, pass_hash));
+    env::commit(&AuthJournal {
+        existence: hash_exists as u8,
+        db_hash: file_data_hash,
+        id_hash,
+        pass_hash,
+        nonce,
+        reply_key_hash,
+    });
 
 }
\ No newline at end of file
@@ -1,42 +1,20 @@
 
-mod keys;
-
 use methods::{RISC0_CIRCUIT_ELF, RISC0_CIRCUIT_ID};
 use risc0_zkvm::{default_prover, ExecutorEnv};
 use risc0_zkvm::Receipt;
-use rsa::Pkcs1v15Encrypt;
-use std::net::TcpStream;
-use bincode;
-use serde::{Serialize, Deserialize};
-
-
-use std::fs::File;
-use std::io::Write;
+use zkk_protocol::crypto::ReplyKey;
+use zkk_protocol::frame::{Hello, HelloAck, MAX_FRAME_LEN};
+use zkk_protocol::{
+    AsReply, Authenticator, Challenge, ErrorReply, Frame, MessageKind, MessageReceived, PowStamp, TgsReply,
+    TgsRequest, DEFAULT_CLOCK_SKEW,
+};
 
-use log::{info, debug};
 
+use log::debug;
 
-#[derive(Debug, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
-struct MessageReceived {
-    #[bincode(with_serde)]
-    u_pk: rsa::RsaPublicKey,
-    #[bincode(with_serde)]
-    proof: Receipt,
-}
+use tls::KdcStream;
 
-#[derive(Debug, bincode::Encode, bincode::Decode)]
-struct MessageSent {
-    signature: [u8; 64],
-    signature_data: SignBundle,
-}
-
-#[derive(Debug, bincode::Encode, bincode::Decode)]
-struct SignBundle {
-    ssk: String,
-    pass_hash: [u8; 32],
-    comb_hash: [u8; 32],
-    timestamp: u64,
-}
+mod tls;
 
 
 fn main() {
@@ -45,7 +23,7 @@ fn main() {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let (private_key, public_key) = keys::generate_rsa_keypair().expect("Failed to generate RSA key pair");
+    let reply_key = ReplyKey::generate();
 
     
     let input = b"1234567890password12session456";
@@ -55,77 +33,169 @@ fn main() {
 
     println!("{:?}", RISC0_CIRCUIT_ID);
 
-    let mut receipt;
-    let receipt_path  = format!("./receipt_{}.bin", service_id_str);
-
-    if std::path::Path::new(&receipt_path).exists() {
-        println!("Loading existing proof from {}", receipt_path);
-        receipt = load_receipt(&receipt_path).expect("failed to load receipt");
-        println!("Loaded receipt: {:?}", receipt);
-        if let Err(_) = receipt.verify(RISC0_CIRCUIT_ID) {
-            println!("Saved proof didn't successfully verify; regenerating proof");
-            receipt = authenticate_user(input.to_vec());
-            save_receipt(&receipt, &receipt_path).expect("failed to save receipt");
-        }
-    } else {
-        println!("No existing proof found, generating a new one");
-        receipt = authenticate_user(input.to_vec());
-        save_receipt(&receipt, &receipt_path).expect("failed to save receipt");
-    }
+    let addr = "127.0.0.1:7878";
 
+    // Receipts are bound to a server nonce and accepted once, so there is
+    // nothing worth caching between runs. Proving outlasts the server's idle
+    // timeout, so the proof goes out on a second connection.
+    let challenge = {
+        let (mut stream, version) = connect(addr);
+        request_challenge(&mut stream, version)
+    };
+    println!(
+        "Received challenge for {}, valid until {}, difficulty {}",
+        service_id_str, challenge.expires_at, challenge.difficulty
+    );
+
+    let receipt = authenticate_user(
+        input.to_vec(),
+        challenge.nonce,
+        zkk_protocol::crypto::reply_key_hash(&reply_key.public_key()),
+    );
+
+    // The stamp covers the reply key, so it is solved once that is fixed.
+    let stamp = PowStamp::solve(&challenge.nonce, &reply_key.public_key(), challenge.difficulty)
+        .expect("server asked for more proof of work than a client will do");
+
+    let (mut stream, version) = connect(addr);
 
     let m = MessageReceived{
-        u_pk: public_key,
+        reply_key: reply_key.public_key(),
+        stamp,
         proof: receipt,
     };
 
-    let addr = "127.0.0.1:7878";
-    let mut stream = TcpStream::connect(addr).expect("failed to connect");
-    bincode::encode_into_std_write(m, &mut stream, bincode::config::standard()).expect("failed to serialize");
+    Frame::new(version, MessageKind::AsReq, &m)
+        .expect("failed to serialize")
+        .write_to(&mut stream)
+        .expect("failed to send proof");
     println!("Sent proof to {}", addr);
 
-    let res = bincode::decode_from_std_read::<Vec<u8>, _, _>(&mut stream, bincode::config::standard()).expect("failed to read/deserialize");
+    let reply = Frame::read_from(&mut stream, MAX_FRAME_LEN).expect("failed to read response");
+    let res: Vec<u8> = match reply.kind {
+        MessageKind::AsRep => reply.body().expect("failed to deserialize response"),
+        MessageKind::Error => exit_with_kdc_error(&reply),
+        other => panic!("unexpected response kind: {:?}", other),
+    };
 
     println!("Received response: {:?}", res);
-    if let Err(e) = stream.shutdown(std::net::Shutdown::Both) {
+
+    let plaintext = reply_key.open(&res).expect("Failed to open response");
+    let as_reply: AsReply = zkk_protocol::decode(&plaintext)
+        .expect("Failed to deserialize decrypted response");
+    println!("Received ticket-granting ticket valid until {}", as_reply.expires_at);
+
+    // Every further service ticket comes from the TGT; no new proof needed
+    // until it expires.
+    let reply = request_service_ticket(&mut stream, version, &as_reply, &service_id_str);
+    println!("Decrypted response: {:?}", reply.ticket);
+    // `reply.session_key` is what the service recovers from the ticket; the
+    // client uses it to talk to the service.
+
+    let ticket = &reply.ticket.signature_data;
+    match ticket.check_validity(now(), DEFAULT_CLOCK_SKEW) {
+        Ok(()) => println!("Ticket for {} valid until {}", ticket.service, ticket.expires_at),
+        Err(e) => eprintln!("Received an unusable ticket: {}", e),
+    }
+
+    if let Err(e) = stream.shutdown() {
         eprintln!("Failed to disconnect: {}", e);
     } else {
         println!("Disconnected from {}", addr);
     }
+}
 
-    let plaintext = private_key
-        .decrypt(Pkcs1v15Encrypt, &res)
-        .expect("Failed to decrypt response");
-    let (response, _): (MessageSent, _) =
-        bincode::decode_from_slice(&plaintext, bincode::config::standard())
-            .expect("Failed to deserialize decrypted response");
-    
-    println!("Decrypted response: {:?}", response);
+/// Trades the TGT for a ticket to `service`.
+fn request_service_ticket(stream: &mut KdcStream, version: u16, tgt: &AsReply, service: &str) -> TgsReply {
+    let request = TgsRequest {
+        tgt: tgt.tgt.clone(),
+        service: service.to_string(),
+        authenticator: Authenticator::new(&tgt.session_key, &tgt.tgt, service, now()),
+    };
+    Frame::new(version, MessageKind::TgsReq, &request)
+        .expect("failed to serialize TGS request")
+        .write_to(stream)
+        .expect("failed to send TGS request");
+
+    let reply = Frame::read_from(stream, MAX_FRAME_LEN).expect("failed to read TGS reply");
+    let sealed: Vec<u8> = match reply.kind {
+        MessageKind::TgsRep => reply.body().expect("failed to deserialize TGS reply"),
+        MessageKind::Error => exit_with_kdc_error(&reply),
+        other => panic!("unexpected reply to TGS request: {:?}", other),
+    };
+    let plaintext = zkk_protocol::crypto::open(&tgt.session_key, &sealed).expect("Failed to open TGS reply");
+    zkk_protocol::decode(&plaintext).expect("Failed to deserialize TGS reply")
 }
 
-pub fn authenticate_user(input: Vec<u8>) -> Receipt{
-    let env = ExecutorEnv::builder()
-        .write(&input).unwrap()
-        .build().unwrap();
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
 
-    let prover = default_prover();    
-    let receipt = prover.prove(env, RISC0_CIRCUIT_ELF).unwrap().receipt;
+fn connect(addr: &str) -> (KdcStream, u16) {
+    let mut stream = KdcStream::connect(addr).expect("failed to connect");
+    let version = negotiate_version(&mut stream);
+    println!("Negotiated protocol version {}", version);
+    (stream, version)
+}
 
-    receipt
+/// Opens the connection with a `Hello` and returns the version the server picked.
+fn negotiate_version(stream: &mut KdcStream) -> u16 {
+    Frame::new(zkk_protocol::PROTOCOL_VERSION, MessageKind::Hello, &Hello::supported())
+        .expect("failed to serialize hello")
+        .write_to(stream)
+        .expect("failed to send hello");
+
+    let reply = Frame::read_from(stream, MAX_FRAME_LEN).expect("failed to read hello reply");
+    match reply.kind {
+        MessageKind::Error => exit_with_kdc_error(&reply),
+        MessageKind::HelloAck => {
+            let ack: HelloAck = reply.body().expect("failed to deserialize hello ack");
+            ack.version
+        }
+        MessageKind::VersionRejected => {
+            let server: Hello = reply.body().expect("failed to deserialize server versions");
+            panic!("server only speaks protocol versions {}..={}", server.min_version, server.max_version);
+        }
+        other => panic!("unexpected reply to hello: {:?}", other),
+    }
 }
 
+/// Asks for the nonce the next proof must commit to.
+fn request_challenge(stream: &mut KdcStream, version: u16) -> Challenge {
+    Frame::new(version, MessageKind::ChallengeReq, &())
+        .expect("failed to serialize challenge request")
+        .write_to(stream)
+        .expect("failed to send challenge request");
+
+    let reply = Frame::read_from(stream, MAX_FRAME_LEN).expect("failed to read challenge");
+    match reply.kind {
+        MessageKind::Challenge => reply.body().expect("failed to deserialize challenge"),
+        MessageKind::Error => exit_with_kdc_error(&reply),
+        other => panic!("unexpected reply to challenge request: {:?}", other),
+    }
+}
 
-pub fn save_receipt(receipt: &Receipt, path: &str) -> anyhow::Result<()> {
-    let encoded = bincode::serde::encode_to_vec(receipt, bincode::config::standard())?; // compact binary encoding
-    let mut file = File::create(path)?;
-    file.write_all(&encoded)?;
-    Ok(())
+/// Prints the server's error code and exits with it.
+fn exit_with_kdc_error(reply: &Frame) -> ! {
+    let error: ErrorReply = reply.body().expect("failed to deserialize error reply");
+    let error = error.signature_data.error;
+    eprintln!("Server rejected request: {} (code {})", error, error.code());
+    std::process::exit(error.code() as i32);
 }
 
+pub fn authenticate_user(input: Vec<u8>, nonce: [u8; 32], reply_key_hash: [u8; 32]) -> Receipt{
+    let env = ExecutorEnv::builder()
+        .write(&input).unwrap()
+        .write(&nonce).unwrap()
+        .write(&reply_key_hash).unwrap()
+        .build().unwrap();
 
-pub fn load_receipt(path: &str) -> anyhow::Result<Receipt> {
-    let data = std::fs::read(path)?;
-    let (receipt,_) = bincode::serde::decode_from_slice(&data, bincode::config::standard())?;
-    Ok(receipt)
+    let prover = default_prover();    
+    let receipt = prover.prove(env, RISC0_CIRCUIT_ELF).unwrap().receipt;
+
+    receipt
 }
 
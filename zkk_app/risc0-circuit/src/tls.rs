@@ -0,0 +1,269 @@
+//! Connection to the KDC, over TLS when the environment asks for it.
+//!
+//! - `ZKK_TLS_CA`: PEM file of the only CA to trust, e.g. the `ca.pem` from
+//!   `zkk_server tls dev-certs`. The certificate must also name the host.
+//! - `ZKK_TLS_PIN`: hex sha256 of the one server certificate to accept, as
+//!   printed by `dev-certs` and logged by the server at startup. On its own
+//!   it replaces CA and name checks.
+//!
+//! With neither set the connection is plaintext.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned};
+use sha2::{Digest, Sha256};
+
+pub enum KdcStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl KdcStream {
+    pub fn connect(addr: &str) -> anyhow::Result<Self> {
+        let config = client_config()?;
+        let stream = TcpStream::connect(addr).with_context(|| format!("failed to connect to {}", addr))?;
+        let Some(config) = config else {
+            return Ok(KdcStream::Plain(stream));
+        };
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+            .with_context(|| format!("{} is not a valid TLS server name", host))?;
+        let connection = ClientConnection::new(config, name)?;
+        Ok(KdcStream::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    /// Ends the session, with a TLS close_notify first if there is one.
+    pub fn shutdown(&mut self) -> std::io::Result<()> {
+        let stream = match self {
+            KdcStream::Plain(stream) => stream,
+            KdcStream::Tls(tls) => {
+                tls.conn.send_close_notify();
+                tls.flush()?;
+                &mut tls.sock
+            }
+        };
+        stream.shutdown(std::net::Shutdown::Both)
+    }
+}
+
+impl Read for KdcStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            KdcStream::Plain(stream) => stream.read(buf),
+            KdcStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for KdcStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            KdcStream::Plain(stream) => stream.write(buf),
+            KdcStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            KdcStream::Plain(stream) => stream.flush(),
+            KdcStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+fn client_config() -> anyhow::Result<Option<Arc<ClientConfig>>> {
+    let ca = std::env::var_os("ZKK_TLS_CA").map(PathBuf::from);
+    let pin = std::env::var("ZKK_TLS_PIN").ok();
+    config_from(ca.as_deref(), pin.as_deref())
+}
+
+/// The TLS configuration for a `ZKK_TLS_CA` of `ca` and a `ZKK_TLS_PIN` of `pin`.
+fn config_from(ca: Option<&Path>, pin: Option<&str>) -> anyhow::Result<Option<Arc<ClientConfig>>> {
+    if ca.is_none() && pin.is_none() {
+        return Ok(None);
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let ca_verifier = ca.map(|path| ca_verifier(path, provider.clone())).transpose()?;
+    let builder = ClientConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+    let config = match pin {
+        Some(pin) => {
+            let mut digest = [0u8; 32];
+            hex::decode_to_slice(pin.trim(), &mut digest).context("ZKK_TLS_PIN must be 64 hex digits")?;
+            let verifier = PinnedVerifier {
+                digest,
+                ca: ca_verifier,
+                provider,
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        }
+        None => builder
+            .with_webpki_verifier(ca_verifier.expect("ZKK_TLS_CA is set"))
+            .with_no_client_auth(),
+    };
+    Ok(Some(Arc::new(config)))
+}
+
+/// Trusts only the CA certificates in the PEM file at `path`.
+fn ca_verifier(path: &Path, provider: Arc<CryptoProvider>) -> anyhow::Result<Arc<WebPkiServerVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path).with_context(|| format!("failed to read {}", path.display()))? {
+        roots.add(cert?)?;
+    }
+    if roots.is_empty() {
+        bail!("no CA certificate in {}", path.display());
+    }
+    Ok(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?)
+}
+
+/// Accepts only the certificate hashing to `digest`, and only if `ca`, when
+/// set, accepts it too.
+#[derive(Debug)]
+struct PinnedVerifier {
+    digest: [u8; 32],
+    ca: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if <[u8; 32]>::from(Sha256::digest(end_entity)) != self.digest {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        match &self.ca {
+            Some(ca) => ca.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+
+    use super::*;
+
+    /// A CA and a `localhost` certificate it signed, with the CA's PEM.
+    fn ca_and_server() -> (String, CertificateDer<'static>) {
+        let mut ca_params = CertificateParams::default();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        let params = CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        let cert = params.signed_by(&KeyPair::generate().unwrap(), &ca).unwrap();
+        (ca.pem(), cert.der().clone())
+    }
+
+    fn self_signed() -> CertificateDer<'static> {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap().cert.der().clone()
+    }
+
+    fn verify(verifier: &dyn ServerCertVerifier, cert: &CertificateDer<'_>) -> Result<ServerCertVerified, rustls::Error> {
+        let name = ServerName::try_from("localhost").unwrap();
+        verifier.verify_server_cert(cert, &[], &name, &[], UnixTime::now())
+    }
+
+    fn provider() -> Arc<CryptoProvider> {
+        Arc::new(rustls::crypto::ring::default_provider())
+    }
+
+    #[test]
+    fn ca_file_is_the_only_root_trusted() {
+        let (ca, server) = ca_and_server();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, ca).unwrap();
+        let verifier = ca_verifier(&path, provider()).unwrap();
+        assert!(verify(verifier.as_ref(), &server).is_ok());
+        assert!(verify(verifier.as_ref(), &self_signed()).is_err());
+        let other = ServerName::try_from("kdc.example").unwrap();
+        assert!(verifier.verify_server_cert(&server, &[], &other, &[], UnixTime::now()).is_err());
+
+        assert!(config_from(Some(&path), None).unwrap().is_some());
+        assert!(config_from(None, None).unwrap().is_none());
+        std::fs::write(&path, "").unwrap();
+        let error = config_from(Some(&path), None).unwrap_err();
+        assert!(error.to_string().contains("no CA certificate"), "{:#}", error);
+        assert!(config_from(Some(&dir.path().join("missing.pem")), None).is_err());
+    }
+
+    #[test]
+    fn pin_accepts_only_the_pinned_certificate() {
+        let pinned = self_signed();
+        let verifier = PinnedVerifier {
+            digest: Sha256::digest(&pinned).into(),
+            ca: None,
+            provider: provider(),
+        };
+        assert!(verify(&verifier, &pinned).is_ok());
+        assert!(matches!(
+            verify(&verifier, &self_signed()),
+            Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure))
+        ));
+
+        let pin = hex::encode(Sha256::digest(&pinned));
+        assert!(config_from(None, Some(&pin)).unwrap().is_some());
+        assert!(config_from(None, Some("not hex")).is_err());
+        assert!(config_from(None, Some(&pin[..62])).is_err());
+    }
+
+    #[test]
+    fn pin_with_a_ca_needs_both() {
+        let (ca, server) = ca_and_server();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, ca).unwrap();
+        let pinned = |cert: &CertificateDer<'_>| PinnedVerifier {
+            digest: Sha256::digest(cert).into(),
+            ca: Some(ca_verifier(&path, provider()).unwrap()),
+            provider: provider(),
+        };
+        assert!(verify(&pinned(&server), &server).is_ok());
+        // Pinned but not from the CA, and from the CA but not pinned.
+        let other = self_signed();
+        assert!(verify(&pinned(&other), &other).is_err());
+        let (_, other_server) = ca_and_server();
+        assert!(verify(&pinned(&other_server), &server).is_err());
+    }
+}
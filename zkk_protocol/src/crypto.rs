@@ -0,0 +1,218 @@
+//! Primitives both ends of the AS and TGS exchanges must agree on.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+const NONCE_LEN: usize = 12;
+const PUBLIC_KEY_LEN: usize = 32;
+const SEAL_TO_INFO: &[u8] = b"zkk seal_to v1";
+const REPLY_KEY_LABEL: &[u8] = b"zkk reply key v1";
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("sealed data failed authentication")]
+pub struct OpenError;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("recipient key is a low-order X25519 point")]
+pub struct BadPublicKey;
+
+/// ChaCha20-Poly1305 under `key` with a random nonce; returns `nonce || ciphertext`.
+pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .expect("ChaCha20-Poly1305 encryption is infallible for in-memory buffers");
+    let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    out
+}
+
+/// Reverses [`seal`].
+pub fn open(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, OpenError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(OpenError);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| OpenError)
+}
+
+/// Seals `plaintext` so only the holder of the X25519 secret for `recipient`
+/// can open it: a fresh ephemeral key agrees a secret with `recipient`, HKDF
+/// turns it into a [`seal`] key. Returns `ephemeral public key || sealed`.
+pub fn seal_to(recipient: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, BadPublicKey> {
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*recipient));
+    if !shared.was_contributory() {
+        return Err(BadPublicKey);
+    }
+    let key = seal_to_key(shared.as_bytes(), &ephemeral_public, recipient);
+    let mut out = ephemeral_public.to_vec();
+    out.extend_from_slice(&seal(&key, plaintext));
+    Ok(out)
+}
+
+fn seal_to_key(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> [u8; 32] {
+    let mut salt = [0u8; 2 * PUBLIC_KEY_LEN];
+    salt[..PUBLIC_KEY_LEN].copy_from_slice(ephemeral);
+    salt[PUBLIC_KEY_LEN..].copy_from_slice(recipient);
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(SEAL_TO_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// The client's X25519 key for one `AsReq`. Its public half goes in
+/// `MessageReceived::reply_key`; [`ReplyKey::open`] reverses [`seal_to`].
+pub struct ReplyKey(StaticSecret);
+
+impl ReplyKey {
+    pub fn generate() -> Self {
+        ReplyKey(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.0).to_bytes()
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, OpenError> {
+        if sealed.len() < PUBLIC_KEY_LEN {
+            return Err(OpenError);
+        }
+        let (ephemeral, sealed) = sealed.split_at(PUBLIC_KEY_LEN);
+        let ephemeral: [u8; 32] = ephemeral.try_into().expect("split at PUBLIC_KEY_LEN");
+        let shared = self.0.diffie_hellman(&PublicKey::from(ephemeral));
+        if !shared.was_contributory() {
+            return Err(OpenError);
+        }
+        open(&seal_to_key(shared.as_bytes(), &ephemeral, &self.public_key()), sealed)
+    }
+}
+
+/// What the guest commits for `MessageReceived::reply_key`, so the KDC only
+/// seals the `AsReply` to the key the prover chose.
+pub fn reply_key_hash(public_key: &[u8; 32]) -> [u8; 32] {
+    use sha2::Digest;
+
+    let mut hasher = Sha256::new();
+    hasher.update(REPLY_KEY_LABEL);
+    hasher.update(public_key);
+    hasher.finalize().into()
+}
+
+impl std::fmt::Debug for ReplyKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReplyKey(..)")
+    }
+}
+
+/// HMAC-SHA256 over length-prefixed `parts`, so no two part lists collide.
+pub fn mac(key: &[u8; 32], label: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(label.as_bytes());
+    for part in parts {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Constant-time comparison of two MACs.
+pub fn mac_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [1; 32];
+
+    #[test]
+    fn seal_round_trips() {
+        for plaintext in [&b""[..], b"ticket", &[7; 4096]] {
+            let sealed = seal(&KEY, plaintext);
+            assert_eq!(sealed.len(), NONCE_LEN + plaintext.len() + 16);
+            assert_eq!(open(&KEY, &sealed).unwrap(), plaintext);
+        }
+        // A fresh nonce every time.
+        assert_ne!(seal(&KEY, b"ticket"), seal(&KEY, b"ticket"));
+    }
+
+    #[test]
+    fn tampered_or_misdirected_seal_is_refused() {
+        let sealed = seal(&KEY, b"ticket");
+        for i in [0, NONCE_LEN, sealed.len() - 1] {
+            let mut altered = sealed.clone();
+            altered[i] ^= 1;
+            assert_eq!(open(&KEY, &altered), Err(OpenError), "byte {}", i);
+        }
+        assert_eq!(open(&[2; 32], &sealed), Err(OpenError));
+        assert_eq!(open(&KEY, &sealed[..sealed.len() - 1]), Err(OpenError));
+        assert_eq!(open(&KEY, &sealed[..NONCE_LEN - 1]), Err(OpenError));
+    }
+
+    #[test]
+    fn seal_to_opens_with_the_reply_key() {
+        let key = ReplyKey::generate();
+        let sealed = seal_to(&key.public_key(), b"as reply").unwrap();
+        assert_eq!(key.open(&sealed).unwrap(), b"as reply");
+        // A fresh ephemeral key every time.
+        assert_ne!(sealed[..PUBLIC_KEY_LEN], seal_to(&key.public_key(), b"as reply").unwrap()[..PUBLIC_KEY_LEN]);
+    }
+
+    #[test]
+    fn tampered_or_misdirected_seal_to_is_refused() {
+        let key = ReplyKey::generate();
+        let sealed = seal_to(&key.public_key(), b"as reply").unwrap();
+        for i in [0, PUBLIC_KEY_LEN, PUBLIC_KEY_LEN + NONCE_LEN, sealed.len() - 1] {
+            let mut altered = sealed.clone();
+            altered[i] ^= 1;
+            assert_eq!(key.open(&altered), Err(OpenError), "byte {}", i);
+        }
+        assert_eq!(ReplyKey::generate().open(&sealed), Err(OpenError));
+        assert_eq!(key.open(&sealed[..PUBLIC_KEY_LEN - 1]), Err(OpenError));
+        // The all-zero point would make the shared secret known to anyone.
+        assert_eq!(seal_to(&[0; 32], b"as reply"), Err(BadPublicKey));
+        let mut low_order = sealed;
+        low_order[..PUBLIC_KEY_LEN].fill(0);
+        assert_eq!(key.open(&low_order), Err(OpenError));
+    }
+
+    /// Clients, guests and the KDC are built separately; they must agree on this.
+    #[test]
+    fn reply_key_hash_is_stable() {
+        let hash: String = reply_key_hash(&[9; 32]).iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hash, "abdfdbbbb3b98838e972f774a8d77f0e13044fe5bc702a471b4f2d0b2cfe987c");
+    }
+
+    /// The hash the guest commits for the client's key is the one the KDC
+    /// recomputes from the `AsReq`, and no other key's.
+    #[cfg(feature = "receipt")]
+    #[test]
+    fn journal_reply_key_hash_matches_the_client_key() {
+        let key = ReplyKey::generate();
+        let journal = crate::AuthJournal {
+            existence: 1,
+            db_hash: [1; 32],
+            id_hash: [2; 32],
+            pass_hash: [3; 32],
+            nonce: [4; 32],
+            reply_key_hash: reply_key_hash(&key.public_key()),
+        };
+        let committed = risc0_zkvm::serde::to_vec(&journal).unwrap();
+        let journal: crate::AuthJournal = risc0_zkvm::serde::from_slice(&committed).unwrap();
+        assert_eq!(journal.reply_key_hash, reply_key_hash(&key.public_key()));
+        assert_ne!(journal.reply_key_hash, reply_key_hash(&ReplyKey::generate().public_key()));
+        assert_ne!(journal.reply_key_hash, key.public_key());
+    }
+}
@@ -0,0 +1,208 @@
+//! Errors the KDC reports back to a client instead of dropping the connection,
+//! and errors from checking a ticket.
+
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+
+/// Why the KDC refused a request.
+///
+/// On the wire this is just [`KdcError::code`], so a client built against an
+/// older crate still decodes codes it does not know as [`KdcError::Other`].
+/// Codes are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum KdcError {
+    #[error("malformed frame or message body")]
+    BadFraming,
+    #[error("unsupported protocol version")]
+    UnsupportedVersion,
+    #[error("message kind not expected at this point")]
+    UnexpectedMessage,
+    #[error("proof failed verification")]
+    ProofInvalid,
+    #[error("proof was produced by a guest image the server does not accept")]
+    WrongImageId,
+    #[error("credential not found in database")]
+    CredentialNotFound,
+    #[error("proof was made against a different credential database")]
+    DbHashMismatch,
+    #[error("internal server error")]
+    Internal,
+    #[error("server is at capacity, retry later")]
+    Busy,
+    #[error("proof is bound to a nonce the server never issued")]
+    NonceUnknown,
+    #[error("challenge nonce has expired, request a new one")]
+    NonceExpired,
+    #[error("challenge nonce was already used")]
+    NonceReplayed,
+    #[error("ticket-granting ticket is malformed or was not issued by this KDC")]
+    TicketInvalid,
+    #[error("ticket-granting ticket has expired, authenticate again")]
+    TicketExpired,
+    #[error("authenticator is forged or outside the allowed clock skew")]
+    AuthenticatorInvalid,
+    #[error("authenticator was already used")]
+    AuthenticatorReplayed,
+    #[error("no such service is registered with the KDC")]
+    UnknownService,
+    #[error("proof was not made for the reply key sent with it")]
+    ReplyKeyMismatch,
+    #[error("proof-of-work stamp does not meet the challenge difficulty")]
+    PowInvalid,
+    #[error("too many requests from this address, retry later")]
+    RateLimited,
+    #[error("receipt kind or size is not accepted by this server")]
+    UnsupportedReceipt,
+    #[error("proof journal does not match the layout of its guest image")]
+    BadJournal,
+    #[error("unknown error code {0}")]
+    Other(u16),
+}
+
+impl KdcError {
+    pub fn code(&self) -> u16 {
+        match self {
+            KdcError::BadFraming => 1,
+            KdcError::UnsupportedVersion => 2,
+            KdcError::UnexpectedMessage => 3,
+            KdcError::ProofInvalid => 4,
+            KdcError::WrongImageId => 5,
+            KdcError::CredentialNotFound => 6,
+            KdcError::DbHashMismatch => 7,
+            KdcError::Internal => 8,
+            KdcError::Busy => 9,
+            KdcError::NonceUnknown => 10,
+            KdcError::NonceExpired => 11,
+            KdcError::NonceReplayed => 12,
+            KdcError::TicketInvalid => 13,
+            KdcError::TicketExpired => 14,
+            KdcError::AuthenticatorInvalid => 15,
+            KdcError::AuthenticatorReplayed => 16,
+            KdcError::UnknownService => 17,
+            KdcError::ReplyKeyMismatch => 18,
+            KdcError::PowInvalid => 19,
+            KdcError::RateLimited => 20,
+            KdcError::UnsupportedReceipt => 21,
+            KdcError::BadJournal => 22,
+            KdcError::Other(code) => *code,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1 => KdcError::BadFraming,
+            2 => KdcError::UnsupportedVersion,
+            3 => KdcError::UnexpectedMessage,
+            4 => KdcError::ProofInvalid,
+            5 => KdcError::WrongImageId,
+            6 => KdcError::CredentialNotFound,
+            7 => KdcError::DbHashMismatch,
+            8 => KdcError::Internal,
+            9 => KdcError::Busy,
+            10 => KdcError::NonceUnknown,
+            11 => KdcError::NonceExpired,
+            12 => KdcError::NonceReplayed,
+            13 => KdcError::TicketInvalid,
+            14 => KdcError::TicketExpired,
+            15 => KdcError::AuthenticatorInvalid,
+            16 => KdcError::AuthenticatorReplayed,
+            17 => KdcError::UnknownService,
+            18 => KdcError::ReplyKeyMismatch,
+            19 => KdcError::PowInvalid,
+            20 => KdcError::RateLimited,
+            21 => KdcError::UnsupportedReceipt,
+            22 => KdcError::BadJournal,
+            other => KdcError::Other(other),
+        }
+    }
+}
+
+impl From<crate::FrameError> for KdcError {
+    fn from(err: crate::FrameError) -> Self {
+        match err {
+            crate::FrameError::UnsupportedVersion(_) => KdcError::UnsupportedVersion,
+            crate::FrameError::UnknownKind(_) => KdcError::UnexpectedMessage,
+            _ => KdcError::BadFraming,
+        }
+    }
+}
+
+impl Encode for KdcError {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.code().encode(encoder)
+    }
+}
+
+impl<Context> Decode<Context> for KdcError {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(KdcError::from_code(u16::decode(decoder)?))
+    }
+}
+
+bincode::impl_borrow_decode!(KdcError);
+
+/// The signed part of an [`ErrorReply`].
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct ErrorBundle {
+    pub key_id: crate::messages::KeyId,
+    pub error: KdcError,
+    pub timestamp: u64,
+}
+
+impl ErrorBundle {
+    /// The exact bytes the server signs.
+    pub fn signing_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        crate::encode(self)
+    }
+}
+
+/// Body of a [`crate::MessageKind::Error`] frame, signed with the same key as tickets.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct ErrorReply {
+    pub signature: [u8; 64],
+    pub signature_data: ErrorBundle,
+}
+
+/// Why a ticket is not valid at the time it was checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TicketError {
+    #[error("ticket is not valid before {not_before} (now {now})")]
+    NotYetValid { not_before: u64, now: u64 },
+    #[error("ticket expired at {expires_at} (now {now})")]
+    Expired { expires_at: u64, now: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(error: KdcError) -> ErrorReply {
+        ErrorReply {
+            signature: [7; 64],
+            signature_data: ErrorBundle { key_id: 2, error, timestamp: 1_792_281_600 },
+        }
+    }
+
+    #[test]
+    fn error_reply_round_trips() {
+        for code in 1..=22 {
+            let reply = reply(KdcError::from_code(code));
+            let decoded: ErrorReply = crate::decode(&crate::encode(&reply).unwrap()).unwrap();
+            assert_eq!(decoded, reply);
+            assert_eq!(decoded.signature_data.error.code(), code);
+            assert!(!matches!(decoded.signature_data.error, KdcError::Other(_)), "code {}", code);
+        }
+    }
+
+    #[test]
+    fn unknown_code_decodes_as_other() {
+        let bytes = crate::encode(&reply(KdcError::Other(999))).unwrap();
+        let decoded: ErrorReply = crate::decode(&bytes).unwrap();
+        assert_eq!(decoded.signature_data.error, KdcError::Other(999));
+        assert_eq!(decoded.signature_data.error.code(), 999);
+        // Still signed over the same bytes, so a client can check it.
+        assert_eq!(decoded.signature_data.signing_bytes().unwrap(), reply(KdcError::Other(999)).signature_data.signing_bytes().unwrap());
+    }
+}
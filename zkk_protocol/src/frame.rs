@@ -0,0 +1,325 @@
+//! Framing for the KDC TCP protocol.
+//!
+//! Every message is preceded by a fixed 11 byte header:
+//!
+//! ```text
+//! +-------+---------+------+--------+----------------+
+//! | magic | version | kind | length | payload ...    |
+//! |  4 B  |  u16 BE | u8   | u32 BE | `length` bytes |
+//! +-------+---------+------+--------+----------------+
+//! ```
+//!
+//! The payload is the bincode encoding (see [`crate::config`]) of the body
+//! belonging to `kind`. A client may open with [`MessageKind::Hello`] to learn
+//! which version the server speaks before sending anything else. `Hello` and
+//! `VersionRejected` are read at any header version and their bodies never
+//! change, so two peers with no version in common can still say so.
+//!
+//! Authentication takes two rounds: `ChallengeReq` -> `Challenge`, then the
+//! client proves with the challenge nonce and sends `AsReq` -> `AsRep` to get
+//! a ticket-granting ticket. Each `TgsReq` -> `TgsRep` then trades the TGT
+//! for a ticket to one service without proving again.
+
+use std::io::{Read, Write};
+
+use bincode::error::{DecodeError, EncodeError};
+
+use crate::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+pub const MAGIC: [u8; 4] = *b"ZKKB";
+pub const HEADER_LEN: usize = 11;
+/// Default upper bound on a payload; a composite receipt is well below this.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    /// Client -> server, body [`Hello`].
+    Hello = 0,
+    /// Server -> client, body [`HelloAck`].
+    HelloAck = 1,
+    /// Server -> client, body [`Hello`] with the range the server supports.
+    VersionRejected = 2,
+    /// Client -> server, body [`crate::MessageReceived`] proving with a nonce from [`MessageKind::Challenge`].
+    AsReq = 3,
+    /// Server -> client, body `Vec<u8>`: an [`crate::AsReply`] encrypted to the client key.
+    AsRep = 4,
+    /// Server -> client, body [`crate::ErrorReply`]. Always the last frame on a connection.
+    Error = 5,
+    /// Client -> server, empty body `()`.
+    ChallengeReq = 6,
+    /// Server -> client, body [`crate::Challenge`].
+    Challenge = 7,
+    /// Client -> server, body [`crate::TgsRequest`].
+    TgsReq = 8,
+    /// Server -> client, body `Vec<u8>`: a [`crate::TgsReply`] sealed under the TGT session key.
+    TgsRep = 9,
+    /// Client -> server, empty body `()`.
+    KeysReq = 10,
+    /// Server -> client, body [`crate::KeySetReply`].
+    Keys = 11,
+}
+
+impl MessageKind {
+    /// `Hello` and `VersionRejected`, which are valid at every version.
+    pub fn is_negotiation(self) -> bool {
+        matches!(self, MessageKind::Hello | MessageKind::VersionRejected)
+    }
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = FrameError;
+
+    fn try_from(value: u8) -> Result<Self, FrameError> {
+        Ok(match value {
+            0 => MessageKind::Hello,
+            1 => MessageKind::HelloAck,
+            2 => MessageKind::VersionRejected,
+            3 => MessageKind::AsReq,
+            4 => MessageKind::AsRep,
+            5 => MessageKind::Error,
+            6 => MessageKind::ChallengeReq,
+            7 => MessageKind::Challenge,
+            8 => MessageKind::TgsReq,
+            9 => MessageKind::TgsRep,
+            10 => MessageKind::KeysReq,
+            11 => MessageKind::Keys,
+            other => return Err(FrameError::UnknownKind(other)),
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("bad magic {0:02x?}")]
+    BadMagic([u8; 4]),
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
+    #[error("unknown message kind {0}")]
+    UnknownKind(u8),
+    #[error("frame of {len} bytes exceeds limit of {max}")]
+    TooLarge { len: u32, max: u32 },
+    #[error("malformed body: {0}")]
+    Body(#[from] DecodeError),
+}
+
+/// Range of protocol versions a peer is willing to speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+}
+
+impl Hello {
+    /// The range this build of the crate supports.
+    pub const fn supported() -> Self {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Highest version both `self` and `peer` speak, if any.
+    pub fn negotiate(&self, peer: &Hello) -> Option<u16> {
+        let low = self.min_version.max(peer.min_version);
+        let high = self.max_version.min(peer.max_version);
+        (low <= high).then_some(high)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct HelloAck {
+    pub version: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u16,
+    pub kind: MessageKind,
+    pub len: u32,
+}
+
+impl FrameHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[0..4].copy_from_slice(&MAGIC);
+        out[4..6].copy_from_slice(&self.version.to_be_bytes());
+        out[6] = self.kind as u8;
+        out[7..11].copy_from_slice(&self.len.to_be_bytes());
+        out
+    }
+
+    /// Validates a raw header. `max_len` bounds the payload the caller is willing to buffer.
+    pub fn parse(bytes: &[u8; HEADER_LEN], max_len: u32) -> Result<Self, FrameError> {
+        let magic: [u8; 4] = bytes[0..4].try_into().expect("4 bytes");
+        if magic != MAGIC {
+            return Err(FrameError::BadMagic(magic));
+        }
+        let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+        let kind = MessageKind::try_from(bytes[6])?;
+        if !kind.is_negotiation() && !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let len = u32::from_be_bytes(bytes[7..11].try_into().expect("4 bytes"));
+        if len > max_len {
+            return Err(FrameError::TooLarge { len, max: max_len });
+        }
+        Ok(FrameHeader { version, kind, len })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub version: u16,
+    pub kind: MessageKind,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new<T: bincode::Encode>(version: u16, kind: MessageKind, body: &T) -> Result<Self, EncodeError> {
+        Ok(Frame {
+            version,
+            kind,
+            payload: crate::encode(body)?,
+        })
+    }
+
+    pub fn header(&self) -> FrameHeader {
+        FrameHeader {
+            version: self.version,
+            kind: self.kind,
+            len: self.payload.len() as u32,
+        }
+    }
+
+    pub fn body<T: bincode::Decode<()>>(&self) -> Result<T, DecodeError> {
+        crate::decode(&self.payload)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        out.extend_from_slice(&self.header().to_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.to_bytes())?;
+        writer.flush()
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R, max_len: u32) -> Result<Self, FrameError> {
+        let mut raw = [0u8; HEADER_LEN];
+        reader.read_exact(&mut raw)?;
+        let header = FrameHeader::parse(&raw, max_len)?;
+        let mut payload = vec![0u8; header.len as usize];
+        reader.read_exact(&mut payload)?;
+        Ok(Frame {
+            version: header.version,
+            kind: header.kind,
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn header(version: u16, kind: MessageKind) -> [u8; HEADER_LEN] {
+        FrameHeader { version, kind, len: 0 }.to_bytes()
+    }
+
+    #[test]
+    fn negotiation_is_read_at_any_version() {
+        for version in [0, 1, MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1, u16::MAX] {
+            for kind in [MessageKind::Hello, MessageKind::VersionRejected] {
+                let parsed = FrameHeader::parse(&header(version, kind), MAX_FRAME_LEN).unwrap();
+                assert_eq!((parsed.version, parsed.kind), (version, kind));
+            }
+            assert!(matches!(
+                FrameHeader::parse(&header(version, MessageKind::ChallengeReq), MAX_FRAME_LEN),
+                Err(FrameError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            FrameHeader::parse(&header(version, MessageKind::ChallengeReq), MAX_FRAME_LEN).unwrap();
+        }
+    }
+
+    #[test]
+    fn negotiate_picks_highest_common_version() {
+        let server = Hello { min_version: 9, max_version: 10 };
+        assert_eq!(server.negotiate(&Hello { min_version: 5, max_version: 9 }), Some(9));
+        assert_eq!(server.negotiate(&Hello { min_version: 9, max_version: 12 }), Some(10));
+        assert_eq!(server.negotiate(&Hello { min_version: 3, max_version: 8 }), None);
+        assert_eq!(server.negotiate(&Hello { min_version: 11, max_version: 12 }), None);
+    }
+
+    fn frame() -> impl Strategy<Value = Frame> {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION, 0u8..12, prop::collection::vec(any::<u8>(), 0..512)).prop_map(
+            |(version, kind, payload)| Frame {
+                version,
+                kind: MessageKind::try_from(kind).unwrap(),
+                payload,
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn read_from_survives_garbage(bytes in prop::collection::vec(any::<u8>(), 0..64), max_len in any::<u32>()) {
+            if let Ok(frame) = Frame::read_from(&mut bytes.as_slice(), max_len) {
+                prop_assert!(frame.payload.len() as u64 <= max_len as u64);
+                prop_assert_eq!(&frame.to_bytes()[..], &bytes[..HEADER_LEN + frame.payload.len()]);
+            }
+        }
+
+        #[test]
+        fn read_from_round_trips(frame in frame(), trailing in prop::collection::vec(any::<u8>(), 0..16)) {
+            let mut bytes = frame.to_bytes();
+            bytes.extend_from_slice(&trailing);
+            let mut reader = bytes.as_slice();
+            prop_assert_eq!(Frame::read_from(&mut reader, MAX_FRAME_LEN).unwrap(), frame);
+            prop_assert_eq!(reader, &trailing[..]);
+        }
+
+        #[test]
+        fn read_from_rejects_truncated(frame in frame(), cut in any::<prop::sample::Index>()) {
+            let bytes = frame.to_bytes();
+            let truncated = &bytes[..cut.index(bytes.len())];
+            prop_assert!(matches!(
+                Frame::read_from(&mut &truncated[..], MAX_FRAME_LEN),
+                Err(FrameError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+            ));
+        }
+
+        /// Refused from the header alone: nothing after it is read or allocated.
+        #[test]
+        fn read_from_rejects_oversized(max_len in 0..MAX_FRAME_LEN, excess in 1..=u32::MAX) {
+            let len = max_len.saturating_add(excess);
+            prop_assume!(len > max_len);
+            let header = FrameHeader { version: PROTOCOL_VERSION, kind: MessageKind::AsReq, len }.to_bytes();
+            prop_assert!(matches!(
+                Frame::read_from(&mut &header[..], max_len),
+                Err(FrameError::TooLarge { len: l, max }) if l == len && max == max_len
+            ), "{} byte frame accepted", len);
+        }
+
+        #[test]
+        fn parse_rejects_bad_magic(mut header in any::<[u8; HEADER_LEN]>()) {
+            prop_assume!(header[..4] != MAGIC);
+            prop_assert!(matches!(FrameHeader::parse(&header, MAX_FRAME_LEN), Err(FrameError::BadMagic(_))));
+            header[..4].copy_from_slice(&MAGIC);
+            match FrameHeader::parse(&header, u32::MAX) {
+                Ok(parsed) => prop_assert_eq!(parsed.to_bytes(), header),
+                Err(FrameError::UnsupportedVersion(_) | FrameError::UnknownKind(_)) => {}
+                Err(e) => prop_assert!(false, "unexpected error {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Public output committed by the guest in `methods/guest/src/main.rs`.
+
+use serde::{Deserialize, Serialize};
+
+/// The guest journal, in commit order.
+///
+/// The guest commits this struct directly. risc0 serializes a struct field by
+/// field, so the layout is the field order below. Changing it needs a new
+/// [`JournalSchema`], so the KDC can keep accepting images built before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthJournal {
+    /// `1` if the credential hash was found in the database, `0` otherwise.
+    pub existence: u8,
+    /// SHA-256 of the credential database the guest searched.
+    pub db_hash: [u8; 32],
+    /// SHA-256 of `user_id || service_id`.
+    pub id_hash: [u8; 32],
+    /// SHA-256 of `password || service_id`.
+    pub pass_hash: [u8; 32],
+    /// The [`crate::Challenge`] nonce the proof was made for.
+    pub nonce: [u8; 32],
+    /// Hash of the key the `AsReply` must be sealed to, see `crypto::reply_key_hash`.
+    pub reply_key_hash: [u8; 32],
+}
+
+impl AuthJournal {
+    pub fn exists(&self) -> bool {
+        self.existence == 1
+    }
+
+    /// Decodes the journal of a receipt from the guest in this tree.
+    #[cfg(feature = "receipt")]
+    pub fn from_receipt(receipt: &risc0_zkvm::Receipt) -> Result<Self, risc0_zkvm::serde::Error> {
+        JournalSchema::CURRENT.decode(receipt)
+    }
+}
+
+/// Journal layout a guest image commits. The KDC is told the schema of each
+/// image ID it accepts, since the journal itself does not say.
+///
+/// Every layout decodes to the current [`AuthJournal`]; a new one gets a
+/// variant here whose decoder converts from what that guest commits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalSchema {
+    /// [`AuthJournal`] as above, with the challenge nonce and reply key hash.
+    V1,
+}
+
+impl JournalSchema {
+    /// The layout the guest in this tree commits.
+    pub const CURRENT: Self = JournalSchema::V1;
+
+    pub fn version(self) -> u16 {
+        match self {
+            JournalSchema::V1 => 1,
+        }
+    }
+
+    /// `None` for versions this build cannot decode.
+    pub fn from_version(version: u16) -> Option<Self> {
+        match version {
+            1 => Some(JournalSchema::V1),
+            _ => None,
+        }
+    }
+
+    /// Refuses a journal that is not whole words, which risc0's decoder
+    /// would panic on rather than return an error for.
+    #[cfg(feature = "receipt")]
+    pub fn decode(self, receipt: &risc0_zkvm::Receipt) -> Result<AuthJournal, risc0_zkvm::serde::Error> {
+        if !receipt.journal.bytes.len().is_multiple_of(4) {
+            return Err(risc0_zkvm::serde::Error::DeserializeUnexpectedEnd);
+        }
+        match self {
+            JournalSchema::V1 => receipt.journal.decode(),
+        }
+    }
+}
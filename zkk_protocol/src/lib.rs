@@ -0,0 +1,257 @@
+//! Wire types shared by the zk-kerberos server, the RISC0 host, the mopro FFI and the web client.
+//!
+//! Everything that crosses a process boundary lives here so both ends are compiled
+//! against the same definitions and the same bincode configuration.
+
+#[cfg(feature = "crypto")]
+pub mod crypto;
+pub mod error;
+pub mod frame;
+pub mod journal;
+pub mod messages;
+#[cfg(feature = "crypto")]
+pub mod pow;
+
+pub use error::{ErrorReply, KdcError, TicketError};
+pub use frame::{Frame, FrameError, MessageKind};
+pub use journal::{AuthJournal, JournalSchema};
+pub use messages::{
+    AsReply, AsReqHead, Authenticator, Challenge, KeyId, KeySetBundle, KeySetReply, MessageSent, PowStamp, PublishedKey,
+    SignBundle, TgsReply, TgsRequest, DEFAULT_CLOCK_SKEW,
+};
+#[cfg(feature = "receipt")]
+pub use messages::MessageReceived;
+
+use bincode::config::{Limit, LittleEndian, Varint};
+use bincode::error::{DecodeError, EncodeError};
+
+/// Bumped whenever the protocol changes. Since version 10 a `Hello` sent
+/// with any header version is answered, so a peer outside the range still
+/// learns it.
+pub const PROTOCOL_VERSION: u16 = 11;
+/// Oldest version the current build still serves. Only raised when peers of
+/// the older version can no longer be answered in their own encoding, or
+/// only unsafely. Version 1 had no challenge round, so its receipts could be
+/// replayed; version 2 tickets had no expiry; version 3 returned a service
+/// ticket straight from the proof; version 4 signatures did not say which
+/// key made them; version 5 tickets carried their session key in the clear;
+/// version 6 encrypted the `AsReply` with RSA PKCS#1 v1.5; version 7 proofs
+/// did not commit to the key the reply was sealed to; version 8 let a client
+/// have any receipt verified without spending work first; version 9
+/// and 10 tickets carried the user's password hash, and the same
+/// `comb_hash` to every service.
+pub const MIN_PROTOCOL_VERSION: u16 = 11;
+
+/// Most any one decode may allocate for the containers it reads, whatever
+/// lengths the input claims. Only reached by payloads above 4 MiB; smaller
+/// ones get a limit scaled to their length, see [`decode`].
+pub const DECODE_LIMIT: usize = 4 * frame::MAX_FRAME_LEN as usize;
+
+/// What one payload byte may decode to: a one byte varint can fill a `u64`.
+const DECODE_EXPANSION: usize = 8;
+
+/// The bincode configuration every peer must use. The limit only applies
+/// when decoding; the encoding is plain `standard()`.
+pub type Config = bincode::config::Configuration<LittleEndian, Varint, Limit<DECODE_LIMIT>>;
+
+pub const fn config() -> Config {
+    bincode::config::standard().with_limit::<DECODE_LIMIT>()
+}
+
+/// Evaluates `$decode` with `$config` limited to [`DECODE_EXPANSION`] times
+/// the smallest size class holding `$len` bytes, so a short payload cannot
+/// claim a long container. The limit is part of the configuration's type,
+/// hence the fixed classes.
+macro_rules! with_decode_limit {
+    ($len:expr, |$config:ident| $decode:expr) => {{
+        const KIB: usize = 1024;
+        match $len {
+            len if len <= 4 * KIB => {
+                let $config = bincode::config::standard().with_limit::<{ $crate::DECODE_EXPANSION * 4 * KIB }>();
+                $decode
+            }
+            len if len <= 64 * KIB => {
+                let $config = bincode::config::standard().with_limit::<{ $crate::DECODE_EXPANSION * 64 * KIB }>();
+                $decode
+            }
+            len if len <= 1024 * KIB => {
+                let $config = bincode::config::standard().with_limit::<{ $crate::DECODE_EXPANSION * 1024 * KIB }>();
+                $decode
+            }
+            len if len <= 4096 * KIB => {
+                let $config = bincode::config::standard().with_limit::<{ $crate::DECODE_EXPANSION * 4096 * KIB }>();
+                $decode
+            }
+            _ => {
+                let $config = $crate::config();
+                $decode
+            }
+        }
+    }};
+}
+pub(crate) use with_decode_limit;
+
+pub fn encode<T: bincode::Encode>(value: &T) -> Result<Vec<u8>, EncodeError> {
+    bincode::encode_to_vec(value, config())
+}
+
+/// Decodes `bytes` as a single `T`, rejecting trailing garbage. Allocates at
+/// most a small multiple of `bytes.len()`, whatever lengths the bytes claim.
+pub fn decode<T: bincode::Decode<()>>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let (value, read) = with_decode_limit!(bytes.len(), |config| bincode::decode_from_slice(bytes, config))?;
+    check_consumed(bytes, read)?;
+    Ok(value)
+}
+
+fn check_consumed(bytes: &[u8], read: usize) -> Result<(), DecodeError> {
+    if read != bytes.len() {
+        return Err(DecodeError::OtherString(format!(
+            "{} trailing bytes after message",
+            bytes.len() - read
+        )));
+    }
+    Ok(())
+}
+
+/// Encoding used for receipts stored on disk or handed across the FFI.
+#[cfg(feature = "receipt")]
+pub fn encode_receipt(receipt: &risc0_zkvm::Receipt) -> Result<Vec<u8>, EncodeError> {
+    bincode::serde::encode_to_vec(receipt, config())
+}
+
+/// Decodes what [`encode_receipt`] wrote, with the same checks as [`decode`].
+#[cfg(feature = "receipt")]
+pub fn decode_receipt(bytes: &[u8]) -> Result<risc0_zkvm::Receipt, DecodeError> {
+    let (receipt, read) = with_decode_limit!(bytes.len(), |config| bincode::serde::decode_from_slice(bytes, config))?;
+    check_consumed(bytes, read)?;
+    Ok(receipt)
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::error::DecodeError;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// A bincode varint length prefix using its widest form for `len`.
+    fn length_prefix(len: u64) -> Vec<u8> {
+        let mut out = vec![253];
+        out.extend_from_slice(&len.to_le_bytes());
+        out
+    }
+
+    fn limit_exceeded<T: std::fmt::Debug>(result: Result<T, DecodeError>) -> bool {
+        matches!(result, Err(DecodeError::LimitExceeded))
+    }
+
+    #[test]
+    fn length_prefix_is_checked_against_payload() {
+        // A short payload claiming a long container fails before allocating it.
+        for len in [1 << 16, 1 << 24, u32::MAX as u64, u64::MAX] {
+            assert!(limit_exceeded(decode::<Vec<u8>>(&length_prefix(len))), "{}", len);
+            assert!(limit_exceeded(decode::<String>(&length_prefix(len))), "{}", len);
+            assert!(limit_exceeded(decode::<TgsRequest>(&length_prefix(len))), "{}", len);
+        }
+        // Within the limit the claim is fine, the bytes are just not there.
+        assert!(matches!(decode::<Vec<u8>>(&length_prefix(100)), Err(DecodeError::UnexpectedEnd { .. })));
+    }
+
+    #[test]
+    fn nested_length_prefixes_share_the_limit() {
+        // Each prefix alone is within the limit for this payload, together they are not.
+        let mut bytes = length_prefix(1 << 10);
+        bytes.extend(length_prefix(1 << 10));
+        assert!(matches!(decode::<Vec<u8>>(&length_prefix(1 << 10)), Err(DecodeError::UnexpectedEnd { .. })));
+        assert!(limit_exceeded(decode::<Vec<Vec<Vec<u8>>>>(&bytes)));
+        bytes.truncate(9);
+        bytes.extend(length_prefix(1 << 12));
+        assert!(limit_exceeded(decode::<Vec<Vec<u64>>>(&bytes)));
+    }
+
+    #[test]
+    fn decode_rejects_trailing_bytes() {
+        let mut bytes = encode(&Challenge { nonce: [7; 32], expires_at: 1, difficulty: 8 }).unwrap();
+        decode::<Challenge>(&bytes).unwrap();
+        bytes.push(0);
+        assert!(matches!(decode::<Challenge>(&bytes), Err(DecodeError::OtherString(_))));
+    }
+
+    #[cfg(feature = "receipt")]
+    #[test]
+    fn decode_receipt_rejects_trailing_bytes() {
+        use risc0_zkvm::sha::Digest;
+        use risc0_zkvm::{FakeReceipt, InnerReceipt, MaybePruned, Receipt};
+
+        let inner = InnerReceipt::Fake(FakeReceipt::new(MaybePruned::Pruned(Digest::ZERO)));
+        let receipt = Receipt::new(inner, vec![1, 2, 3]);
+        let mut bytes = encode_receipt(&receipt).unwrap();
+        assert_eq!(decode_receipt(&bytes).unwrap().journal.bytes, vec![1, 2, 3]);
+        bytes.push(0);
+        assert!(matches!(decode_receipt(&bytes), Err(DecodeError::OtherString(_))));
+    }
+
+    proptest! {
+        #[test]
+        fn decode_survives_garbage(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let _ = decode::<TgsRequest>(&bytes);
+            let _ = decode::<TgsReply>(&bytes);
+            let _ = decode::<AsReply>(&bytes);
+            let _ = decode::<KeySetReply>(&bytes);
+            let _ = decode::<ErrorReply>(&bytes);
+            let _ = decode::<frame::Hello>(&bytes);
+            let _ = decode::<Vec<Vec<u8>>>(&bytes);
+            let _ = AsReqHead::peek(&bytes);
+            #[cfg(feature = "receipt")]
+            {
+                let _ = decode::<MessageReceived>(&bytes);
+                let _ = decode_receipt(&bytes);
+            }
+        }
+
+        /// Valid messages with any one length field replaced.
+        #[test]
+        fn decode_survives_corrupt_lengths(
+            tgt in prop::collection::vec(any::<u8>(), 0..64),
+            service in "[a-z]{0,16}",
+            len in any::<u32>(),
+            at in any::<prop::sample::Index>(),
+        ) {
+            let request = TgsRequest {
+                tgt,
+                service,
+                authenticator: Authenticator { timestamp: 1, mac: [3; 32] },
+            };
+            let bytes = encode(&request).unwrap();
+            prop_assert_eq!(decode::<TgsRequest>(&bytes).unwrap(), request);
+            let at = at.index(bytes.len());
+            let mut corrupt = bytes[..at].to_vec();
+            corrupt.push(252);
+            corrupt.extend_from_slice(&len.to_le_bytes());
+            corrupt.extend_from_slice(&bytes[at..]);
+            let _ = decode::<TgsRequest>(&corrupt);
+        }
+
+        #[cfg(feature = "receipt")]
+        #[test]
+        fn decode_receipt_survives_corrupt_lengths(
+            journal in prop::collection::vec(any::<u8>(), 0..64),
+            len in any::<u32>(),
+            at in any::<prop::sample::Index>(),
+        ) {
+            use risc0_zkvm::sha::Digest;
+            use risc0_zkvm::{FakeReceipt, InnerReceipt, MaybePruned, Receipt};
+
+            let inner = InnerReceipt::Fake(FakeReceipt::new(MaybePruned::Pruned(Digest::ZERO)));
+            let bytes = encode_receipt(&Receipt::new(inner, journal.clone())).unwrap();
+            prop_assert_eq!(decode_receipt(&bytes).unwrap().journal.bytes, journal);
+            let at = at.index(bytes.len());
+            let mut corrupt = bytes[..at].to_vec();
+            corrupt.push(252);
+            corrupt.extend_from_slice(&len.to_le_bytes());
+            corrupt.extend_from_slice(&bytes[at..]);
+            let _ = decode_receipt(&corrupt);
+        }
+    }
+}
+
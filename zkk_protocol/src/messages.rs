@@ -0,0 +1,388 @@
+//! Request and response bodies exchanged between the client and the KDC.
+
+use crate::error::TicketError;
+
+/// Identifies one of the KDC's signing keys. Assigned in increasing order.
+pub type KeyId = u32;
+
+/// Clock difference tolerated between the KDC and whoever checks a ticket, in seconds.
+pub const DEFAULT_CLOCK_SKEW: u64 = 5 * 60;
+
+/// A single-use nonce the client must commit to in its proof.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct Challenge {
+    pub nonce: [u8; 32],
+    /// Unix seconds after which the server no longer accepts proofs for this nonce.
+    pub expires_at: u64,
+    /// Leading zero bits the [`PowStamp`] for this nonce must have. Set by the
+    /// server from its load when the challenge is issued.
+    pub difficulty: u8,
+}
+
+/// Hashcash-style stamp over a challenge nonce and a reply key (see `pow`),
+/// so every receipt the KDC verifies cost its sender some work first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, bincode::Encode, bincode::Decode)]
+pub struct PowStamp {
+    /// The [`Challenge`] nonce, which the proof must also commit to.
+    pub nonce: [u8; 32],
+    pub counter: u64,
+}
+
+/// The fields in front of the receipt in a [`MessageReceived`]. The KDC
+/// decodes and checks them before it touches the receipt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct AsReqHead {
+    pub reply_key: [u8; 32],
+    pub stamp: PowStamp,
+}
+
+impl AsReqHead {
+    /// Decodes the head of an `AsReq` body, leaving the rest unread.
+    pub fn peek(body: &[u8]) -> Result<Self, bincode::error::DecodeError> {
+        crate::with_decode_limit!(body.len(), |config| bincode::decode_from_slice(body, config)).map(|(head, _)| head)
+    }
+}
+
+/// Sent by the client: the proof plus the key the response should be sealed to.
+/// Starts with the fields of [`AsReqHead`], in the same order.
+#[cfg(feature = "receipt")]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, bincode::Encode, bincode::Decode)]
+pub struct MessageReceived {
+    /// X25519 public key, fresh for this request (see `crypto::ReplyKey`).
+    pub reply_key: [u8; 32],
+    pub stamp: PowStamp,
+    #[bincode(with_serde)]
+    pub proof: risc0_zkvm::Receipt,
+}
+
+/// The AS stage's answer to a proof, sealed to `MessageReceived::reply_key`
+/// with `crypto::seal_to`.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct AsReply {
+    /// Ticket-granting ticket, sealed under a key only the KDC holds. Opaque to the client.
+    pub tgt: Vec<u8>,
+    /// Shared with the KDC through the TGT. Authenticates [`TgsRequest`]s and
+    /// seals their replies.
+    pub session_key: [u8; 32],
+    /// When the TGT stops being accepted, Unix seconds.
+    pub expires_at: u64,
+}
+
+/// Asks for a ticket to `service` without proving again.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct TgsRequest {
+    pub tgt: Vec<u8>,
+    /// Service the ticket is requested for; picks the lifetime policy and becomes the audience.
+    pub service: String,
+    pub authenticator: Authenticator,
+}
+
+/// Proof that the sender of a [`TgsRequest`] holds the TGT session key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct Authenticator {
+    /// Sender's clock, Unix seconds. The KDC only accepts it within its clock skew.
+    pub timestamp: u64,
+    /// HMAC-SHA256 under the session key over the TGT, service and timestamp.
+    pub mac: [u8; 32],
+}
+
+#[cfg(feature = "crypto")]
+impl Authenticator {
+    const LABEL: &'static str = "zkk tgs authenticator v1";
+
+    pub fn new(session_key: &[u8; 32], tgt: &[u8], service: &str, timestamp: u64) -> Self {
+        Authenticator {
+            timestamp,
+            mac: Self::compute(session_key, tgt, service, timestamp),
+        }
+    }
+
+    pub fn verify(&self, session_key: &[u8; 32], tgt: &[u8], service: &str) -> bool {
+        crate::crypto::mac_eq(&self.mac, &Self::compute(session_key, tgt, service, self.timestamp))
+    }
+
+    fn compute(session_key: &[u8; 32], tgt: &[u8], service: &str, timestamp: u64) -> [u8; 32] {
+        crate::crypto::mac(session_key, Self::LABEL, &[tgt, service.as_bytes(), &timestamp.to_be_bytes()])
+    }
+}
+
+/// Body of a [`crate::MessageKind::TgsRep`], sealed under the TGT session key.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct TgsReply {
+    /// Presented to the service as is.
+    pub ticket: MessageSent,
+    /// The client's copy of the ticket's session key. Fresh for every ticket.
+    pub session_key: [u8; 32],
+}
+
+/// A service ticket, signed by the KDC.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct MessageSent {
+    pub signature: [u8; 64],
+    pub signature_data: SignBundle,
+}
+
+/// The ticket body. `MessageSent::signature` is over its bincode encoding.
+///
+/// All times are Unix seconds.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct SignBundle {
+    /// Key the ticket is signed with, so verifiers can pick it from the KDC's key set.
+    pub key_id: KeyId,
+    /// The ticket's session key, sealed under the service's long-term key, so
+    /// only the service can read it. The client gets its copy in [`TgsReply`].
+    pub sealed_session_key: Vec<u8>,
+    /// Names the user to this service only: HMAC-SHA256 of the user's
+    /// `comb_hash` under the service's long-term key. Stable across the
+    /// user's tickets to one service, unrelated between services.
+    pub pseudonym: [u8; 32],
+    /// Service the ticket was issued for.
+    pub service: String,
+    /// When the ticket was issued.
+    pub timestamp: u64,
+    /// The ticket is not valid before this time.
+    pub not_before: u64,
+    /// The ticket is not valid from this time on.
+    pub expires_at: u64,
+}
+
+impl SignBundle {
+    /// The exact bytes the server signs.
+    pub fn signing_bytes(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        crate::encode(self)
+    }
+
+    /// Checks `now` against the validity window, allowing the clocks of
+    /// issuer and verifier to differ by up to `skew` seconds either way.
+    pub fn check_validity(&self, now: u64, skew: u64) -> Result<(), TicketError> {
+        if now.saturating_add(skew) < self.not_before {
+            return Err(TicketError::NotYetValid {
+                not_before: self.not_before,
+                now,
+            });
+        }
+        if now.saturating_sub(skew) >= self.expires_at {
+            return Err(TicketError::Expired {
+                expires_at: self.expires_at,
+                now,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// One ticket signing key as published by the KDC. Times are Unix seconds.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct PublishedKey {
+    pub key_id: KeyId,
+    /// Ed25519 public key.
+    pub public_key: [u8; 32],
+    /// When the KDC starts signing with it. Later than `KeySetBundle::issued_at`
+    /// for a key announced ahead of a rotation.
+    pub not_before: u64,
+    /// When tickets signed with it stop being accepted. Unset while the key is
+    /// current or upcoming.
+    pub not_after: Option<u64>,
+}
+
+/// The signing keys verifiers should accept tickets from.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct KeySetBundle {
+    pub keys: Vec<PublishedKey>,
+    /// Verifiers keep the newest set they have seen, so an old one cannot be
+    /// replayed to bring back a retired key.
+    pub issued_at: u64,
+    /// Fetch a new set before this; a key announced ahead activates no sooner.
+    pub expires_at: u64,
+}
+
+impl KeySetBundle {
+    /// The exact bytes the root key signs.
+    pub fn signing_bytes(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        crate::encode(self)
+    }
+}
+
+/// Body of a [`crate::MessageKind::Keys`] frame, signed with the KDC's
+/// long-term root key, which verifiers pin.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct KeySetReply {
+    pub signature: [u8; 64],
+    pub signature_data: KeySetBundle,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+    use crate::error::{ErrorBundle, ErrorReply, KdcError};
+    use crate::frame::{Frame, Hello, HelloAck, MessageKind};
+
+    fn round_trip<T: bincode::Encode + bincode::Decode<()> + PartialEq + Debug>(value: T) -> Vec<u8> {
+        let bytes = crate::encode(&value).unwrap();
+        assert_eq!(crate::decode::<T>(&bytes).unwrap(), value);
+        bytes
+    }
+
+    fn stamp() -> PowStamp {
+        PowStamp { nonce: [1; 32], counter: 212 }
+    }
+
+    fn ticket() -> MessageSent {
+        MessageSent {
+            signature: [2; 64],
+            signature_data: SignBundle {
+                key_id: 3,
+                sealed_session_key: vec![4; 60],
+                pseudonym: [6; 32],
+                service: "admin".into(),
+                timestamp: 1_792_281_600,
+                not_before: 1_792_281_600,
+                expires_at: 1_792_282_500,
+            },
+        }
+    }
+
+    fn key_set() -> KeySetReply {
+        KeySetReply {
+            signature: [7; 64],
+            signature_data: KeySetBundle {
+                keys: vec![
+                    PublishedKey {
+                        key_id: 1,
+                        public_key: [8; 32],
+                        not_before: 1_789_689_600,
+                        not_after: Some(1_792_317_900),
+                    },
+                    PublishedKey {
+                        key_id: 2,
+                        public_key: [9; 32],
+                        not_before: 1_792_281_600,
+                        not_after: None,
+                    },
+                ],
+                issued_at: 1_792_281_600,
+                expires_at: 1_792_368_000,
+            },
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        round_trip(Challenge { nonce: [1; 32], expires_at: 1_792_281_900, difficulty: 20 });
+        round_trip(stamp());
+        round_trip(AsReqHead { reply_key: [10; 32], stamp: stamp() });
+        round_trip(AsReply { tgt: vec![11; 120], session_key: [12; 32], expires_at: 1_792_317_600 });
+        round_trip(TgsRequest {
+            tgt: vec![11; 120],
+            service: "wifi".into(),
+            authenticator: Authenticator { timestamp: 1_792_281_600, mac: [13; 32] },
+        });
+        round_trip(TgsReply { ticket: ticket(), session_key: [14; 32] });
+        round_trip(ticket());
+        round_trip(ticket().signature_data);
+        round_trip(key_set());
+        round_trip(key_set().signature_data);
+        round_trip(key_set().signature_data.keys[0].clone());
+        let error = ErrorBundle { key_id: 3, error: KdcError::NonceExpired, timestamp: 1_792_281_600 };
+        round_trip(ErrorReply { signature: [15; 64], signature_data: error.clone() });
+        round_trip(error);
+        for code in 0..=64 {
+            assert_eq!(round_trip(KdcError::from_code(code)), round_trip(code));
+        }
+        round_trip(Hello::supported());
+        round_trip(HelloAck { version: crate::PROTOCOL_VERSION });
+    }
+
+    #[cfg(feature = "receipt")]
+    #[test]
+    fn as_req_round_trips_and_starts_with_its_head() {
+        use risc0_zkvm::sha::Digest;
+        use risc0_zkvm::{FakeReceipt, InnerReceipt, MaybePruned, Receipt};
+
+        let inner = InnerReceipt::Fake(FakeReceipt::new(MaybePruned::Pruned(Digest::ZERO)));
+        let request = MessageReceived {
+            reply_key: [10; 32],
+            stamp: stamp(),
+            proof: Receipt::new(inner, vec![16; 40]),
+        };
+        let bytes = crate::encode(&request).unwrap();
+        let decoded: MessageReceived = crate::decode(&bytes).unwrap();
+        assert_eq!(crate::encode(&decoded).unwrap(), bytes);
+        assert_eq!(
+            AsReqHead::peek(&bytes).unwrap(),
+            AsReqHead { reply_key: request.reply_key, stamp: request.stamp }
+        );
+        assert_eq!(crate::encode_receipt(&request.proof).unwrap(), crate::encode_receipt(&decoded.proof).unwrap());
+    }
+
+    #[cfg(feature = "receipt")]
+    #[test]
+    fn journal_round_trips() {
+        let journal = crate::AuthJournal {
+            existence: 1,
+            db_hash: [17; 32],
+            id_hash: [18; 32],
+            pass_hash: [19; 32],
+            nonce: [20; 32],
+            reply_key_hash: [21; 32],
+        };
+        let words = risc0_zkvm::serde::to_vec(&journal).unwrap();
+        assert_eq!(risc0_zkvm::serde::from_slice::<crate::AuthJournal, _>(&words).unwrap(), journal);
+    }
+
+    /// Fixed encodings: these fail when the wire format drifts, which needs a
+    /// `PROTOCOL_VERSION` bump (and, if old peers can no longer be served, a
+    /// `MIN_PROTOCOL_VERSION` one).
+    #[test]
+    fn encodings_are_stable() {
+        let vectors: [(&str, Vec<u8>, &str); 4] = [
+            (
+                "Challenge",
+                crate::encode(&Challenge { nonce: [1; 32], expires_at: 1_792_281_900, difficulty: 20 }).unwrap(),
+                "0101010101010101010101010101010101010101010101010101010101010101fc2c0dd46a14",
+            ),
+            (
+                "TgsRequest",
+                crate::encode(&TgsRequest {
+                    tgt: vec![11; 3],
+                    service: "wifi".into(),
+                    authenticator: Authenticator { timestamp: 1_792_281_600, mac: [13; 32] },
+                })
+                .unwrap(),
+                "030b0b0b0477696669fc000cd46a0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d0d",
+            ),
+            (
+                "SignBundle",
+                ticket().signature_data.signing_bytes().unwrap(),
+                concat!(
+                    "033c040404040404040404040404040404040404040404040404040404040404",
+                    "0404040404040404040404040404040404040404040404040404040404040606",
+                    "0606060606060606060606060606060606060606060606060606060606060561",
+                    "646d696efc000cd46afc000cd46afc840fd46a",
+                ),
+            ),
+            (
+                "ErrorBundle",
+                ErrorBundle {
+                    key_id: 3,
+                    error: KdcError::NonceExpired,
+                    timestamp: 1_792_281_600,
+                }
+                .signing_bytes()
+                .unwrap(),
+                "030bfc000cd46a",
+            ),
+        ];
+        for (name, bytes, expected) in vectors {
+            assert_eq!(to_hex(&bytes), expected, "{} encoding changed", name);
+        }
+        let frame = Frame::new(9, MessageKind::HelloAck, &HelloAck { version: 9 }).unwrap();
+        assert_eq!(to_hex(&frame.to_bytes()), "5a4b4b420009010000000109", "frame encoding changed");
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
@@ -0,0 +1,100 @@
+//! Hashcash-style puzzle a client solves before the KDC verifies its receipt.
+//!
+//! A [`PowStamp`] is good for `difficulty` bits when SHA-256 over a label, the
+//! challenge nonce, the reply key and the counter starts with that many zero
+//! bits. Finding one takes about `2^difficulty` hashes; checking it takes one.
+//! Hashing the reply key in means a stamp cannot be lifted onto a request
+//! whose reply is sealed to someone else.
+
+use sha2::{Digest, Sha256};
+
+use crate::PowStamp;
+
+const LABEL: &[u8] = b"zkk pow v1\0";
+/// Highest difficulty a client agrees to work on; a server asking for more
+/// is refused rather than searched for forever.
+pub const MAX_DIFFICULTY: u8 = 32;
+
+impl PowStamp {
+    /// Searches for a counter meeting `difficulty`. `None` above [`MAX_DIFFICULTY`].
+    pub fn solve(nonce: &[u8; 32], reply_key: &[u8; 32], difficulty: u8) -> Option<Self> {
+        if difficulty > MAX_DIFFICULTY {
+            return None;
+        }
+        (0..=u64::MAX)
+            .map(|counter| PowStamp { nonce: *nonce, counter })
+            .find(|stamp| stamp.meets(reply_key, difficulty))
+    }
+
+    pub fn meets(&self, reply_key: &[u8; 32], difficulty: u8) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(LABEL);
+        hasher.update(self.nonce);
+        hasher.update(reply_key);
+        hasher.update(self.counter.to_be_bytes());
+        leading_zero_bits(&hasher.finalize()) >= u32::from(difficulty)
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte != 0 {
+            return bits + byte.leading_zeros();
+        }
+        bits += 8;
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 32] = [1; 32];
+    const REPLY_KEY: [u8; 32] = [2; 32];
+
+    #[test]
+    fn solved_stamp_meets_its_difficulty() {
+        for difficulty in [0, 1, 8, 12] {
+            let stamp = PowStamp::solve(&NONCE, &REPLY_KEY, difficulty).unwrap();
+            assert_eq!(stamp.nonce, NONCE);
+            assert!(stamp.meets(&REPLY_KEY, difficulty));
+            assert!((0..difficulty).all(|easier| stamp.meets(&REPLY_KEY, easier)));
+        }
+        // The first counter that does, so solving is deterministic.
+        let stamp = PowStamp::solve(&NONCE, &REPLY_KEY, 12).unwrap();
+        assert!((0..stamp.counter).all(|counter| !PowStamp { nonce: NONCE, counter }.meets(&REPLY_KEY, 12)));
+    }
+
+    #[test]
+    fn stamp_under_the_difficulty_is_refused() {
+        let stamp = (0..)
+            .map(|counter| PowStamp { nonce: NONCE, counter })
+            .find(|stamp| stamp.meets(&REPLY_KEY, 4) && !stamp.meets(&REPLY_KEY, 8))
+            .unwrap();
+        assert!(stamp.meets(&REPLY_KEY, 4));
+        assert!(!stamp.meets(&REPLY_KEY, 8));
+
+        // Bound to the reply key and nonce it was solved for.
+        let stamp = PowStamp::solve(&NONCE, &REPLY_KEY, 16).unwrap();
+        assert!(!stamp.meets(&[3; 32], 16));
+        assert!(!PowStamp { nonce: [4; 32], ..stamp }.meets(&REPLY_KEY, 16));
+    }
+
+    #[test]
+    fn difficulty_above_the_maximum_is_not_searched() {
+        assert_eq!(PowStamp::solve(&NONCE, &REPLY_KEY, MAX_DIFFICULTY + 1), None);
+        assert_eq!(PowStamp::solve(&NONCE, &REPLY_KEY, u8::MAX), None);
+        // No hash has more zero bits than it has bits.
+        assert!(!PowStamp { nonce: NONCE, counter: 0 }.meets(&REPLY_KEY, u8::MAX));
+    }
+
+    #[test]
+    fn leading_zero_bits_counts_across_bytes() {
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+        assert_eq!(leading_zero_bits(&[0x01, 0xff]), 7);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x10]), 19);
+        assert_eq!(leading_zero_bits(&[0; 32]), 256);
+    }
+}
@@ -0,0 +1,346 @@
+//! Append-only audit log of `AsReq` and `TgsReq` outcomes.
+//!
+//! One JSON object per line. Every entry carries the hash of the one before
+//! it (`prev`, all zeros for the first) and its own `hash`, SHA-256 over the
+//! rest of the entry, so editing, reordering or dropping an entry breaks the
+//! chain from there on. Dropping entries off the end does not; operators who
+//! need that record the head hash printed by `zkk_server audit verify`
+//! somewhere the KDC cannot write.
+//!
+//! Entries identify a client only by its comb hash, the same pseudonym its
+//! tickets carry. Peer addresses, the credential hashes the comb hash is made
+//! from and anything sealed are never written.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context};
+use hex::FromHex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zkk_protocol::{KdcError, KeyId};
+
+use crate::config::AuditCommand;
+use crate::kdc::now;
+
+const HASH_DOMAIN: &[u8] = b"zkk audit v1\0";
+const GENESIS: [u8; 32] = [0; 32];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    AsReq,
+    TgsReq,
+}
+
+/// What is known about one request by the time it is answered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub event: Event,
+    /// `issued`, or the `KdcError` the request was refused with.
+    pub outcome: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comb_hash: Option<String>,
+    /// Guest image ID the proof was checked against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_id: Option<String>,
+    /// Digest of the credential database the proof was checked against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub db_digest: Option<String>,
+    /// Key that signed the ticket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<KeyId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+}
+
+impl Record {
+    pub fn new(event: Event) -> Self {
+        Record {
+            event,
+            outcome: String::new(),
+            comb_hash: None,
+            image_id: None,
+            db_digest: None,
+            key_id: None,
+            service: None,
+        }
+    }
+
+    pub fn set_outcome<T>(&mut self, result: &Result<T, KdcError>) {
+        self.outcome = outcome(result);
+    }
+}
+
+/// `issued`, or the name of the error.
+pub fn outcome<T>(result: &Result<T, KdcError>) -> String {
+    match result {
+        Ok(_) => "issued".to_owned(),
+        Err(error) => format!("{:?}", error),
+    }
+}
+
+/// The hashed part of an entry.
+#[derive(Debug, Serialize, Deserialize)]
+struct Body {
+    seq: u64,
+    time: u64,
+    #[serde(flatten)]
+    record: Record,
+    prev: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    body: Body,
+    hash: String,
+}
+
+impl Body {
+    fn hash(&self) -> anyhow::Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        hasher.update(HASH_DOMAIN);
+        hasher.update(serde_json::to_vec(self)?);
+        Ok(hasher.finalize().into())
+    }
+}
+
+struct Head {
+    file: File,
+    next_seq: u64,
+    hash: [u8; 32],
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    head: Mutex<Head>,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it if needed. The chain is
+    /// continued from the last entry; `zkk_server audit verify` checks the rest.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        let mut next_seq = 0;
+        let mut hash = GENESIS;
+        match File::open(path) {
+            Ok(mut file) => {
+                let last = last_line(&mut file).with_context(|| format!("failed to read {}", path.display()))?;
+                if let Some(bytes) = last {
+                    let entry = parse_entry(&bytes).with_context(|| format!("last entry of {}", path.display()))?;
+                    next_seq = entry.body.seq + 1;
+                    hash = parse_hash(&entry.hash)?;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Ok(AuditLog {
+            path: path.to_owned(),
+            head: Mutex::new(Head { file, next_seq, hash }),
+        })
+    }
+
+    /// Appends `record` and syncs it to disk. Blocks until the disk is done,
+    /// so callers run it off the async runtime.
+    pub fn append(&self, record: Record) -> anyhow::Result<()> {
+        let mut head = self.head.lock().expect("audit log poisoned");
+        let body = Body {
+            seq: head.next_seq,
+            time: now(),
+            record,
+            prev: hex::encode(head.hash),
+        };
+        let hash = body.hash()?;
+        let mut line = serde_json::to_vec(&Entry {
+            body,
+            hash: hex::encode(hash),
+        })?;
+        line.push(b'\n');
+        head.file
+            .write_all(&line)
+            .and_then(|()| head.file.sync_data())
+            .with_context(|| format!("failed to append to {}", self.path.display()))?;
+        head.next_seq += 1;
+        head.hash = hash;
+        Ok(())
+    }
+}
+
+/// The last line of `file`, without its newline. Read backwards from the
+/// end, so opening a long log does not read all of it.
+fn last_line(file: &mut File) -> std::io::Result<Option<Vec<u8>>> {
+    const CHUNK: u64 = 4096;
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut end = len;
+    let mut line = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(CHUNK);
+        let mut chunk = vec![0u8; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        if end == len && chunk.last() == Some(&b'\n') {
+            chunk.pop();
+        }
+        let found = chunk.iter().rposition(|&byte| byte == b'\n');
+        if let Some(newline) = found {
+            chunk.drain(..=newline);
+        }
+        chunk.extend_from_slice(&line);
+        line = chunk;
+        if found.is_some() {
+            break;
+        }
+        end = start;
+    }
+    Ok((!line.is_empty()).then_some(line))
+}
+
+/// Lines of `file` with their numbers, counting from 1.
+fn read_lines(file: File) -> impl Iterator<Item = anyhow::Result<(usize, Vec<u8>)>> {
+    BufReader::new(file)
+        .split(b'\n')
+        .enumerate()
+        .map(|(i, line)| Ok((i + 1, line?)))
+}
+
+/// Parses one line, which must be exactly what [`AuditLog::append`] writes
+/// for it: fields the hash does not cover cannot be slipped in.
+fn parse_entry(line: &[u8]) -> anyhow::Result<Entry> {
+    let entry: Entry = serde_json::from_slice(line).context("not an audit entry")?;
+    if serde_json::to_vec(&entry)? != line {
+        bail!("not in canonical form");
+    }
+    Ok(entry)
+}
+
+fn parse_hash(text: &str) -> anyhow::Result<[u8; 32]> {
+    <[u8; 32]>::from_hex(text).with_context(|| format!("invalid hash {:?}", text))
+}
+
+/// Checks every link in `path`. A file starting at `seq` 0 must chain from
+/// all zeros; an export starting later is checked from its first `prev`.
+/// Returns the number of entries and the head hash.
+fn verify(path: &Path) -> anyhow::Result<(u64, [u8; 32])> {
+    let file = File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut count = 0;
+    let mut expected: Option<(u64, [u8; 32])> = None;
+    let mut head = GENESIS;
+    for item in read_lines(file) {
+        let (line, bytes) = item?;
+        let entry = parse_entry(&bytes).with_context(|| format!("line {}", line))?;
+        let prev = parse_hash(&entry.body.prev).with_context(|| format!("line {}", line))?;
+        match expected {
+            Some((seq, hash)) => {
+                if entry.body.seq != seq {
+                    bail!("line {}: expected entry {}, found {}", line, seq, entry.body.seq);
+                }
+                if prev != hash {
+                    bail!("line {}: entry {} does not chain from entry {}", line, seq, seq - 1);
+                }
+            }
+            None if entry.body.seq == 0 && prev != GENESIS => {
+                bail!("line {}: entry 0 has a non-zero prev", line)
+            }
+            None => {}
+        }
+        let hash = entry.body.hash()?;
+        if hex::encode(hash) != entry.hash {
+            bail!("line {}: entry {} was modified", line, entry.body.seq);
+        }
+        expected = Some((entry.body.seq + 1, hash));
+        head = hash;
+        count += 1;
+    }
+    Ok((count, head))
+}
+
+/// Runs a `zkk_server audit ...` command against the log at `path`.
+pub fn admin(command: &AuditCommand, path: Option<&Path>) -> anyhow::Result<()> {
+    let configured = || path.context("no audit log configured; set `audit_log` or pass --audit-log");
+    match command {
+        AuditCommand::Verify { file } => {
+            let file = match file {
+                Some(file) => file.as_path(),
+                None => configured()?,
+            };
+            let (count, head) = verify(file)?;
+            println!("{} entries verify, head {}", count, hex::encode(head));
+        }
+        AuditCommand::Export { from, to, since, until } => {
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            let file = File::open(configured()?)?;
+            for item in read_lines(file) {
+                let (line, bytes) = item?;
+                let entry = parse_entry(&bytes).with_context(|| format!("line {}", line))?;
+                let body = &entry.body;
+                let in_range = from.is_none_or(|from| body.seq >= from)
+                    && to.is_none_or(|to| body.seq <= to)
+                    && since.is_none_or(|since| body.time >= since)
+                    && until.is_none_or(|until| body.time < until);
+                if in_range {
+                    out.write_all(&bytes)?;
+                    writeln!(out)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last(contents: &[u8]) -> Option<Vec<u8>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        std::fs::write(&path, contents).unwrap();
+        last_line(&mut File::open(&path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn last_line_reads_back_from_the_end() {
+        assert_eq!(last(b""), None);
+        assert_eq!(last(b"one\n"), Some(b"one".to_vec()));
+        assert_eq!(last(b"one\ntwo\n"), Some(b"two".to_vec()));
+        assert_eq!(last(b"one\ntwo"), Some(b"two".to_vec()));
+        // Lines longer than a chunk, ending on and across chunk boundaries.
+        for len in [4095, 4096, 4097, 10_000] {
+            let long = vec![b'x'; len];
+            let mut contents = b"first\n".to_vec();
+            contents.extend_from_slice(&long);
+            contents.push(b'\n');
+            assert_eq!(last(&contents), Some(long.clone()), "{}", len);
+            assert_eq!(last(&contents[6..]), Some(long), "{}", len);
+        }
+    }
+
+    #[test]
+    fn reopened_log_continues_the_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        for _ in 0..3 {
+            let log = AuditLog::open(&path).unwrap();
+            for event in [Event::AsReq, Event::TgsReq] {
+                let mut record = Record::new(event);
+                record.set_outcome::<()>(&Err(KdcError::NonceExpired));
+                log.append(record).unwrap();
+            }
+        }
+        assert_eq!(verify(&path).unwrap().0, 6);
+    }
+}
@@ -0,0 +1,203 @@
+//! Single-use challenge nonces that bind each proof to one authentication attempt.
+//!
+//! Each challenge also sets the proof-of-work difficulty its `AsReq` stamp
+//! must meet, fixed when it is issued: the busier the verifier pool, the more
+//! work a client spends before its receipt is looked at.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::RngCore;
+use zkk_protocol::{AsReqHead, Challenge, KdcError};
+
+use crate::kdc::{self, now};
+
+pub struct Challenges {
+    ttl: Duration,
+    capacity: usize,
+    pow_bits: (u8, u8),
+    issued: Mutex<HashMap<[u8; 32], Issued>>,
+}
+
+struct Issued {
+    expires_at: u64,
+    difficulty: u8,
+    consumed: bool,
+}
+
+impl Challenges {
+    /// Difficulty runs from `pow_bits.0` with an idle pool to `pow_bits.1`.
+    pub fn new(ttl: Duration, capacity: usize, pow_bits: (u8, u8)) -> Self {
+        Challenges {
+            ttl,
+            capacity,
+            pow_bits,
+            issued: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A fresh nonce. `load` is [`crate::workers::VerifierPool::load`]; the
+    /// difficulty rises linearly with it and is highest once as many requests
+    /// wait for a worker as there are workers. Refused with `Busy` when
+    /// `capacity` nonces are still live.
+    pub fn issue(&self, load: f64) -> Result<Challenge, KdcError> {
+        let (min, max) = self.pow_bits;
+        let difficulty = min + (f64::from(max - min) * (load / 2.0).clamp(0.0, 1.0)).round() as u8;
+        let mut nonce = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let now = now();
+        let expires_at = now + self.ttl.as_secs();
+
+        let mut issued = self.issued.lock().expect("challenge table poisoned");
+        if issued.len() >= self.capacity {
+            issued.retain(|_, entry| entry.expires_at >= now);
+            if issued.len() >= self.capacity {
+                return Err(kdc::reject(KdcError::Busy, "too many outstanding challenges"));
+            }
+        }
+        issued.insert(
+            nonce,
+            Issued {
+                expires_at,
+                difficulty,
+                consumed: false,
+            },
+        );
+        Ok(Challenge {
+            nonce,
+            expires_at,
+            difficulty,
+        })
+    }
+
+    /// Checks the stamp on an `AsReq` against the challenge it names, without
+    /// spending it: that happens in [`Challenges::consume`], once a worker is
+    /// free to verify the receipt.
+    pub fn check_stamp(&self, head: &AsReqHead) -> Result<(), KdcError> {
+        let nonce = &head.stamp.nonce;
+        let issued = self.issued.lock().expect("challenge table poisoned");
+        let Some(entry) = issued.get(nonce) else {
+            return Err(kdc::reject(KdcError::NonceUnknown, hex::encode(nonce)));
+        };
+        if entry.consumed {
+            return Err(kdc::reject(KdcError::NonceReplayed, hex::encode(nonce)));
+        }
+        if entry.expires_at < now() {
+            return Err(kdc::reject(KdcError::NonceExpired, hex::encode(nonce)));
+        }
+        if !head.stamp.meets(&head.reply_key, entry.difficulty) {
+            return Err(kdc::reject(KdcError::PowInvalid, format!("difficulty {}", entry.difficulty)));
+        }
+        Ok(())
+    }
+
+    /// Marks `nonce` used. Consumed nonces are remembered until they expire so
+    /// a replay is reported as such.
+    pub fn consume(&self, nonce: &[u8; 32]) -> Result<(), KdcError> {
+        let mut issued = self.issued.lock().expect("challenge table poisoned");
+        let Some(entry) = issued.get_mut(nonce) else {
+            return Err(kdc::reject(KdcError::NonceUnknown, hex::encode(nonce)));
+        };
+        if entry.consumed {
+            return Err(kdc::reject(KdcError::NonceReplayed, hex::encode(nonce)));
+        }
+        if entry.expires_at < now() {
+            issued.remove(nonce);
+            return Err(kdc::reject(KdcError::NonceExpired, hex::encode(nonce)));
+        }
+        entry.consumed = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zkk_protocol::PowStamp;
+
+    use super::*;
+
+    const REPLY_KEY: [u8; 32] = [4; 32];
+    const TTL: Duration = Duration::from_secs(300);
+
+    /// The head of an `AsReq` answering `challenge`, with a stamp that meets it.
+    fn head(challenge: &Challenge) -> AsReqHead {
+        AsReqHead {
+            reply_key: REPLY_KEY,
+            stamp: PowStamp::solve(&challenge.nonce, &REPLY_KEY, challenge.difficulty).unwrap(),
+        }
+    }
+
+    fn expire(challenges: &Challenges, nonce: &[u8; 32]) {
+        let mut issued = challenges.issued.lock().unwrap();
+        issued.get_mut(nonce).unwrap().expires_at = now() - 1;
+    }
+
+    #[test]
+    fn valid_stamp_is_accepted_once() {
+        let challenges = Challenges::new(TTL, 16, (8, 8));
+        let challenge = challenges.issue(0.0).unwrap();
+        assert_eq!(challenge.difficulty, 8);
+        let head = head(&challenge);
+        assert_eq!(challenges.check_stamp(&head), Ok(()));
+        // Checking does not spend it.
+        assert_eq!(challenges.check_stamp(&head), Ok(()));
+        assert_eq!(challenges.consume(&head.stamp.nonce), Ok(()));
+        assert_eq!(challenges.check_stamp(&head), Err(KdcError::NonceReplayed));
+        assert_eq!(challenges.consume(&head.stamp.nonce), Err(KdcError::NonceReplayed));
+    }
+
+    #[test]
+    fn stamp_under_the_difficulty_is_refused() {
+        let challenges = Challenges::new(TTL, 16, (8, 8));
+        let challenge = challenges.issue(0.0).unwrap();
+        let mut head = head(&challenge);
+        head.stamp = (0..)
+            .map(|counter| PowStamp { nonce: challenge.nonce, counter })
+            .find(|stamp| !stamp.meets(&REPLY_KEY, 8))
+            .unwrap();
+        assert_eq!(challenges.check_stamp(&head), Err(KdcError::PowInvalid));
+        // A refused stamp leaves the nonce for a good one.
+        assert_eq!(challenges.check_stamp(&self::head(&challenge)), Ok(()));
+    }
+
+    #[test]
+    fn unknown_nonce_is_refused() {
+        let challenges = Challenges::new(TTL, 16, (0, 0));
+        challenges.issue(0.0).unwrap();
+        let head = head(&Challenge {
+            nonce: [9; 32],
+            expires_at: now() + 60,
+            difficulty: 0,
+        });
+        assert_eq!(challenges.check_stamp(&head), Err(KdcError::NonceUnknown));
+        assert_eq!(challenges.consume(&head.stamp.nonce), Err(KdcError::NonceUnknown));
+    }
+
+    #[test]
+    fn expired_nonce_is_refused() {
+        let challenges = Challenges::new(TTL, 16, (0, 0));
+        let challenge = challenges.issue(0.0).unwrap();
+        assert_eq!(challenge.expires_at, now() + TTL.as_secs());
+        let head = head(&challenge);
+        expire(&challenges, &challenge.nonce);
+        assert_eq!(challenges.check_stamp(&head), Err(KdcError::NonceExpired));
+        assert_eq!(challenges.consume(&challenge.nonce), Err(KdcError::NonceExpired));
+        // Forgotten once found expired.
+        assert_eq!(challenges.consume(&challenge.nonce), Err(KdcError::NonceUnknown));
+    }
+
+    #[test]
+    fn pending_challenges_are_capped() {
+        let challenges = Challenges::new(TTL, 2, (0, 0));
+        let first = challenges.issue(0.0).unwrap();
+        let second = challenges.issue(0.0).unwrap();
+        assert_eq!(challenges.issue(0.0), Err(KdcError::Busy));
+        // Consumed nonces still count until they expire, so replays are named.
+        challenges.consume(&second.nonce).unwrap();
+        assert_eq!(challenges.issue(0.0), Err(KdcError::Busy));
+        expire(&challenges, &first.nonce);
+        challenges.issue(0.0).unwrap();
+        assert_eq!(challenges.consume(&first.nonce), Err(KdcError::NonceUnknown));
+    }
+}
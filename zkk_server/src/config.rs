@@ -0,0 +1,1000 @@
+//! Server configuration: a TOML file (see `kdc.toml`), overridden by command line flags.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Parser;
+use hex::FromHex;
+use risc0_zkvm::sha::Digest;
+use serde::Deserialize;
+use zkk_protocol::frame::MAX_FRAME_LEN;
+use zkk_protocol::pow::MAX_DIFFICULTY;
+use zkk_protocol::{JournalSchema, KeyId};
+
+use crate::connection::Limits;
+use crate::db::cid::{Cid, CidError};
+
+#[derive(Debug, Parser)]
+#[command(name = "zkk_server", about = "Zero-knowledge Kerberos KDC")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML configuration file. Relative paths inside it are resolved against its directory.
+    #[arg(short, long, default_value = "kdc.toml", global = true)]
+    pub config: PathBuf,
+    /// Address the KDC listens on.
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    /// Address the HTTP API (`/v1/...`) listens on. Off unless set.
+    #[arg(long)]
+    pub http_listen: Option<SocketAddr>,
+    /// Origin (`https://host[:port]`) browsers may call the HTTP API from.
+    /// Repeatable; replaces the list from the file.
+    #[arg(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+    /// PEM certificate chain to serve TLS with on the KDC and HTTP listeners. Needs --tls-key.
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert.
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+    /// Address Prometheus metrics (`GET /metrics`) are served on. Off unless set.
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Accepted guest image ID, as printed by the host (`[w0, ..., w7]`) or 64 hex digits,
+    /// with the current journal schema. Repeatable; replaces the list from the file.
+    #[arg(long = "image-id", value_parser = parse_image_id)]
+    pub image_ids: Vec<Digest>,
+    /// Read the credential database from a local file.
+    #[arg(long, group = "db_source")]
+    pub db_file: Option<PathBuf>,
+    /// Fetch the credential database from an HTTP(S) URL.
+    #[arg(long, group = "db_source")]
+    pub db_url: Option<String>,
+    /// Fetch the credential database by CID (`bafkrei…`) through an IPFS gateway.
+    #[arg(long, group = "db_source")]
+    pub db_cid: Option<Cid>,
+    /// IPFS gateway used with `source = "ipfs"` / --db-cid.
+    #[arg(long)]
+    pub ipfs_gateway: Option<String>,
+    /// Directory verified copies of the credential database are kept in.
+    #[arg(long)]
+    pub db_cache_dir: Option<PathBuf>,
+    /// Directory holding the Ed25519 ticket signing keyring.
+    #[arg(long, global = true)]
+    pub signing_keyring: Option<PathBuf>,
+    /// File holding the base64 Ed25519 root key the published key set is signed with.
+    #[arg(long, global = true)]
+    pub root_key: Option<PathBuf>,
+    /// File holding the base64 key ticket-granting tickets are sealed under.
+    #[arg(long)]
+    pub tgt_key: Option<PathBuf>,
+    /// Append-only audit log of issued and refused requests, see `zkk_server audit`.
+    #[arg(long, global = true)]
+    pub audit_log: Option<PathBuf>,
+    /// Ticket lifetime in seconds for services without their own policy.
+    #[arg(long)]
+    pub ticket_lifetime: Option<u64>,
+    #[arg(long, value_enum, global = true)]
+    pub log_level: Option<LogLevel>,
+    #[arg(long, value_enum, global = true)]
+    pub log_format: Option<LogFormat>,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum Command {
+    /// Inspect or change the ticket signing keyring instead of serving.
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Check or extract entries from the audit log instead of serving.
+    #[command(subcommand)]
+    Audit(AuditCommand),
+    /// Make TLS certificates instead of serving.
+    #[command(subcommand)]
+    Tls(TlsCommand),
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum KeysCommand {
+    /// Generate the TGT key, root key, first signing key and a key for each
+    /// service under `[services]`. Refuses if any of them exists.
+    Init,
+    /// Every key with its status, creation time and public key.
+    List,
+    /// `<id> <base64 public key>` for each key services should accept tickets from.
+    Public,
+    /// The base64 root public key verifiers pin to check published key sets.
+    Root,
+    /// Add a signing key now instead of waiting for the schedule.
+    Rotate,
+    /// Stop signing with and publishing a key, e.g. because it leaked. A
+    /// retired active key is replaced at once. A running server notices
+    /// within 30 seconds, when it next re-reads the keyring.
+    Retire { id: KeyId },
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum AuditCommand {
+    /// Check that no entry was modified, removed or reordered and print the head hash.
+    Verify {
+        /// An exported range to check instead of the configured log.
+        file: Option<PathBuf>,
+    },
+    /// Print the entries in a range, unchanged, so the range verifies on its own.
+    Export {
+        /// First sequence number.
+        #[arg(long)]
+        from: Option<u64>,
+        /// Last sequence number.
+        #[arg(long)]
+        to: Option<u64>,
+        /// Entries written at or after this Unix time.
+        #[arg(long)]
+        since: Option<u64>,
+        /// Entries written before this Unix time.
+        #[arg(long)]
+        until: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum TlsCommand {
+    /// A new self-signed CA and a server certificate it signed, for testing.
+    /// Prints the pin of the server certificate.
+    DevCerts {
+        /// Directory to write `ca.pem`, `server.pem` and `server.key` to.
+        #[arg(long, default_value = "tls")]
+        out: PathBuf,
+        /// DNS name or IP address the certificate is for. Repeatable.
+        #[arg(long = "name", default_values = ["localhost", "127.0.0.1"])]
+        names: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per event.
+    #[default]
+    Text,
+    /// One JSON object per event, with the spans it happened in.
+    Json,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("failed to parse {path}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("missing `{0}`")]
+    Missing(&'static str),
+    #[error("no accepted image IDs; set `image_ids` or pass --image-id")]
+    NoImageIds,
+    #[error("invalid image ID {0:?}: {1}")]
+    ImageId(String, String),
+    #[error("image ID {0} is listed twice")]
+    DuplicateImageId(Digest),
+    #[error("image ID {0} has journal_schema {1}, but only up to {max} can be decoded", max = JournalSchema::CURRENT.version())]
+    JournalSchema(Digest, u16),
+    #[error("{0} {1:?} must start with http:// or https://")]
+    Url(&'static str, String),
+    #[error("invalid CID {0:?}: {1}")]
+    Cid(String, CidError),
+    #[error("invalid credential_db.sha256 {0:?}: expected 64 hex digits")]
+    Sha256(String),
+    #[error("`{0}` must be greater than zero")]
+    Zero(&'static str),
+    #[error("keys.rotation_overlap_secs is {overlap}, but tickets live up to {needed}s including clock skew")]
+    Overlap { overlap: u64, needed: u64 },
+    #[error("keys.announce_ahead_secs must be less than keys.rotate_after_secs")]
+    AnnounceAhead,
+    #[error("`{0}` must be at most {1}")]
+    TooLarge(&'static str, u32),
+    #[error("limits.pow_min_bits must not exceed limits.pow_max_bits, which must not exceed {MAX_DIFFICULTY}")]
+    PowBits,
+}
+
+/// Validated configuration the server runs with.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub command: Option<Command>,
+    pub listen: SocketAddr,
+    pub http_listen: Option<SocketAddr>,
+    /// Origins allowed to call the HTTP API cross-origin. None unless set.
+    pub cors_origins: Vec<String>,
+    /// Plaintext unless set.
+    pub tls: Option<TlsFiles>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub images: Vec<AcceptedImage>,
+    pub credential_db: CredentialDbConfig,
+    pub db_cache: DbCacheConfig,
+    pub keys: KeyFiles,
+    pub key_rotation: KeyRotation,
+    /// Not written when unset.
+    pub audit_log: Option<PathBuf>,
+    pub tickets: TicketPolicy,
+    /// How long a challenge nonce may be used after it is issued.
+    pub challenge_ttl: Duration,
+    /// Clock difference tolerated when checking TGTs and authenticators.
+    pub clock_skew: Duration,
+    pub limits: Limits,
+    pub receipts: ReceiptPolicy,
+}
+
+/// Where the credential database is fetched from, see [`crate::db`].
+#[derive(Debug, Clone)]
+pub enum CredentialDbConfig {
+    File { path: PathBuf, sha256: Option<[u8; 32]> },
+    Http { url: String, sha256: Option<[u8; 32]> },
+    Ipfs { gateway: String, cid: Cid },
+}
+
+#[derive(Debug, Clone)]
+pub struct DbCacheConfig {
+    /// Memory only when unset.
+    pub dir: Option<PathBuf>,
+    /// How often sources that are not pinned by digest are fetched again.
+    pub refresh: Duration,
+    /// Largest database taken from a source; longer downloads are cut off.
+    pub max_bytes: u64,
+}
+
+/// How long tickets are valid, per service.
+#[derive(Debug, Clone)]
+pub struct TicketPolicy {
+    /// Lifetime of ticket-granting tickets; service tickets never outlive theirs.
+    pub tgt_lifetime: Duration,
+    pub default_lifetime: Duration,
+    pub lifetimes: BTreeMap<String, Duration>,
+}
+
+impl TicketPolicy {
+    pub fn lifetime(&self, service: &str) -> Duration {
+        self.lifetimes.get(service).copied().unwrap_or(self.default_lifetime)
+    }
+}
+
+/// Receipts worth verifying. Anything else is refused once decoded, before
+/// verification starts.
+#[derive(Debug, Clone)]
+pub struct ReceiptPolicy {
+    pub kinds: Vec<ReceiptKind>,
+    /// Segments plus assumption receipts a composite receipt may carry.
+    pub max_segments: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptKind {
+    /// One STARK per segment; what the prover produces by default.
+    Composite,
+    Succinct,
+    Groth16,
+}
+
+/// When the signing key is replaced, see [`crate::keyring`].
+#[derive(Debug, Clone, Copy)]
+pub struct KeyRotation {
+    pub rotate_after: Duration,
+    /// How long a superseded key keeps verifying. Covers the longest ticket lifetime.
+    pub overlap: Duration,
+    /// How long before activation the next key is published. Published key
+    /// sets are valid for this long, so verifiers always learn it in time.
+    pub announce_ahead: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyFiles {
+    pub signing_keyring: PathBuf,
+    pub root_key: PathBuf,
+    pub tgt_key: PathBuf,
+    /// Long-term key of each registered service, from `[services.<name>]`.
+    pub services: BTreeMap<String, PathBuf>,
+}
+
+/// A guest image whose proofs are accepted.
+#[derive(Debug, Clone)]
+pub struct AcceptedImage {
+    pub id: Digest,
+    /// Names the image in logs and metrics, e.g. after the guest release.
+    pub label: String,
+    /// Layout of the journal the guest commits.
+    pub journal: JournalSchema,
+    /// Unix time from which proofs from this image are refused. Never if unset.
+    pub sunset_at: Option<u64>,
+}
+
+impl AcceptedImage {
+    /// Labelled by its first hex digits, with the current journal schema and no sunset.
+    fn new(id: Digest) -> Self {
+        AcceptedImage {
+            label: id.to_string()[..8].to_owned(),
+            id,
+            journal: JournalSchema::CURRENT,
+            sunset_at: None,
+        }
+    }
+}
+
+/// Certificate and key the KDC and HTTP listeners serve TLS with.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Layout of the TOML file. Everything is optional so flags can fill gaps.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    http_listen: Option<SocketAddr>,
+    http_cors_origins: Option<Vec<String>>,
+    tls: Option<FileTls>,
+    metrics_listen: Option<SocketAddr>,
+    log_level: Option<LogLevel>,
+    log_format: Option<LogFormat>,
+    audit_log: Option<PathBuf>,
+    image_ids: Option<Vec<FileImage>>,
+    ticket_lifetime_secs: Option<u64>,
+    tgt_lifetime_secs: Option<u64>,
+    challenge_ttl_secs: Option<u64>,
+    clock_skew_secs: Option<u64>,
+    credential_db: Option<FileCredentialDb>,
+    #[serde(default)]
+    db_cache: FileDbCache,
+    #[serde(default)]
+    keys: FileKeys,
+    #[serde(default)]
+    limits: FileLimits,
+    #[serde(default)]
+    receipts: FileReceipts,
+    #[serde(default)]
+    services: BTreeMap<String, FileService>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileService {
+    /// File holding the 32 base64 bytes shared with the service.
+    key: PathBuf,
+    ticket_lifetime_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ImageIdSpec {
+    Words([u32; 8]),
+    Hex(String),
+}
+
+/// An `image_ids` entry: the ID alone, or a table describing it.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FileImage {
+    Id(ImageIdSpec),
+    Entry(FileImageEntry),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileImageEntry {
+    id: ImageIdSpec,
+    label: Option<String>,
+    journal_schema: Option<u16>,
+    sunset_at: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase", deny_unknown_fields)]
+enum FileCredentialDb {
+    File {
+        path: PathBuf,
+        sha256: Option<String>,
+    },
+    Http {
+        url: String,
+        sha256: Option<String>,
+    },
+    Ipfs {
+        cid: String,
+        gateway: Option<String>,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileDbCache {
+    dir: Option<PathBuf>,
+    refresh_secs: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileReceipts {
+    kinds: Option<Vec<ReceiptKind>>,
+    max_segments: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTls {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileKeys {
+    signing_keyring: Option<PathBuf>,
+    root_key: Option<PathBuf>,
+    tgt_key: Option<PathBuf>,
+    rotate_after_secs: Option<u64>,
+    rotation_overlap_secs: Option<u64>,
+    announce_ahead_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileLimits {
+    idle_timeout_secs: Option<u64>,
+    read_timeout_secs: Option<u64>,
+    write_timeout_secs: Option<u64>,
+    max_requests_per_connection: Option<u32>,
+    max_connections: Option<usize>,
+    verify_workers: Option<usize>,
+    verify_queue_timeout_secs: Option<u64>,
+    max_pending_challenges: Option<usize>,
+    max_replay_cache: Option<usize>,
+    pow_min_bits: Option<u8>,
+    pow_max_bits: Option<u8>,
+    rate_limit_per_minute: Option<u32>,
+    rate_limit_burst: Option<u32>,
+    max_rate_limited_sources: Option<usize>,
+    max_message_bytes: Option<u32>,
+    max_receipt_bytes: Option<u32>,
+}
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+const DEFAULT_TICKET_LIFETIME_SECS: u64 = 8 * 60 * 60;
+const DEFAULT_TGT_LIFETIME_SECS: u64 = 10 * 60 * 60;
+/// Proving takes seconds on a laptop and minutes on a phone.
+const DEFAULT_CHALLENGE_TTL_SECS: u64 = 5 * 60;
+const DEFAULT_DB_REFRESH_SECS: u64 = 5 * 60;
+/// The guest reads the whole database, so it stays far below this.
+const DEFAULT_DB_MAX_BYTES: u64 = 16 << 20;
+/// The guest runs a few million cycles at most, a handful of segments.
+const DEFAULT_MAX_RECEIPT_SEGMENTS: usize = 16;
+const DEFAULT_KEY_ROTATION_SECS: u64 = 30 * 24 * 60 * 60;
+/// Capped at half the rotation period when that is shorter.
+const DEFAULT_ANNOUNCE_AHEAD_SECS: u64 = 24 * 60 * 60;
+/// A local IPFS node's gateway.
+const DEFAULT_IPFS_GATEWAY: &str = "http://127.0.0.1:8080";
+
+impl ServerConfig {
+    pub fn load(cli: Cli) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(&cli.config).map_err(|source| ConfigError::Read {
+            path: cli.config.clone(),
+            source,
+        })?;
+        let file: FileConfig = toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: cli.config.clone(),
+            source,
+        })?;
+        let base = cli.config.parent().unwrap_or(Path::new("."));
+        let relative = |path: PathBuf| base.join(path);
+
+        let images = if cli.image_ids.is_empty() {
+            file.image_ids
+                .unwrap_or_default()
+                .into_iter()
+                .map(FileImage::resolve)
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            cli.image_ids.into_iter().map(AcceptedImage::new).collect()
+        };
+        // Subcommands never verify proofs, so they run before an ID is set.
+        if images.is_empty() && cli.command.is_none() {
+            return Err(ConfigError::NoImageIds);
+        }
+        for (i, image) in images.iter().enumerate() {
+            if images[..i].iter().any(|earlier| earlier.id == image.id) {
+                return Err(ConfigError::DuplicateImageId(image.id));
+            }
+        }
+
+        let file_gateway = match &file.credential_db {
+            Some(FileCredentialDb::Ipfs { gateway, .. }) => gateway.clone(),
+            _ => None,
+        };
+        let gateway = cli
+            .ipfs_gateway
+            .or(file_gateway)
+            .unwrap_or_else(|| DEFAULT_IPFS_GATEWAY.to_owned());
+        let credential_db = match (cli.db_file, cli.db_url, cli.db_cid) {
+            (Some(path), _, _) => CredentialDbConfig::File { path, sha256: None },
+            (_, Some(url), _) => CredentialDbConfig::Http { url, sha256: None },
+            (_, _, Some(cid)) => CredentialDbConfig::Ipfs { gateway, cid },
+            _ => match file.credential_db.ok_or(ConfigError::Missing("credential_db"))? {
+                FileCredentialDb::File { path, sha256 } => CredentialDbConfig::File {
+                    path: relative(path),
+                    sha256: sha256.map(parse_sha256).transpose()?,
+                },
+                FileCredentialDb::Http { url, sha256 } => CredentialDbConfig::Http {
+                    url,
+                    sha256: sha256.map(parse_sha256).transpose()?,
+                },
+                FileCredentialDb::Ipfs { cid, .. } => CredentialDbConfig::Ipfs {
+                    gateway,
+                    cid: cid.parse().map_err(|e| ConfigError::Cid(cid, e))?,
+                },
+            },
+        };
+        match &credential_db {
+            CredentialDbConfig::Http { url, .. } => check_url("credential_db.url", url)?,
+            CredentialDbConfig::Ipfs { gateway, .. } => check_url("credential_db.gateway", gateway)?,
+            CredentialDbConfig::File { .. } => {}
+        }
+
+        let db_refresh = file.db_cache.refresh_secs.unwrap_or(DEFAULT_DB_REFRESH_SECS);
+        if db_refresh == 0 {
+            return Err(ConfigError::Zero("db_cache.refresh_secs"));
+        }
+        let db_max_bytes = file.db_cache.max_bytes.unwrap_or(DEFAULT_DB_MAX_BYTES);
+        if db_max_bytes == 0 {
+            return Err(ConfigError::Zero("db_cache.max_bytes"));
+        }
+        let db_cache = DbCacheConfig {
+            dir: cli.db_cache_dir.or(file.db_cache.dir.map(relative)),
+            refresh: Duration::from_secs(db_refresh),
+            max_bytes: db_max_bytes,
+        };
+
+        let receipts = ReceiptPolicy {
+            kinds: file
+                .receipts
+                .kinds
+                .unwrap_or_else(|| vec![ReceiptKind::Composite, ReceiptKind::Succinct]),
+            max_segments: file.receipts.max_segments.unwrap_or(DEFAULT_MAX_RECEIPT_SEGMENTS),
+        };
+        if receipts.kinds.is_empty() {
+            return Err(ConfigError::Missing("receipts.kinds"));
+        }
+        if receipts.max_segments == 0 {
+            return Err(ConfigError::Zero("receipts.max_segments"));
+        }
+
+        let mut keys = KeyFiles {
+            signing_keyring: cli
+                .signing_keyring
+                .or(file.keys.signing_keyring.map(relative))
+                .ok_or(ConfigError::Missing("keys.signing_keyring"))?,
+            root_key: cli
+                .root_key
+                .or(file.keys.root_key.map(relative))
+                .ok_or(ConfigError::Missing("keys.root_key"))?,
+            tgt_key: cli
+                .tgt_key
+                .or(file.keys.tgt_key.map(relative))
+                .ok_or(ConfigError::Missing("keys.tgt_key"))?,
+            services: BTreeMap::new(),
+        };
+
+        let ticket_lifetime = cli
+            .ticket_lifetime
+            .or(file.ticket_lifetime_secs)
+            .unwrap_or(DEFAULT_TICKET_LIFETIME_SECS);
+        if ticket_lifetime == 0 {
+            return Err(ConfigError::Zero("ticket_lifetime_secs"));
+        }
+        let mut lifetimes = BTreeMap::new();
+        for (service, policy) in file.services {
+            keys.services.insert(service.clone(), relative(policy.key));
+            match policy.ticket_lifetime_secs {
+                Some(0) => return Err(ConfigError::Zero("services.*.ticket_lifetime_secs")),
+                Some(secs) => {
+                    lifetimes.insert(service, Duration::from_secs(secs));
+                }
+                None => {}
+            }
+        }
+        let tgt_lifetime = file.tgt_lifetime_secs.unwrap_or(DEFAULT_TGT_LIFETIME_SECS);
+        if tgt_lifetime == 0 {
+            return Err(ConfigError::Zero("tgt_lifetime_secs"));
+        }
+        let tickets = TicketPolicy {
+            tgt_lifetime: Duration::from_secs(tgt_lifetime),
+            default_lifetime: Duration::from_secs(ticket_lifetime),
+            lifetimes,
+        };
+
+        let clock_skew = file.clock_skew_secs.unwrap_or(zkk_protocol::DEFAULT_CLOCK_SKEW);
+        let rotate_after = file.keys.rotate_after_secs.unwrap_or(DEFAULT_KEY_ROTATION_SECS);
+        if rotate_after == 0 {
+            return Err(ConfigError::Zero("keys.rotate_after_secs"));
+        }
+        // A superseded key must verify every ticket it signed until that ticket expires.
+        let longest_ticket = tickets
+            .lifetimes
+            .values()
+            .chain([&tickets.default_lifetime])
+            .max()
+            .map_or(tickets.tgt_lifetime, |&longest| longest.min(tickets.tgt_lifetime));
+        let needed = longest_ticket.as_secs() + clock_skew;
+        let overlap = file.keys.rotation_overlap_secs.unwrap_or(needed);
+        if overlap < needed {
+            return Err(ConfigError::Overlap { overlap, needed });
+        }
+        let announce_ahead = file
+            .keys
+            .announce_ahead_secs
+            .unwrap_or(DEFAULT_ANNOUNCE_AHEAD_SECS.min(rotate_after / 2));
+        if announce_ahead == 0 {
+            return Err(ConfigError::Zero("keys.announce_ahead_secs"));
+        }
+        if announce_ahead >= rotate_after {
+            return Err(ConfigError::AnnounceAhead);
+        }
+        let key_rotation = KeyRotation {
+            rotate_after: Duration::from_secs(rotate_after),
+            overlap: Duration::from_secs(overlap),
+            announce_ahead: Duration::from_secs(announce_ahead),
+        };
+
+        let cors_origins = if cli.cors_origins.is_empty() {
+            file.http_cors_origins.unwrap_or_default()
+        } else {
+            cli.cors_origins
+        };
+        for origin in &cors_origins {
+            check_url("http_cors_origins", origin)?;
+        }
+
+        let tls = match (cli.tls_cert, cli.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+            (Some(_), None) => return Err(ConfigError::Missing("--tls-key")),
+            (None, Some(_)) => return Err(ConfigError::Missing("--tls-cert")),
+            (None, None) => file.tls.map(|tls| TlsFiles {
+                cert: relative(tls.cert),
+                key: relative(tls.key),
+            }),
+        };
+
+        let challenge_ttl = file.challenge_ttl_secs.unwrap_or(DEFAULT_CHALLENGE_TTL_SECS);
+        if challenge_ttl == 0 {
+            return Err(ConfigError::Zero("challenge_ttl_secs"));
+        }
+
+        Ok(ServerConfig {
+            command: cli.command,
+            listen: cli
+                .listen
+                .or(file.listen)
+                .unwrap_or_else(|| DEFAULT_LISTEN.parse().expect("valid default address")),
+            http_listen: cli.http_listen.or(file.http_listen),
+            cors_origins,
+            tls,
+            metrics_listen: cli.metrics_listen.or(file.metrics_listen),
+            log_level: cli.log_level.or(file.log_level).unwrap_or_default(),
+            log_format: cli.log_format.or(file.log_format).unwrap_or_default(),
+            images,
+            credential_db,
+            db_cache,
+            keys,
+            key_rotation,
+            audit_log: cli.audit_log.or(file.audit_log.map(relative)),
+            tickets,
+            challenge_ttl: Duration::from_secs(challenge_ttl),
+            clock_skew: Duration::from_secs(clock_skew),
+            limits: file.limits.resolve()?,
+            receipts,
+        })
+    }
+}
+
+impl ImageIdSpec {
+    fn into_digest(self) -> Result<Digest, ConfigError> {
+        match self {
+            ImageIdSpec::Words(words) => Ok(Digest::from(words)),
+            ImageIdSpec::Hex(hex) => parse_image_id(&hex).map_err(|e| ConfigError::ImageId(hex, e)),
+        }
+    }
+}
+
+impl FileImage {
+    fn resolve(self) -> Result<AcceptedImage, ConfigError> {
+        let entry = match self {
+            FileImage::Id(id) => return id.into_digest().map(AcceptedImage::new),
+            FileImage::Entry(entry) => entry,
+        };
+        let id = entry.id.into_digest()?;
+        let journal = match entry.journal_schema {
+            Some(version) => JournalSchema::from_version(version).ok_or(ConfigError::JournalSchema(id, version))?,
+            None => JournalSchema::CURRENT,
+        };
+        let default = AcceptedImage::new(id);
+        Ok(AcceptedImage {
+            label: entry.label.unwrap_or(default.label),
+            journal,
+            sunset_at: entry.sunset_at,
+            ..default
+        })
+    }
+}
+
+impl FileLimits {
+    fn resolve(self) -> Result<Limits, ConfigError> {
+        fn secs(value: Option<u64>, default: Duration, name: &'static str) -> Result<Duration, ConfigError> {
+            match value {
+                Some(0) => Err(ConfigError::Zero(name)),
+                Some(secs) => Ok(Duration::from_secs(secs)),
+                None => Ok(default),
+            }
+        }
+        fn count<T: Default + PartialEq>(value: Option<T>, default: T, name: &'static str) -> Result<T, ConfigError> {
+            match value {
+                Some(v) if v == T::default() => Err(ConfigError::Zero(name)),
+                Some(v) => Ok(v),
+                None => Ok(default),
+            }
+        }
+
+        // Frames are never read past the protocol's own cap, leaving room for the `AsReq` head.
+        fn bytes(value: Option<u32>, default: u32, name: &'static str) -> Result<u32, ConfigError> {
+            match value {
+                Some(v) if v > MAX_FRAME_LEN / 2 => Err(ConfigError::TooLarge(name, MAX_FRAME_LEN / 2)),
+                other => count(other, default, name),
+            }
+        }
+
+        let defaults = Limits::default();
+        let pow_min_bits = self.pow_min_bits.unwrap_or(defaults.pow_min_bits);
+        let pow_max_bits = self.pow_max_bits.unwrap_or(defaults.pow_max_bits);
+        if pow_min_bits > pow_max_bits || pow_max_bits > MAX_DIFFICULTY {
+            return Err(ConfigError::PowBits);
+        }
+        Ok(Limits {
+            idle_timeout: secs(self.idle_timeout_secs, defaults.idle_timeout, "limits.idle_timeout_secs")?,
+            read_timeout: secs(self.read_timeout_secs, defaults.read_timeout, "limits.read_timeout_secs")?,
+            write_timeout: secs(self.write_timeout_secs, defaults.write_timeout, "limits.write_timeout_secs")?,
+            max_requests: count(self.max_requests_per_connection, defaults.max_requests, "limits.max_requests_per_connection")?,
+            max_connections: count(self.max_connections, defaults.max_connections, "limits.max_connections")?,
+            verify_workers: count(self.verify_workers, defaults.verify_workers, "limits.verify_workers")?,
+            verify_queue_timeout: secs(
+                self.verify_queue_timeout_secs,
+                defaults.verify_queue_timeout,
+                "limits.verify_queue_timeout_secs",
+            )?,
+            max_pending_challenges: count(
+                self.max_pending_challenges,
+                defaults.max_pending_challenges,
+                "limits.max_pending_challenges",
+            )?,
+            max_replay_cache: count(self.max_replay_cache, defaults.max_replay_cache, "limits.max_replay_cache")?,
+            pow_min_bits,
+            pow_max_bits,
+            rate_limit_per_minute: count(
+                self.rate_limit_per_minute,
+                defaults.rate_limit_per_minute,
+                "limits.rate_limit_per_minute",
+            )?,
+            rate_limit_burst: count(self.rate_limit_burst, defaults.rate_limit_burst, "limits.rate_limit_burst")?,
+            max_rate_limited_sources: count(
+                self.max_rate_limited_sources,
+                defaults.max_rate_limited_sources,
+                "limits.max_rate_limited_sources",
+            )?,
+            max_message_bytes: bytes(self.max_message_bytes, defaults.max_message_bytes, "limits.max_message_bytes")?,
+            max_receipt_bytes: bytes(self.max_receipt_bytes, defaults.max_receipt_bytes, "limits.max_receipt_bytes")?,
+        })
+    }
+}
+
+fn check_url(name: &'static str, url: &str) -> Result<(), ConfigError> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(ConfigError::Url(name, url.to_owned()))
+    }
+}
+
+fn parse_sha256(hex: String) -> Result<[u8; 32], ConfigError> {
+    <[u8; 32]>::from_hex(hex.trim()).map_err(|_| ConfigError::Sha256(hex))
+}
+
+/// Accepts `[w0, w1, ..., w7]` (how the host prints `RISC0_CIRCUIT_ID`) or 64 hex digits.
+pub fn parse_image_id(s: &str) -> Result<Digest, String> {
+    let s = s.trim();
+    if s.contains(',') {
+        let words = s
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .split(',')
+            .map(|w| w.trim().parse::<u32>().map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let words: [u32; 8] = words
+            .try_into()
+            .map_err(|w: Vec<u32>| format!("expected 8 words, got {}", w.len()))?;
+        return Ok(Digest::from(words));
+    }
+    Digest::from_hex(s).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE_ID: &str = "0101010102020202030303030404040405050505060606060707070708080808";
+
+    /// Loads a config file made of `top` (top-level keys) and `tables`, after
+    /// the minimum every server needs, with `args` on the command line.
+    fn load(dir: &Path, top: &str, tables: &str, args: &[&str]) -> Result<ServerConfig, ConfigError> {
+        let path = dir.join("kdc.toml");
+        let text = format!(
+            r#"
+image_ids = ["{IMAGE_ID}"]
+{top}
+[credential_db]
+source = "file"
+path = "db.txt"
+[keys]
+signing_keyring = "signing"
+root_key = "root.key"
+tgt_key = "tgt.key"
+{tables}
+"#
+        );
+        std::fs::write(&path, text).unwrap();
+        let mut cli = vec!["zkk_server", "--config", path.to_str().unwrap()];
+        cli.extend(args);
+        ServerConfig::load(Cli::try_parse_from(cli).unwrap())
+    }
+
+    #[test]
+    fn unknown_field_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load(dir.path(), "", "", &[]).is_ok());
+        let err = load(dir.path(), "lisen = \"127.0.0.1:7878\"", "", &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }), "{err}");
+        let err = load(dir.path(), "", "[limits]\nmax_conections = 1", &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }), "{err}");
+        let err = load(dir.path(), "", "[services.webmail]\nkey = \"webmail.key\"\nlifetime = 60", &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }), "{err}");
+    }
+
+    #[test]
+    fn relative_paths_resolve_against_the_config_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = load(
+            dir.path(),
+            "audit_log = \"audit.log\"",
+            "[db_cache]\ndir = \"cache\"\n[tls]\ncert = \"tls/server.pem\"\nkey = \"/etc/zkk/server.key\"\n[services.webmail]\nkey = \"services/webmail.key\"",
+            &[],
+        )
+        .unwrap();
+        let dir = dir.path();
+        assert!(matches!(&config.credential_db, CredentialDbConfig::File { path, .. } if path == &dir.join("db.txt")));
+        assert_eq!(config.keys.signing_keyring, dir.join("signing"));
+        assert_eq!(config.keys.root_key, dir.join("root.key"));
+        assert_eq!(config.keys.tgt_key, dir.join("tgt.key"));
+        assert_eq!(config.keys.services["webmail"], dir.join("services/webmail.key"));
+        assert_eq!(config.audit_log, Some(dir.join("audit.log")));
+        assert_eq!(config.db_cache.dir, Some(dir.join("cache")));
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.cert, dir.join("tls/server.pem"));
+        assert_eq!(tls.key, PathBuf::from("/etc/zkk/server.key"));
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let other_id = "09".repeat(32);
+        let config = load(
+            dir.path(),
+            "listen = \"127.0.0.1:1000\"\nticket_lifetime_secs = 600\nhttp_cors_origins = [\"https://file.example\"]",
+            "",
+            &[
+                "--listen",
+                "127.0.0.1:2000",
+                "--ticket-lifetime",
+                "300",
+                "--image-id",
+                &other_id,
+                "--cors-origin",
+                "https://cli.example",
+                "--tgt-key",
+                "cli/tgt.key",
+                "--db-url",
+                "https://db.example/db.txt",
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.listen, "127.0.0.1:2000".parse().unwrap());
+        assert_eq!(config.tickets.default_lifetime, Duration::from_secs(300));
+        assert_eq!(config.images.len(), 1);
+        assert_eq!(config.images[0].id, parse_image_id(&other_id).unwrap());
+        assert_eq!(config.cors_origins, ["https://cli.example"]);
+        // Flags are taken as given, relative to where the server runs.
+        assert_eq!(config.keys.tgt_key, PathBuf::from("cli/tgt.key"));
+        assert!(matches!(&config.credential_db, CredentialDbConfig::Http { url, .. } if url == "https://db.example/db.txt"));
+    }
+
+    #[test]
+    fn overlap_shorter_than_the_longest_ticket_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let top = "ticket_lifetime_secs = 3600\nclock_skew_secs = 300";
+        let service = "[services.webmail]\nkey = \"webmail.key\"\nticket_lifetime_secs = 7200";
+        let err = load(dir.path(), top, "rotation_overlap_secs = 3899", &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::Overlap { overlap: 3899, needed: 3900 }), "{err}");
+        let config = load(dir.path(), top, "rotation_overlap_secs = 3900", &[]).unwrap();
+        assert_eq!(config.key_rotation.overlap, Duration::from_secs(3900));
+
+        // A service's own lifetime counts too.
+        let err = load(dir.path(), top, &format!("rotation_overlap_secs = 3900\n{service}"), &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::Overlap { overlap: 3900, needed: 7500 }), "{err}");
+        // Unset, it covers the longest ticket.
+        let config = load(dir.path(), top, service, &[]).unwrap();
+        assert_eq!(config.key_rotation.overlap, Duration::from_secs(7500));
+    }
+
+    #[test]
+    fn bad_image_id_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kdc.toml");
+        let rest = "[credential_db]\nsource = \"file\"\npath = \"db.txt\"\n[keys]\nsigning_keyring = \"s\"\nroot_key = \"r\"\ntgt_key = \"t\"";
+        for id in ["\"0g\"", "\"0101\"", &format!("{{ id = \"{}zz\" }}", &IMAGE_ID[2..])] {
+            std::fs::write(&path, format!("image_ids = [{id}]\n{rest}")).unwrap();
+            let cli = Cli::parse_from(["zkk_server", "--config", path.to_str().unwrap()]);
+            let err = ServerConfig::load(cli).unwrap_err();
+            assert!(matches!(err, ConfigError::ImageId(..)), "{id}: {err}");
+        }
+        assert!(Cli::try_parse_from(["zkk_server", "--image-id", "not hex"]).is_err());
+        assert!(Cli::try_parse_from(["zkk_server", "--image-id", "[1, 2, 3]"]).is_err());
+    }
+
+    #[test]
+    fn pow_min_bits_above_max_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = load(dir.path(), "", "[limits]\npow_min_bits = 12\npow_max_bits = 10", &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::PowBits), "{err}");
+        let err = load(dir.path(), "", &format!("[limits]\npow_max_bits = {}", MAX_DIFFICULTY + 1), &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::PowBits), "{err}");
+        let config = load(dir.path(), "", "[limits]\npow_min_bits = 10\npow_max_bits = 10", &[]).unwrap();
+        assert_eq!((config.limits.pow_min_bits, config.limits.pow_max_bits), (10, 10));
+    }
+
+    #[test]
+    fn only_serving_needs_an_image_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kdc.toml");
+        let rest = "[credential_db]\nsource = \"file\"\npath = \"db.txt\"\n[keys]\nsigning_keyring = \"s\"\nroot_key = \"r\"\ntgt_key = \"t\"";
+        std::fs::write(&path, rest).unwrap();
+        let path = path.to_str().unwrap();
+        for args in [&["keys", "list"][..], &["audit", "verify"], &["tls", "dev-certs"]] {
+            let cli = Cli::try_parse_from([&["zkk_server", "--config", path][..], args].concat()).unwrap();
+            let config = ServerConfig::load(cli).unwrap();
+            assert!(config.images.is_empty(), "{args:?}");
+        }
+        let err = ServerConfig::load(Cli::parse_from(["zkk_server", "--config", path])).unwrap_err();
+        assert!(matches!(err, ConfigError::NoImageIds), "{err}");
+        let cli = Cli::parse_from(["zkk_server", "--config", path, "--image-id", IMAGE_ID]);
+        assert_eq!(ServerConfig::load(cli).unwrap().images.len(), 1);
+    }
+}
@@ -0,0 +1,341 @@
+//! Per-connection lifecycle.
+//!
+//! A connection moves through
+//!
+//! ```text
+//! Idle --first byte--> Reading --frame--> Writing --+--> Idle
+//!   |                     |                  |      |
+//!   +------------------+--+------------------+      +--> Closed (request limit / error sent)
+//!                      v
+//!                   Closed
+//! ```
+//!
+//! `Idle` is bounded by the idle timeout. Once the first byte of a frame has
+//! arrived the whole frame must be read before the read deadline, and every
+//! reply must be written before the write deadline, so a peer trickling one
+//! byte at a time cannot hold a task past those bounds.
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use tracing::{debug, error, info_span, trace, warn, Instrument};
+use zkk_protocol::frame::{FrameHeader, Hello, HelloAck, HEADER_LEN, MAX_FRAME_LEN};
+use zkk_protocol::{Frame, FrameError, KdcError, MessageKind};
+
+use crate::kdc::{self, Kdc};
+use crate::keys;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// How long to wait for the first byte of the next frame.
+    pub idle_timeout: Duration,
+    /// How long a peer has to deliver a whole frame once it started sending.
+    pub read_timeout: Duration,
+    /// How long a peer has to accept a whole reply.
+    pub write_timeout: Duration,
+    /// Requests answered, `Hello` included, before the server closes the connection.
+    pub max_requests: u32,
+    /// Connections served at once; further peers get `KdcError::Busy`.
+    pub max_connections: usize,
+    /// Proofs verified at once.
+    pub verify_workers: usize,
+    /// How long a request may wait for a free verification worker.
+    pub verify_queue_timeout: Duration,
+    /// Challenge nonces issued but not yet expired; further `ChallengeReq`s get `KdcError::Busy`.
+    pub max_pending_challenges: usize,
+    /// TGS authenticators remembered for replay detection; when full, further `TgsReq`s get `KdcError::Busy`.
+    pub max_replay_cache: usize,
+    /// Proof-of-work difficulty, in bits, of challenges issued while the verifier pool is idle.
+    pub pow_min_bits: u8,
+    /// Difficulty once as many proofs wait for a worker as there are workers.
+    pub pow_max_bits: u8,
+    /// `ChallengeReq`s, `AsReq`s and `TgsReq`s each source may send per minute on average.
+    pub rate_limit_per_minute: u32,
+    /// Requests a source may send at once after being quiet.
+    pub rate_limit_burst: u32,
+    /// Sources tracked by the rate limiter; when every one is still limited, new ones get `KdcError::Busy`.
+    pub max_rate_limited_sources: usize,
+    /// Largest payload accepted for any frame but `AsReq`.
+    pub max_message_bytes: u32,
+    /// Largest receipt accepted in an `AsReq`.
+    pub max_receipt_bytes: u32,
+}
+
+/// Room for the `AsReqHead` in front of the receipt.
+const AS_REQ_HEAD_BYTES: u32 = 128;
+
+impl Limits {
+    /// Largest payload read for a frame of `kind`; longer ones are refused
+    /// before anything is buffered.
+    pub fn max_payload(&self, kind: MessageKind) -> u32 {
+        match kind {
+            MessageKind::AsReq => self.max_receipt_bytes + AS_REQ_HEAD_BYTES,
+            _ => self.max_message_bytes,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            idle_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(10),
+            max_requests: 8,
+            max_connections: 256,
+            verify_workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            verify_queue_timeout: Duration::from_secs(5),
+            max_pending_challenges: 65536,
+            max_replay_cache: 65536,
+            pow_min_bits: 8,
+            pow_max_bits: 20,
+            rate_limit_per_minute: 30,
+            rate_limit_burst: 20,
+            max_rate_limited_sources: 65536,
+            max_message_bytes: 64 * 1024,
+            max_receipt_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// The first header byte has arrived.
+    Reading(u8),
+    Writing,
+    Closed(CloseReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer closed its side between frames.
+    PeerClosed,
+    IdleTimeout,
+    /// The peer stalled part way through a frame.
+    ReadTimeout,
+    /// The peer stopped accepting our reply.
+    WriteTimeout,
+    RequestLimit,
+    /// An `Error` frame was sent; it is always the last frame.
+    Rejected(KdcError),
+    Io,
+}
+
+pub struct Connection<S> {
+    stream: S,
+    peer: IpAddr,
+    limits: Limits,
+    kdc: Arc<Kdc>,
+    state: State,
+    version: u16,
+    served: u32,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S, peer: IpAddr, limits: Limits, kdc: Arc<Kdc>) -> Self {
+        Connection {
+            stream,
+            peer,
+            limits,
+            kdc,
+            state: State::Idle,
+            version: zkk_protocol::MIN_PROTOCOL_VERSION,
+            served: 0,
+        }
+    }
+
+    /// Drives the connection until it closes and reports why.
+    pub async fn run(mut self) -> CloseReason {
+        loop {
+            trace!(state = ?self.state, "Connection state");
+            self.state = match self.state {
+                State::Idle => self.wait_for_frame().await,
+                State::Reading(first) => match self.read_frame(first).await {
+                    Ok(frame) => {
+                        let span = info_span!("request", kind = ?frame.kind, version = frame.version);
+                        self.dispatch(frame).instrument(span).await
+                    }
+                    Err(reason) => State::Closed(reason),
+                },
+                State::Writing => unreachable!("writes complete inside `send`"),
+                State::Closed(reason) => return self.close(reason).await,
+            };
+        }
+    }
+
+    /// Turns the peer away without reading anything, e.g. when the server is full.
+    pub async fn refuse(mut self, error: KdcError) -> CloseReason {
+        let reason = self.reject(error).await;
+        self.close(reason).await
+    }
+
+    async fn wait_for_frame(&mut self) -> State {
+        if self.served >= self.limits.max_requests {
+            return State::Closed(CloseReason::RequestLimit);
+        }
+        let mut first = [0u8; 1];
+        match timeout(self.limits.idle_timeout, self.stream.read(&mut first)).await {
+            Ok(Ok(0)) => State::Closed(CloseReason::PeerClosed),
+            Ok(Ok(_)) => State::Reading(first[0]),
+            Ok(Err(_)) => State::Closed(CloseReason::Io),
+            Err(_) => State::Closed(CloseReason::IdleTimeout),
+        }
+    }
+
+    async fn read_frame(&mut self, first: u8) -> Result<Frame, CloseReason> {
+        let read = async {
+            let mut raw = [0u8; HEADER_LEN];
+            raw[0] = first;
+            self.stream.read_exact(&mut raw[1..]).await?;
+            let header = FrameHeader::parse(&raw, MAX_FRAME_LEN)?;
+            let max = self.limits.max_payload(header.kind);
+            if header.len > max {
+                return Err(FrameError::TooLarge { len: header.len, max });
+            }
+            let mut payload = vec![0u8; header.len as usize];
+            self.stream.read_exact(&mut payload).await?;
+            Ok::<_, FrameError>(Frame {
+                version: header.version,
+                kind: header.kind,
+                payload,
+            })
+        };
+        match timeout(self.limits.read_timeout, read).await {
+            Ok(Ok(frame)) => {
+                debug!(kind = ?frame.kind, version = frame.version, len = frame.payload.len(), "Frame received");
+                Ok(frame)
+            }
+            Ok(Err(FrameError::Io(_))) => Err(CloseReason::Io),
+            Ok(Err(e)) => {
+                let reason = e.to_string();
+                Err(self.reject(kdc::reject(e.into(), reason)).await)
+            }
+            Err(_) => Err(CloseReason::ReadTimeout),
+        }
+    }
+
+    async fn dispatch(&mut self, frame: Frame) -> State {
+        if !frame.kind.is_negotiation() {
+            // Peers that skip `Hello` are answered in the version they used.
+            self.version = frame.version;
+        }
+        let costly = matches!(frame.kind, MessageKind::ChallengeReq | MessageKind::AsReq | MessageKind::TgsReq);
+        if costly {
+            if let Err(error) = self.kdc.admit(self.peer) {
+                return State::Closed(self.reject(error).await);
+            }
+        }
+        match frame.kind {
+            MessageKind::Hello => {
+                // Counted like any request, so looping `Hello` cannot hold the slot.
+                self.served += 1;
+                let peer: Hello = match frame.body() {
+                    Ok(peer) => peer,
+                    Err(e) => return State::Closed(self.reject(kdc::reject(KdcError::BadFraming, e)).await),
+                };
+                let reply = match Hello::supported().negotiate(&peer) {
+                    Some(version) => {
+                        debug!(version, "Negotiated protocol version");
+                        self.version = version;
+                        Frame::new(version, MessageKind::HelloAck, &HelloAck { version })
+                    }
+                    None => {
+                        debug!(?peer, "No common protocol version");
+                        Frame::new(frame.version, MessageKind::VersionRejected, &Hello::supported())
+                    }
+                };
+                match reply {
+                    Ok(reply) => self.send(&reply).await,
+                    Err(e) => State::Closed(self.reject(kdc::reject(KdcError::Internal, e)).await),
+                }
+            }
+            MessageKind::ChallengeReq => {
+                self.served += 1;
+                let reply = self
+                    .kdc
+                    .challenge()
+                    .and_then(|challenge| {
+                        Frame::new(self.version, MessageKind::Challenge, &challenge)
+                            .map_err(|e| kdc::reject(KdcError::Internal, e))
+                    });
+                match reply {
+                    Ok(reply) => self.send(&reply).await,
+                    Err(error) => State::Closed(self.reject(error).await),
+                }
+            }
+            MessageKind::KeysReq => {
+                self.served += 1;
+                let reply = keys::key_set()
+                    .and_then(|set| Frame::new(self.version, MessageKind::Keys, &set))
+                    .map_err(|e| kdc::reject(KdcError::Internal, e));
+                match reply {
+                    Ok(reply) => self.send(&reply).await,
+                    Err(error) => State::Closed(self.reject(error).await),
+                }
+            }
+            MessageKind::AsReq => {
+                self.served += 1;
+                let reply = self.kdc.as_req(frame.payload).await.and_then(|sealed| {
+                    Frame::new(self.version, MessageKind::AsRep, &sealed).map_err(|e| kdc::reject(KdcError::Internal, e))
+                });
+                match reply {
+                    Ok(reply) => self.send(&reply).await,
+                    Err(error) => State::Closed(self.reject(error).await),
+                }
+            }
+            MessageKind::TgsReq => {
+                self.served += 1;
+                let reply = self.kdc.tgs_req(frame.payload).await.and_then(|sealed| {
+                    Frame::new(self.version, MessageKind::TgsRep, &sealed).map_err(|e| kdc::reject(KdcError::Internal, e))
+                });
+                match reply {
+                    Ok(reply) => self.send(&reply).await,
+                    Err(error) => State::Closed(self.reject(error).await),
+                }
+            }
+            other => State::Closed(self.reject(kdc::reject(KdcError::UnexpectedMessage, format!("{:?}", other))).await),
+        }
+    }
+
+    async fn send(&mut self, frame: &Frame) -> State {
+        self.state = State::Writing;
+        let write = async {
+            self.stream.write_all(&frame.to_bytes()).await?;
+            self.stream.flush().await
+        };
+        match timeout(self.limits.write_timeout, write).await {
+            Ok(Ok(())) => State::Idle,
+            Ok(Err(_)) => State::Closed(CloseReason::Io),
+            Err(_) => State::Closed(CloseReason::WriteTimeout),
+        }
+    }
+
+    /// Best-effort `Error` frame; the connection is closed afterwards either way.
+    async fn reject(&mut self, error: KdcError) -> CloseReason {
+        debug!(code = error.code(), %error, "Sending error");
+        match kdc::error_reply(error).and_then(|reply| Frame::new(self.version, MessageKind::Error, &reply)) {
+            Ok(frame) => {
+                if let State::Closed(reason) = self.send(&frame).await {
+                    return reason;
+                }
+            }
+            Err(e) => error!("Failed to encode error reply: {}", e),
+        }
+        CloseReason::Rejected(error)
+    }
+
+    async fn close(mut self, reason: CloseReason) -> CloseReason {
+        debug!(requests = self.served, ?reason, "Closing connection");
+        if let Err(e) = timeout(self.limits.write_timeout, self.stream.shutdown()).await.unwrap_or(Ok(())) {
+            if e.kind() != io::ErrorKind::NotConnected {
+                warn!("Failed to shutdown connection: {}", e);
+            }
+        }
+        reason
+    }
+}
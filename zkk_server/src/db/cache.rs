@@ -0,0 +1,260 @@
+//! Verified, content-addressed copy of the credential database.
+//!
+//! Bytes from a source are only used once their sha256 matches the digest
+//! the source promised (for IPFS, the one inside the CID), so a compromised
+//! gateway cannot swap the database. Verified copies are kept in memory and,
+//! if a cache directory is configured, on disk as `<hex sha256>` files. Disk
+//! writes go through a temporary file and a rename, so concurrent readers
+//! never see a partial copy.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info, warn};
+
+use super::CredentialDbSource;
+use crate::stats;
+
+/// A copy whose digest has been checked.
+pub struct VerifiedDb {
+    pub digest: [u8; 32],
+    pub bytes: Vec<u8>,
+}
+
+pub struct DbCache {
+    source: Box<dyn CredentialDbSource>,
+    dir: Option<PathBuf>,
+    current: RwLock<Option<Arc<VerifiedDb>>>,
+    /// Serialises fetches so a burst of requests on a cold cache hits the backend once.
+    fetching: Mutex<()>,
+}
+
+impl DbCache {
+    pub fn new(source: Box<dyn CredentialDbSource>, dir: Option<PathBuf>) -> Self {
+        DbCache {
+            source,
+            dir,
+            current: RwLock::new(None),
+            fetching: Mutex::new(()),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        self.source.describe()
+    }
+
+    /// The current verified copy, loading it first if there is none yet. May block.
+    pub fn get(&self) -> anyhow::Result<Arc<VerifiedDb>> {
+        if let Some(db) = self.current() {
+            stats::db_lookup("memory");
+            return Ok(db);
+        }
+        let _fetching = self.fetching.lock().expect("credential DB fetch lock poisoned");
+        match self.current() {
+            Some(db) => {
+                stats::db_lookup("memory");
+                Ok(db)
+            }
+            None => {
+                let (db, source) = self.load_or_fetch()?;
+                stats::db_lookup(source);
+                Ok(db)
+            }
+        }
+    }
+
+    /// Makes the latest verified copy current. Content pinned by digest never
+    /// changes, so it is fetched at most once. May block.
+    pub fn refresh(&self) -> anyhow::Result<Arc<VerifiedDb>> {
+        let _fetching = self.fetching.lock().expect("credential DB fetch lock poisoned");
+        match self.current() {
+            Some(db) if self.source.pinned_digest() == Some(db.digest) => Ok(db),
+            Some(_) => self.fetch_verified(),
+            None => self.load_or_fetch().map(|(db, _)| db),
+        }
+    }
+
+    /// Refreshes every `interval` on the runtime's blocking pool, starting
+    /// now so the first request finds a warm cache. Failures keep the copy
+    /// already in use.
+    pub fn spawn_refresh(self: &Arc<Self>, interval: Duration) {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let job = cache.clone();
+                match tokio::task::spawn_blocking(move || job.refresh()).await {
+                    Ok(Ok(db)) => debug!(digest = %hex::encode(db.digest), "Credential database refreshed"),
+                    Ok(Err(e)) => error!(source = %cache.describe(), "Failed to refresh credential database: {:#}", e),
+                    Err(e) => error!("Credential database refresh task failed: {}", e),
+                }
+            }
+        });
+    }
+
+    fn current(&self) -> Option<Arc<VerifiedDb>> {
+        self.current.read().expect("credential DB cache poisoned").clone()
+    }
+
+    fn install(&self, db: VerifiedDb) -> Arc<VerifiedDb> {
+        let db = Arc::new(db);
+        *self.current.write().expect("credential DB cache poisoned") = Some(db.clone());
+        db
+    }
+
+    /// Caller holds `fetching`. Also returns where the copy came from,
+    /// `disk` or `fetch`.
+    fn load_or_fetch(&self) -> anyhow::Result<(Arc<VerifiedDb>, &'static str)> {
+        if let Some(db) = self.source.pinned_digest().and_then(|digest| self.load(digest)) {
+            info!(digest = %hex::encode(db.digest), "Using credential database from disk cache");
+            return Ok((self.install(db), "disk"));
+        }
+        self.fetch_verified().map(|db| (db, "fetch"))
+    }
+
+    /// Caller holds `fetching`.
+    fn fetch_verified(&self) -> anyhow::Result<Arc<VerifiedDb>> {
+        let started = Instant::now();
+        let fetched = self.source.fetch();
+        stats::db_fetched(started.elapsed(), fetched.is_ok());
+        let fetched = fetched?;
+        let digest = fetched.sha256();
+        if let Some(expected) = fetched.expected_digest {
+            if digest != expected {
+                bail!("fetched content has sha256 {}, expected {}", hex::encode(digest), hex::encode(expected));
+            }
+        }
+        if let Some(dir) = &self.dir {
+            if let Err(e) = store(dir, digest, &fetched.bytes) {
+                warn!("Failed to write credential database to disk cache: {:#}", e);
+            }
+        }
+        let changed = self.current().is_none_or(|db| db.digest != digest);
+        if changed {
+            info!(digest = %hex::encode(digest), source = %self.source.describe(), "Using credential database");
+        }
+        Ok(self.install(VerifiedDb {
+            digest,
+            bytes: fetched.bytes,
+        }))
+    }
+
+    /// A copy on disk, if there is one and it still hashes to `digest`.
+    /// A corrupt copy is removed, so the next verified fetch replaces it.
+    fn load(&self, digest: [u8; 32]) -> Option<VerifiedDb> {
+        let path = self.dir.as_ref()?.join(hex::encode(digest));
+        let bytes = std::fs::read(&path).ok()?;
+        if <[u8; 32]>::from(Sha256::digest(&bytes)) != digest {
+            warn!(digest = %hex::encode(digest), "Removing corrupt disk cache entry");
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+            return None;
+        }
+        Some(VerifiedDb { digest, bytes })
+    }
+}
+
+fn store(dir: &Path, digest: [u8; 32], bytes: &[u8]) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let name = hex::encode(digest);
+    let path = dir.join(&name);
+    if path.exists() {
+        return Ok(());
+    }
+    let tmp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+    std::fs::write(&tmp, bytes).with_context(|| format!("failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("failed to rename {}", tmp.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::db::FetchedDb;
+
+    const DB: &[u8] = b"alice 0123\nbob 4567\n";
+
+    /// Serves `bytes`, promising `pinned` up front, and counts its fetches.
+    struct FakeSource {
+        bytes: Vec<u8>,
+        pinned: Option<[u8; 32]>,
+        fetches: Arc<AtomicUsize>,
+    }
+
+    impl CredentialDbSource for FakeSource {
+        fn describe(&self) -> String {
+            "fake".to_owned()
+        }
+
+        fn pinned_digest(&self) -> Option<[u8; 32]> {
+            self.pinned
+        }
+
+        fn fetch(&self) -> anyhow::Result<FetchedDb> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            Ok(FetchedDb {
+                bytes: self.bytes.clone(),
+                expected_digest: self.pinned,
+            })
+        }
+    }
+
+    fn pinned_cache(bytes: &[u8], pinned: [u8; 32], dir: &Path) -> (DbCache, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let source = FakeSource {
+            bytes: bytes.to_vec(),
+            pinned: Some(pinned),
+            fetches: fetches.clone(),
+        };
+        (DbCache::new(Box::new(source), Some(dir.to_owned())), fetches)
+    }
+
+    fn digest(bytes: &[u8]) -> [u8; 32] {
+        Sha256::digest(bytes).into()
+    }
+
+    #[test]
+    fn pinned_copy_on_disk_is_used_without_fetching() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(hex::encode(digest(DB))), DB).unwrap();
+        let (cache, fetches) = pinned_cache(b"not fetched", digest(DB), dir.path());
+        let db = cache.get().unwrap();
+        assert_eq!((db.bytes.as_slice(), db.digest), (DB, digest(DB)));
+        assert_eq!(cache.refresh().unwrap().digest, digest(DB));
+        assert_eq!(fetches.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn corrupt_copy_on_disk_is_fetched_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(hex::encode(digest(DB)));
+        std::fs::write(&path, b"alice 0123\nmallory 0000\n").unwrap();
+        let (cache, fetches) = pinned_cache(DB, digest(DB), dir.path());
+        assert_eq!(cache.get().unwrap().bytes, DB);
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), DB);
+
+        // The repaired copy serves the next start.
+        let (cache, fetches) = pinned_cache(b"not fetched", digest(DB), dir.path());
+        assert_eq!(cache.get().unwrap().bytes, DB);
+        assert_eq!(fetches.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn fetch_not_matching_the_pinned_digest_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, fetches) = pinned_cache(b"alice 0123\nmallory 0000\n", digest(DB), dir.path());
+        let Err(error) = cache.get() else { panic!("unverified database accepted") };
+        assert!(error.to_string().contains(&format!("expected {}", hex::encode(digest(DB)))), "{:#}", error);
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        assert!(cache.current().is_none());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
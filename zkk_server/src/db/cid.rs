@@ -0,0 +1,138 @@
+//! The one CID shape the credential database is published as: CIDv1, `raw`
+//! codec, sha2-256 multihash, base32 multibase (`bafkrei…`).
+
+use std::fmt;
+use std::str::FromStr;
+
+const CID_V1: u8 = 0x01;
+const RAW_CODEC: u8 = 0x55;
+const SHA2_256: u8 = 0x12;
+const SHA2_256_LEN: u8 = 0x20;
+const BASE32_LOWER: char = 'b';
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum CidError {
+    #[error("CID must use the base32 multibase prefix `b`")]
+    Multibase,
+    #[error("CID is not valid base32")]
+    Base32,
+    #[error("CID must be version 1")]
+    Version,
+    #[error("CID must use the raw codec (0x55)")]
+    Codec,
+    #[error("CID must use a sha2-256 multihash")]
+    Hash,
+    #[error("CID multihash has the wrong length")]
+    Length,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Cid {
+    text: String,
+    digest: [u8; 32],
+}
+
+impl Cid {
+    /// The sha2-256 digest of the content this CID names.
+    pub fn digest(&self) -> [u8; 32] {
+        self.digest
+    }
+}
+
+impl FromStr for Cid {
+    type Err = CidError;
+
+    fn from_str(s: &str) -> Result<Self, CidError> {
+        let s = s.trim();
+        let encoded = s.strip_prefix(BASE32_LOWER).ok_or(CidError::Multibase)?;
+        let bytes = base32_decode(encoded).ok_or(CidError::Base32)?;
+        // Every field below fits a single-byte varint, so the layout is fixed.
+        match bytes.as_slice() {
+            [version, ..] if *version != CID_V1 => Err(CidError::Version),
+            [_, codec, ..] if *codec != RAW_CODEC => Err(CidError::Codec),
+            [_, _, hash, ..] if *hash != SHA2_256 => Err(CidError::Hash),
+            [_, _, _, len, digest @ ..] if *len == SHA2_256_LEN && digest.len() == 32 => Ok(Cid {
+                text: s.to_owned(),
+                digest: digest.try_into().expect("length checked"),
+            }),
+            _ => Err(CidError::Length),
+        }
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl fmt::Debug for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cid({})", self.text)
+    }
+}
+
+/// RFC 4648 base32, lowercase, no padding.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c {
+            b'a'..=b'z' => c - b'a',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    // Leftover bits are padding and must be zero.
+    (buffer == 0).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The database CID from the example `kdc.toml`.
+    const CONFIGURED: &str = "bafkreic6cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetvne";
+
+    #[test]
+    fn configured_cid_parses() {
+        let cid: Cid = CONFIGURED.parse().unwrap();
+        assert_eq!(hex::encode(cid.digest()), "5e16274bf955b68674848a24d37a024ab1fda45791974c872120e3260c927569");
+        assert_eq!(cid.to_string(), CONFIGURED);
+        assert_eq!(format!(" {} ", CONFIGURED).parse::<Cid>().unwrap(), cid);
+    }
+
+    #[test]
+    fn other_cid_shapes_are_refused() {
+        let cases = [
+            // CIDv0, and the same CID in base32 upper case and base58btc.
+            ("QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG", CidError::Multibase),
+            ("BAFKREIC6CYTUX6KVW2DHJBEKETJXUASKWH62IV4RS5GIOIJA4MTAZETVNE", CidError::Multibase),
+            ("zb2rhe5P4gXftAwvA4eXQ5HJwsER2owDyS9sKaQRRVQPn93bA", CidError::Multibase),
+            ("bafkreic6cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetvn1", CidError::Base32),
+            // Leftover bits that are not zero.
+            ("bafkreic6cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetvnf", CidError::Base32),
+            ("babkreic6cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetvne", CidError::Version),
+            // dag-pb, as `ipfs add` makes by default.
+            ("bafybeic6cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetvne", CidError::Codec),
+            // sha3-256.
+            ("bafkrmic6cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetvne", CidError::Hash),
+            // 31 digest bytes, with the length byte still saying 32 and then saying 31.
+            ("bafkreic6cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetv", CidError::Length),
+            ("bafkreh26cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetv", CidError::Length),
+            ("b", CidError::Length),
+            ("", CidError::Multibase),
+        ];
+        for (text, error) in cases {
+            assert_eq!(text.parse::<Cid>(), Err(error), "{text}");
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Where the credential database comes from.
+//!
+//! The guest commits the sha256 of the database it proved membership in; the
+//! KDC keeps its own verified copy in a [`DbCache`] filled from a
+//! [`CredentialDbSource`] and compares.
+//! Which backend is used is a deployment choice, see `[credential_db]` in
+//! `kdc.toml`.
+
+pub mod cache;
+pub mod cid;
+
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+pub use cache::{DbCache, VerifiedDb};
+pub use cid::Cid;
+
+use crate::config::CredentialDbConfig;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Database bytes as fetched, with the digest the source promised, if any.
+pub struct FetchedDb {
+    pub bytes: Vec<u8>,
+    pub expected_digest: Option<[u8; 32]>,
+}
+
+impl FetchedDb {
+    pub fn sha256(&self) -> [u8; 32] {
+        Sha256::digest(&self.bytes).into()
+    }
+}
+
+/// A backend the credential database can be fetched from. Fetches run on
+/// blocking threads and may block.
+pub trait CredentialDbSource: Send + Sync {
+    /// Where the database is fetched from, for logs.
+    fn describe(&self) -> String;
+
+    /// Digest known before fetching, for content that is pinned by hash. Lets a
+    /// verified copy on disk be used without touching the backend.
+    fn pinned_digest(&self) -> Option<[u8; 32]> {
+        None
+    }
+
+    fn fetch(&self) -> anyhow::Result<FetchedDb>;
+}
+
+/// Builds the source configured under `[credential_db]`, refusing
+/// databases over `max_bytes`.
+pub fn open(config: &CredentialDbConfig, max_bytes: u64) -> Box<dyn CredentialDbSource> {
+    match config {
+        CredentialDbConfig::File { path, sha256 } => Box::new(FileSource {
+            path: path.clone(),
+            sha256: *sha256,
+            max_bytes,
+        }),
+        CredentialDbConfig::Http { url, sha256 } => Box::new(HttpSource {
+            url: url.clone(),
+            sha256: *sha256,
+            max_bytes,
+            client: OnceCell::new(),
+        }),
+        CredentialDbConfig::Ipfs { gateway, cid } => Box::new(IpfsSource {
+            url: format!("{}/ipfs/{}", gateway.trim_end_matches('/'), cid),
+            cid: cid.clone(),
+            max_bytes,
+            client: OnceCell::new(),
+        }),
+    }
+}
+
+/// Reads `source` to the end, failing once it passes `max_bytes` instead of
+/// buffering the rest.
+fn read_capped(source: impl Read, max_bytes: u64, what: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    source
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .with_context(|| format!("failed to read {}", what))?;
+    if bytes.len() as u64 > max_bytes {
+        bail!("{} is larger than db_cache.max_bytes ({})", what, max_bytes);
+    }
+    Ok(bytes)
+}
+
+/// A file on local disk, for offline runs and tests.
+pub struct FileSource {
+    path: PathBuf,
+    sha256: Option<[u8; 32]>,
+    max_bytes: u64,
+}
+
+impl CredentialDbSource for FileSource {
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn pinned_digest(&self) -> Option<[u8; 32]> {
+        self.sha256
+    }
+
+    fn fetch(&self) -> anyhow::Result<FetchedDb> {
+        let file =
+            std::fs::File::open(&self.path).with_context(|| format!("failed to read {}", self.path.display()))?;
+        Ok(FetchedDb {
+            bytes: read_capped(file, self.max_bytes, &self.path.display().to_string())?,
+            expected_digest: self.sha256,
+        })
+    }
+}
+
+/// A plain HTTP(S) URL.
+pub struct HttpSource {
+    url: String,
+    sha256: Option<[u8; 32]>,
+    max_bytes: u64,
+    client: OnceCell<reqwest::blocking::Client>,
+}
+
+impl CredentialDbSource for HttpSource {
+    fn describe(&self) -> String {
+        format!("url {}", self.url)
+    }
+
+    fn pinned_digest(&self) -> Option<[u8; 32]> {
+        self.sha256
+    }
+
+    fn fetch(&self) -> anyhow::Result<FetchedDb> {
+        Ok(FetchedDb {
+            bytes: http_get(&self.client, &self.url, self.max_bytes)?,
+            expected_digest: self.sha256,
+        })
+    }
+}
+
+/// Content-addressed fetch through an IPFS HTTP gateway, e.g. a local node on
+/// `http://127.0.0.1:8080`. The expected digest comes from the CID itself.
+pub struct IpfsSource {
+    url: String,
+    cid: Cid,
+    max_bytes: u64,
+    client: OnceCell<reqwest::blocking::Client>,
+}
+
+impl CredentialDbSource for IpfsSource {
+    fn describe(&self) -> String {
+        format!("ipfs {} via {}", self.cid, self.url)
+    }
+
+    fn pinned_digest(&self) -> Option<[u8; 32]> {
+        Some(self.cid.digest())
+    }
+
+    fn fetch(&self) -> anyhow::Result<FetchedDb> {
+        Ok(FetchedDb {
+            bytes: http_get(&self.client, &self.url, self.max_bytes)?,
+            expected_digest: Some(self.cid.digest()),
+        })
+    }
+}
+
+/// The blocking client is built on first use: building (and dropping) one
+/// inside the async runtime panics, fetches always run on a blocking thread.
+/// The body is streamed, so a server sending more than `max_bytes` is cut
+/// off rather than buffered.
+fn http_get(client: &OnceCell<reqwest::blocking::Client>, url: &str, max_bytes: u64) -> anyhow::Result<Vec<u8>> {
+    let client = client.get_or_try_init(|| reqwest::blocking::Client::builder().timeout(FETCH_TIMEOUT).build())?;
+    debug!(%url, "Fetching credential database");
+    let response = client.get(url).send()?.error_for_status()?;
+    if let Some(len) = response.content_length().filter(|&len| len > max_bytes) {
+        bail!("{} announced {} bytes, more than db_cache.max_bytes ({})", url, len, max_bytes);
+    }
+    let bytes = read_capped(response, max_bytes, url)?;
+    if bytes.is_empty() {
+        bail!("{} returned an empty body", url);
+    }
+    debug!(len = bytes.len(), "Fetched credential database");
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Serves one HTTP response with `body` and no `Content-Length`, so only
+    /// the streamed length can give it away.
+    fn serve_once(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/db", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n");
+            let _ = stream.write_all(&body);
+        });
+        url
+    }
+
+    #[test]
+    fn http_body_over_the_limit_is_refused() {
+        let url = serve_once(vec![b'a'; 4096]);
+        let error = http_get(&OnceCell::new(), &url, 1024).unwrap_err();
+        assert!(error.to_string().contains("larger than db_cache.max_bytes"), "{:#}", error);
+
+        let url = serve_once(vec![b'a'; 1024]);
+        assert_eq!(http_get(&OnceCell::new(), &url, 1024).unwrap().len(), 1024);
+    }
+
+    #[test]
+    fn file_over_the_limit_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.txt");
+        std::fs::write(&path, [b'a'; 100]).unwrap();
+        let config = CredentialDbConfig::File { path, sha256: None };
+        assert!(open(&config, 99).fetch().is_err());
+        assert_eq!(open(&config, 100).fetch().unwrap().bytes.len(), 100);
+    }
+}
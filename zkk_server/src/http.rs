@@ -0,0 +1,706 @@
+//! HTTP listener for clients that cannot speak the framed TCP protocol, such
+//! as the web client.
+//!
+//! `GET /v1/keys` serves the published key set (see [`crate::keys::key_set`]).
+//! `signed` holds the exact bytes the root key signed, the bincode encoding
+//! of the `KeySetBundle`; the other fields repeat its contents for
+//! readability.
+//!
+//! `POST /v1/challenge`, `/v1/as-req` and `/v1/tgs-req` are `ChallengeReq`,
+//! `AsReq` and `TgsReq` against the same [`Kdc`] as the TCP listener, with
+//! the same rate limits, proof-of-work check and audit log. Bodies are JSON,
+//! or CBOR when sent as `application/cbor`; replies use the format `Accept`
+//! names, else the request's. Binary fields are base64 text in JSON and byte
+//! strings in CBOR, which also accepts base64 text. A refused request gets an
+//! error status and [`ErrorJson`], carrying the signed `ErrorReply` an `Error`
+//! frame would. With `tls` configured this is HTTPS, on the same certificate
+//! as the KDC listener.
+//!
+//! Connections share the KDC listener's `max_connections` and timeouts, and
+//! the rate limit is taken before a body is read.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::connect_info::Connected;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, FromRequest, FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::serve::IncomingStream;
+use axum::Router;
+use base64::Engine;
+use serde::de::{DeserializeOwned, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, Sleep};
+use tokio_rustls::TlsAcceptor;
+use tower_http::cors::CorsLayer;
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::timeout::TimeoutLayer;
+use tracing::{debug, error, info, info_span, Instrument};
+use zkk_protocol::{AsReqHead, Authenticator, KdcError, KeyId, KeySetReply, PowStamp, TgsRequest};
+
+use crate::connection::Limits;
+use crate::kdc::{self, Kdc};
+use crate::keys;
+use crate::stats;
+use crate::tls;
+
+const CBOR: &str = "application/cbor";
+const JSON: &str = "application/json";
+
+/// Bytes, as base64 text in JSON and a byte string in CBOR.
+#[derive(Debug)]
+struct Binary(Vec<u8>);
+
+impl Binary {
+    fn array<const N: usize>(self, field: &str) -> Result<[u8; N], KdcError> {
+        let len = self.0.len();
+        self.0
+            .try_into()
+            .map_err(|_| kdc::reject(KdcError::BadFraming, format!("{} of {} bytes, expected {}", field, len, N)))
+    }
+}
+
+impl Serialize for Binary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Binary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BinaryVisitor;
+
+        impl Visitor<'_> for BinaryVisitor {
+            type Value = Binary;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("base64 text or a byte string")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<Binary, E> {
+                base64::engine::general_purpose::STANDARD
+                    .decode(text)
+                    .map(Binary)
+                    .map_err(E::custom)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Binary, E> {
+                Ok(Binary(bytes.to_vec()))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<Binary, E> {
+                Ok(Binary(bytes))
+            }
+        }
+
+        deserializer.deserialize_any(BinaryVisitor)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct KeySetJson {
+    keys: Vec<PublishedKeyJson>,
+    issued_at: u64,
+    expires_at: u64,
+    signed: Binary,
+    signature: Binary,
+}
+
+#[derive(Debug, Serialize)]
+struct PublishedKeyJson {
+    key_id: KeyId,
+    public_key: Binary,
+    not_before: u64,
+    not_after: Option<u64>,
+}
+
+impl KeySetJson {
+    fn new(reply: &KeySetReply) -> Result<Self, bincode::error::EncodeError> {
+        let bundle = &reply.signature_data;
+        Ok(KeySetJson {
+            keys: bundle
+                .keys
+                .iter()
+                .map(|key| PublishedKeyJson {
+                    key_id: key.key_id,
+                    public_key: Binary(key.public_key.to_vec()),
+                    not_before: key.not_before,
+                    not_after: key.not_after,
+                })
+                .collect(),
+            issued_at: bundle.issued_at,
+            expires_at: bundle.expires_at,
+            signed: Binary(bundle.signing_bytes()?),
+            signature: Binary(reply.signature.to_vec()),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChallengeJson {
+    nonce: Binary,
+    expires_at: u64,
+    difficulty: u8,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AsReqJson {
+    reply_key: Binary,
+    stamp: StampJson,
+    /// `zkk_protocol::encode_receipt` of the receipt.
+    receipt: Binary,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StampJson {
+    nonce: Binary,
+    counter: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TgsReqJson {
+    tgt: Binary,
+    service: String,
+    authenticator: AuthenticatorJson,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthenticatorJson {
+    timestamp: u64,
+    mac: Binary,
+}
+
+/// Reply to `as-req` and `tgs-req`: the body an `AsRep` or `TgsRep` frame
+/// would carry, sealed the same way.
+#[derive(Debug, Serialize)]
+struct SealedJson {
+    sealed: Binary,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorJson {
+    code: u16,
+    error: String,
+    /// The bincode `ErrorReply`, signed like tickets.
+    reply: Option<Binary>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Json,
+    Cbor,
+}
+
+impl Format {
+    fn named(value: Option<&HeaderValue>, media_type: &str) -> bool {
+        value
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(',').any(|part| part.trim().starts_with(media_type)))
+    }
+
+    fn of_body(headers: &HeaderMap) -> Self {
+        if Self::named(headers.get(header::CONTENT_TYPE), CBOR) {
+            Format::Cbor
+        } else {
+            Format::Json
+        }
+    }
+
+    fn of_reply(headers: &HeaderMap) -> Self {
+        let accept = headers.get(header::ACCEPT);
+        if Self::named(accept, CBOR) {
+            Format::Cbor
+        } else if Self::named(accept, JSON) {
+            Format::Json
+        } else {
+            Self::of_body(headers)
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn respond<T: Serialize>(self, status: StatusCode, value: &T) -> Response {
+        let (content_type, body) = match self {
+            Format::Json => (JSON, serde_json::to_vec(value).map_err(|e| e.to_string())),
+            Format::Cbor => {
+                let mut body = Vec::new();
+                (CBOR, ciborium::into_writer(value, &mut body).map(|()| body).map_err(|e| e.to_string()))
+            }
+        };
+        match body {
+            Ok(body) => (status, [(header::CONTENT_TYPE, content_type)], body).into_response(),
+            Err(e) => {
+                error!("Failed to encode HTTP reply: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    fn refuse(self, error: KdcError) -> Response {
+        let status = match error {
+            KdcError::BadFraming
+            | KdcError::UnsupportedVersion
+            | KdcError::UnexpectedMessage
+            | KdcError::UnsupportedReceipt
+            | KdcError::BadJournal => StatusCode::BAD_REQUEST,
+            KdcError::UnknownService => StatusCode::NOT_FOUND,
+            KdcError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            KdcError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            KdcError::Internal | KdcError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::FORBIDDEN,
+        };
+        let reply = kdc::error_reply(error)
+            .and_then(|reply| zkk_protocol::encode(&reply))
+            .inspect_err(|e| error!("Failed to encode error reply: {}", e))
+            .ok()
+            .map(Binary);
+        let body = ErrorJson {
+            code: error.code(),
+            error: error.to_string(),
+            reply,
+        };
+        self.respond(status, &body)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Format::of_reply(&parts.headers))
+    }
+}
+
+/// A request body in either format.
+struct Body<T>(T);
+
+impl<S: Send + Sync, T: DeserializeOwned> FromRequest<S> for Body<T> {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let reply = Format::of_reply(request.headers());
+        let format = Format::of_body(request.headers());
+        let bytes = Bytes::from_request(request, state).await.map_err(IntoResponse::into_response)?;
+        format
+            .decode(&bytes)
+            .map(Body)
+            .map_err(|e| reply.refuse(kdc::reject(KdcError::BadFraming, e)))
+    }
+}
+
+async fn get_keys(format: Format) -> Response {
+    match keys::key_set().and_then(|reply| KeySetJson::new(&reply)) {
+        Ok(json) => format.respond(StatusCode::OK, &json),
+        Err(e) => format.refuse(kdc::reject(KdcError::Internal, e)),
+    }
+}
+
+async fn post_challenge(
+    State(kdc): State<Arc<Kdc>>,
+    ConnectInfo(Peer(peer)): ConnectInfo<Peer>,
+    format: Format,
+) -> Response {
+    let _span = info_span!("http", %peer, kind = "ChallengeReq").entered();
+    match kdc.challenge() {
+        Ok(challenge) => format.respond(
+            StatusCode::OK,
+            &ChallengeJson {
+                nonce: Binary(challenge.nonce.to_vec()),
+                expires_at: challenge.expires_at,
+                difficulty: challenge.difficulty,
+            },
+        ),
+        Err(error) => format.refuse(error),
+    }
+}
+
+async fn post_as_req(
+    State(kdc): State<Arc<Kdc>>,
+    ConnectInfo(Peer(peer)): ConnectInfo<Peer>,
+    format: Format,
+    Body(request): Body<AsReqJson>,
+) -> Response {
+    let span = info_span!("http", %peer, kind = "AsReq");
+    let result = async {
+        let head = AsReqHead {
+            reply_key: request.reply_key.array("reply_key")?,
+            stamp: PowStamp {
+                nonce: request.stamp.nonce.array("stamp.nonce")?,
+                counter: request.stamp.counter,
+            },
+        };
+        // The `AsReq` body is the head followed by the encoded receipt, so
+        // the receipt is not decoded here, ahead of the stamp check.
+        let mut body = zkk_protocol::encode(&head).map_err(|e| kdc::reject(KdcError::Internal, e))?;
+        body.extend_from_slice(&request.receipt.0);
+        kdc.as_req(body).await
+    }
+    .instrument(span)
+    .await;
+    match result {
+        Ok(sealed) => format.respond(StatusCode::OK, &SealedJson { sealed: Binary(sealed) }),
+        Err(error) => format.refuse(error),
+    }
+}
+
+async fn post_tgs_req(
+    State(kdc): State<Arc<Kdc>>,
+    ConnectInfo(Peer(peer)): ConnectInfo<Peer>,
+    format: Format,
+    Body(request): Body<TgsReqJson>,
+) -> Response {
+    let span = info_span!("http", %peer, kind = "TgsReq");
+    let result = async {
+        let request = TgsRequest {
+            tgt: request.tgt.0,
+            service: request.service,
+            authenticator: Authenticator {
+                timestamp: request.authenticator.timestamp,
+                mac: request.authenticator.mac.array("authenticator.mac")?,
+            },
+        };
+        let body = zkk_protocol::encode(&request).map_err(|e| kdc::reject(KdcError::Internal, e))?;
+        kdc.tgs_req(body).await
+    }
+    .instrument(span)
+    .await;
+    match result {
+        Ok(sealed) => format.respond(StatusCode::OK, &SealedJson { sealed: Binary(sealed) }),
+        Err(error) => format.refuse(error),
+    }
+}
+
+/// Takes a token from the peer's bucket, ahead of reading the body.
+async fn admit(
+    State(kdc): State<Arc<Kdc>>,
+    ConnectInfo(Peer(peer)): ConnectInfo<Peer>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match kdc.admit(peer.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(error) => Format::of_reply(request.headers()).refuse(error),
+    }
+}
+
+/// Largest body accepted for a payload of `max` bytes: base64 grows it by a
+/// third, plus room for the rest of the document.
+fn body_limit(max: u32) -> RequestBodyLimitLayer {
+    RequestBodyLimitLayer::new(max as usize / 3 * 4 + 4096)
+}
+
+/// Connections for `axum::serve`, handed over once their handshake is done
+/// so a slow one does not hold up the rest. Each holds one of the
+/// `connections` permits the KDC listener takes too; past the limit new
+/// ones are closed unanswered.
+struct Listener {
+    local_addr: SocketAddr,
+    ready: mpsc::Receiver<(HttpStream, SocketAddr)>,
+}
+
+impl Listener {
+    fn spawn(
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+        limits: Limits,
+        connections: Arc<Semaphore>,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, ready) = mpsc::channel(64);
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Error accepting HTTP connection: {}", e);
+                        continue;
+                    }
+                };
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    stats::connection(false);
+                    debug!(%peer, "Connection limit reached, refusing HTTP connection");
+                    continue;
+                };
+                stats::connection(true);
+                // Replies go out whole, so Nagle's algorithm would only delay them.
+                if let Err(e) = stream.set_nodelay(true) {
+                    debug!("Failed to set TCP_NODELAY: {}", e);
+                }
+                let tls = tls.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let open = stats::open_connection();
+                    match tls::accept(tls.as_ref(), stream, limits.read_timeout).await {
+                        Ok(stream) => {
+                            let _ = sender.send((HttpStream::new(stream, limits, permit, open), peer)).await;
+                        }
+                        Err(e) => debug!(%peer, "TLS handshake failed: {}", e),
+                    }
+                });
+            }
+        });
+        Ok(Listener { local_addr, ready })
+    }
+}
+
+/// The address a connection came from, for [`ConnectInfo`].
+#[derive(Debug, Clone, Copy)]
+struct Peer(SocketAddr);
+
+impl Connected<IncomingStream<'_, Listener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, Listener>) -> Self {
+        Peer(*stream.remote_addr())
+    }
+}
+
+impl axum::serve::Listener for Listener {
+    type Io = HttpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (HttpStream, SocketAddr) {
+        match self.ready.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop only stops once this is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// A keep-alive connection under the KDC listener's [`Limits`]: it fails
+/// with `TimedOut` after `idle_timeout` without a request, twice
+/// `read_timeout` from a request's first byte to its reply (the timeout
+/// layer bounds the handler's share of that), or `write_timeout` on a
+/// stalled write.
+struct HttpStream {
+    stream: tls::Stream,
+    limits: Limits,
+    idle: bool,
+    read_deadline: Pin<Box<Sleep>>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    _permit: OwnedSemaphorePermit,
+    _open: stats::OpenConnection,
+}
+
+impl HttpStream {
+    fn new(stream: tls::Stream, limits: Limits, permit: OwnedSemaphorePermit, open: stats::OpenConnection) -> Self {
+        HttpStream {
+            stream,
+            limits,
+            idle: true,
+            read_deadline: Box::pin(tokio::time::sleep(limits.idle_timeout)),
+            write_deadline: None,
+            _permit: permit,
+            _open: open,
+        }
+    }
+
+    fn expired(deadline: &mut Pin<Box<Sleep>>, cx: &mut Context<'_>) -> bool {
+        deadline.as_mut().poll(cx).is_ready()
+    }
+
+    /// Arms the write deadline while `result` is pending, failing once it
+    /// passes.
+    fn check_write<T>(&mut self, cx: &mut Context<'_>, result: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if result.is_ready() {
+            self.write_deadline = None;
+            return result;
+        }
+        let limit = self.limits.write_timeout;
+        let deadline = self.write_deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(limit)));
+        if Self::expired(deadline, cx) {
+            Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncRead for HttpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if this.idle && buf.filled().len() > filled => {
+                this.idle = false;
+                let deadline = Instant::now() + 2 * this.limits.read_timeout;
+                this.read_deadline.as_mut().reset(deadline);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending if Self::expired(&mut this.read_deadline, cx) => {
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            result => result,
+        }
+    }
+}
+
+impl AsyncWrite for HttpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                // A reply is going out, so the next request starts from idle.
+                this.idle = true;
+                let deadline = Instant::now() + this.limits.idle_timeout;
+                this.read_deadline.as_mut().reset(deadline);
+            }
+        }
+        this.check_write(cx, result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_flush(cx);
+        this.check_write(cx, result)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_shutdown(cx);
+        this.check_write(cx, result)
+    }
+}
+
+/// Serves until the listener fails. `cors_origins` may call the API from a
+/// browser; without any, cross-origin requests are left to the browser to block.
+pub async fn serve(
+    listen: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    kdc: Arc<Kdc>,
+    limits: Limits,
+    connections: Arc<Semaphore>,
+    cors_origins: &[String],
+) -> io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    info!(%listen, "HTTP listening");
+    serve_on(listener, tls, kdc, limits, connections, cors_origins).await
+}
+
+async fn serve_on(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    kdc: Arc<Kdc>,
+    limits: Limits,
+    connections: Arc<Semaphore>,
+    cors_origins: &[String],
+) -> io::Result<()> {
+    let listener = Listener::spawn(listener, tls, limits, connections)?;
+    let max_message = limits.max_message_bytes;
+    let max_as_req = limits.max_payload(zkk_protocol::MessageKind::AsReq);
+    let mut app = Router::new()
+        .route("/v1/challenge", post(post_challenge).layer(body_limit(max_message)))
+        .route("/v1/as-req", post(post_as_req).layer(body_limit(max_as_req)))
+        .route("/v1/tgs-req", post(post_tgs_req).layer(body_limit(max_message)))
+        .route_layer(middleware::from_fn_with_state(kdc.clone(), admit))
+        .route("/v1/keys", get(get_keys))
+        // The per-route limits above stand in for axum's own.
+        .layer(DefaultBodyLimit::disable())
+        .layer(TimeoutLayer::new(limits.read_timeout))
+        .with_state(kdc);
+    if !cors_origins.is_empty() {
+        let origins = cors_origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        app = app.layer(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::GET, Method::POST])
+                .allow_headers([header::CONTENT_TYPE, header::ACCEPT])
+                .max_age(Duration::from_secs(60 * 60)),
+        );
+    }
+    axum::serve(listener, app.into_make_service_with_connect_info::<Peer>()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::tests::{assert_released, kdc, until_closed, LIMITS};
+
+    /// Serves over `connections` and returns a client connected to it.
+    async fn connect(connections: Arc<Semaphore>) -> (TcpStream, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let kdc = kdc(dir.path());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { serve_on(listener, None, kdc, LIMITS, connections, &[]).await });
+        (TcpStream::connect(addr).await.unwrap(), dir)
+    }
+
+    const CHALLENGE: &[u8] = b"POST /v1/challenge HTTP/1.1\r\nHost: kdc\r\nContent-Length: 0\r\n\r\n";
+
+    #[tokio::test]
+    async fn idle_keep_alive_is_dropped() {
+        let connections = Arc::new(Semaphore::new(1));
+        let (mut client, _dir) = connect(connections.clone()).await;
+        client.write_all(CHALLENGE).await.unwrap();
+        let reply = until_closed(&mut client).await;
+        assert!(reply.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&reply));
+        assert_released(&connections).await;
+    }
+
+    #[tokio::test]
+    async fn trickling_request_is_dropped() {
+        let connections = Arc::new(Semaphore::new(1));
+        let (mut client, _dir) = connect(connections.clone()).await;
+        let trickle = async {
+            for byte in CHALLENGE {
+                client.write_all(&[*byte]).await?;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Ok::<_, io::Error>(())
+        };
+        // The server gives up long before the last byte would go out.
+        assert!(tokio::time::timeout(Duration::from_secs(2), trickle).await.unwrap().is_err());
+        assert_released(&connections).await;
+    }
+
+    #[tokio::test]
+    async fn connection_past_the_limit_is_closed() {
+        let connections = Arc::new(Semaphore::new(1));
+        let held = connections.clone().try_acquire_owned().unwrap();
+        let (mut client, _dir) = connect(connections.clone()).await;
+        client.write_all(CHALLENGE).await.ok();
+        assert!(until_closed(&mut client).await.is_empty());
+        drop(held);
+        assert_released(&connections).await;
+    }
+}
@@ -0,0 +1,511 @@
+//! Verification core: turns an `AsReq` into an encrypted ticket or a `KdcError`.
+//!
+//! Works on message bodies, so the TCP and HTTP listeners share it; each
+//! wraps the results in its own framing.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use risc0_zkvm::sha::Digestible;
+use risc0_zkvm::{InnerReceipt, Receipt};
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info, warn};
+use zkk_protocol::error::ErrorBundle;
+use zkk_protocol::{
+    crypto, AsReqHead, AuthJournal, Challenge, ErrorReply, KdcError, MessageReceived, MessageSent, SignBundle,
+    TgsReply, TgsRequest,
+};
+
+use crate::audit::{self, AuditLog, Event, Record};
+use crate::challenge::Challenges;
+use crate::config::{AcceptedImage, ReceiptKind, ReceiptPolicy, ServerConfig, TicketPolicy};
+use crate::db::{self, DbCache};
+use crate::keys;
+use crate::ratelimit::RateLimiter;
+use crate::secret::Secret;
+use crate::stats;
+use crate::tgs::{self, ReplayCache};
+use crate::workers::VerifierPool;
+
+/// State shared by every listener.
+pub struct Kdc {
+    images: Vec<AcceptedImage>,
+    receipts: ReceiptPolicy,
+    credential_db: Arc<DbCache>,
+    challenges: Challenges,
+    tickets: TicketPolicy,
+    clock_skew: Duration,
+    replays: ReplayCache,
+    pool: VerifierPool,
+    rate_limiter: RateLimiter,
+    audit: Option<AuditLog>,
+}
+
+/// Longest service name accepted in a request.
+const MAX_SERVICE_LEN: usize = 64;
+
+/// Logs why a request is refused and returns the code reported to the client.
+pub fn reject(error: KdcError, reason: impl std::fmt::Display) -> KdcError {
+    debug!(?error, %reason, "Rejecting request");
+    error
+}
+
+/// Signs `error` for an `Error` frame.
+pub fn error_reply(error: KdcError) -> Result<ErrorReply, bincode::error::EncodeError> {
+    let signer = keys::signer();
+    let bundle = ErrorBundle {
+        key_id: signer.key_id,
+        error,
+        timestamp: now(),
+    };
+    let encoded = bundle.signing_bytes()?;
+    Ok(ErrorReply {
+        signature: signer.sign(&encoded),
+        signature_data: bundle,
+    })
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Image ID the receipt claims to come from, if the claim is not pruned.
+fn receipt_image_id(receipt: &Receipt) -> Option<risc0_zkvm::sha::Digest> {
+    let claim = receipt.claim().ok()?;
+    Some(claim.as_value().ok()?.pre.digest())
+}
+
+/// Refuses receipt kinds `policy` does not list, and composite receipts with
+/// more parts than it allows, before any of them is verified.
+fn check_receipt(receipt: &Receipt, policy: &ReceiptPolicy) -> Result<(), KdcError> {
+    let kind = match &receipt.inner {
+        InnerReceipt::Composite(composite) => {
+            let parts = composite.segments.len() + composite.assumption_receipts.len();
+            if parts > policy.max_segments {
+                return Err(reject(KdcError::UnsupportedReceipt, format!("composite receipt with {} parts", parts)));
+            }
+            ReceiptKind::Composite
+        }
+        InnerReceipt::Succinct(_) => ReceiptKind::Succinct,
+        InnerReceipt::Groth16(_) => ReceiptKind::Groth16,
+        _ => return Err(reject(KdcError::UnsupportedReceipt, "fake or unknown receipt kind")),
+    };
+    if !policy.kinds.contains(&kind) {
+        return Err(reject(KdcError::UnsupportedReceipt, format!("{:?} receipt", kind)));
+    }
+    Ok(())
+}
+
+/// The entry for `image_id` in `images`, unless it is missing or sunset by `now`.
+fn accepted_image(
+    images: &[AcceptedImage],
+    image_id: risc0_zkvm::sha::Digest,
+    now: u64,
+) -> Result<&AcceptedImage, KdcError> {
+    let Some(image) = images.iter().find(|image| image.id == image_id) else {
+        return Err(reject(KdcError::WrongImageId, image_id));
+    };
+    if let Some(sunset_at) = image.sunset_at.filter(|&sunset_at| sunset_at <= now) {
+        return Err(reject(KdcError::WrongImageId, format!("{} was sunset at {}", image.label, sunset_at)));
+    }
+    Ok(image)
+}
+
+/// Decodes the journal of a receipt verified against `image` in the image's schema.
+fn decode_journal(image: &AcceptedImage, receipt: &Receipt) -> Result<AuthJournal, KdcError> {
+    // The guest committed something other than its configured schema: a
+    // misconfigured image entry, or a guest that commits unchecked bytes.
+    image.journal.decode(receipt).map_err(|e| {
+        let (image_id, label, schema) = (image.id, &image.label, image.journal.version());
+        warn!(%image_id, label, schema, "Verified journal does not decode: {}", e);
+        KdcError::BadJournal
+    })
+}
+
+impl Kdc {
+    /// Must run inside the runtime: it starts the credential DB refresh task.
+    pub fn new(config: &ServerConfig) -> anyhow::Result<Self> {
+        let audit = config.audit_log.as_deref().map(AuditLog::open).transpose()?;
+        let credential_db = Arc::new(DbCache::new(db::open(&config.credential_db, config.db_cache.max_bytes), config.db_cache.dir.clone()));
+        credential_db.spawn_refresh(config.db_cache.refresh);
+        let now = now();
+        for image in &config.images {
+            let (id, label, schema) = (image.id, &image.label, image.journal.version());
+            match image.sunset_at {
+                Some(sunset_at) if sunset_at <= now => warn!(%id, label, schema, sunset_at, "Image already sunset"),
+                Some(sunset_at) => info!(%id, label, schema, sunset_at, "Accepting image until sunset"),
+                None => info!(%id, label, schema, "Accepting image"),
+            }
+        }
+        let limits = &config.limits;
+        Ok(Kdc {
+            images: config.images.clone(),
+            receipts: config.receipts.clone(),
+            credential_db,
+            challenges: Challenges::new(
+                config.challenge_ttl,
+                limits.max_pending_challenges,
+                (limits.pow_min_bits, limits.pow_max_bits),
+            ),
+            tickets: config.tickets.clone(),
+            clock_skew: config.clock_skew,
+            replays: ReplayCache::new(limits.max_replay_cache),
+            pool: VerifierPool::new(limits.verify_workers, limits.verify_queue_timeout),
+            rate_limiter: RateLimiter::new(
+                limits.rate_limit_per_minute,
+                limits.rate_limit_burst,
+                limits.max_rate_limited_sources,
+            ),
+            audit,
+        })
+    }
+
+    /// Records the outcome of a request in the audit log, if there is one,
+    /// and in the metrics. A ticket is only handed out once its entry is on disk.
+    fn audit<T>(&self, mut record: Record, result: Result<T, KdcError>) -> Result<T, KdcError> {
+        let (event, key_id, service) = (record.event, record.key_id, record.service.clone());
+        let result = match &self.audit {
+            Some(log) => {
+                record.set_outcome(&result);
+                match log.append(record) {
+                    Ok(()) => result,
+                    Err(e) => {
+                        error!("Failed to write audit log: {:#}", e);
+                        result.and(Err(KdcError::Internal))
+                    }
+                }
+            }
+            None => result,
+        };
+        stats::request(event, &audit::outcome(&result), key_id, service.as_deref());
+        result
+    }
+
+    /// Takes a token from `peer`'s bucket. Called for every request that
+    /// costs more than a constant amount of work.
+    pub fn admit(&self, peer: IpAddr) -> Result<(), KdcError> {
+        self.rate_limiter.check(peer).inspect_err(|&error| stats::refused(error))
+    }
+
+    /// Issues the nonce for the next proof.
+    pub fn challenge(&self) -> Result<Challenge, KdcError> {
+        let challenge = self.challenges.issue(self.pool.load())?;
+        debug!(expires_at = challenge.expires_at, difficulty = challenge.difficulty, "Issued challenge");
+        Ok(challenge)
+    }
+
+    /// Answers the body of an `AsReq` on the verification pool, once its
+    /// stamp checks out; until then only the head in front of the receipt is
+    /// decoded. Returns the `AsRep` body.
+    pub async fn as_req(self: &Arc<Self>, body: Vec<u8>) -> Result<Vec<u8>, KdcError> {
+        let head = AsReqHead::peek(&body)
+            .map_err(|e| reject(KdcError::BadFraming, e))
+            .and_then(|head| self.challenges.check_stamp(&head).map(|()| head))
+            .inspect_err(|&error| stats::refused(error))?;
+        let kdc = self.clone();
+        self.pool
+            .run(move || {
+                let mut record = Record::new(Event::AsReq);
+                let result = kdc.authenticate(&body, &head, &mut record);
+                kdc.audit(record, result)
+            })
+            .await
+    }
+
+    /// Verifies an `AsReq` whose `head` passed [`Challenges::check_stamp`]
+    /// and returns the `AsReply` sealed to the client's reply key.
+    fn authenticate(&self, body: &[u8], head: &AsReqHead, record: &mut Record) -> Result<Vec<u8>, KdcError> {
+        // Spent before anything expensive, so one stamp buys one verification.
+        self.challenges.consume(&head.stamp.nonce)?;
+        debug!("Challenge nonce accepted");
+
+        let data: MessageReceived = zkk_protocol::decode(body).map_err(|e| reject(KdcError::BadFraming, e))?;
+        check_receipt(&data.proof, &self.receipts)?;
+
+        let Some(image_id) = receipt_image_id(&data.proof) else {
+            return Err(reject(KdcError::ProofInvalid, "receipt claim is pruned"));
+        };
+        record.image_id = Some(image_id.to_string());
+        let image = accepted_image(&self.images, image_id, now())?;
+        let label = image.label.as_str();
+        debug!(%image_id, label, "Verifying proof");
+        let started = Instant::now();
+        let verified = data.proof.verify(image_id);
+        stats::proof_verified(label, started.elapsed(), verified.is_ok());
+        verified.map_err(|e| reject(KdcError::ProofInvalid, e))?;
+        info!(%image_id, label, "Proof verified");
+
+        let journal = decode_journal(image, &data.proof)?;
+        // The journal's credential hashes identify the user; they are not logged.
+        debug!(existence = journal.existence, db_hash = %hex::encode(journal.db_hash), "Journal decoded");
+        self.grant(&journal, &data.stamp.nonce, &data.reply_key, record)
+    }
+
+    /// Issues a TGT for a verified `journal`, once it matches the request's
+    /// stamp `nonce` and `reply_key` and the current credential database, and
+    /// returns the `AsReply` sealed to `reply_key`.
+    fn grant(
+        &self,
+        journal: &AuthJournal,
+        nonce: &[u8; 32],
+        reply_key: &[u8; 32],
+        record: &mut Record,
+    ) -> Result<Vec<u8>, KdcError> {
+        if journal.nonce != *nonce {
+            return Err(reject(KdcError::NonceUnknown, "proof is for a different nonce than its stamp"));
+        }
+        // The stamp covers the key too, so swapping it in transit means redoing the work.
+        if crypto::reply_key_hash(reply_key) != journal.reply_key_hash {
+            return Err(reject(KdcError::ReplyKeyMismatch, hex::encode(reply_key)));
+        }
+
+        if !journal.exists() {
+            info!("Proof shows the credentials are not in the database");
+            return Err(reject(KdcError::CredentialNotFound, format!("existence = {}", journal.existence)));
+        }
+
+        debug!(source = %self.credential_db.describe(), "Looking up credential database");
+        let db = self.credential_db.get().map_err(|e| reject(KdcError::Internal, format!("{:#}", e)))?;
+        let file_data_hash = db.digest;
+        record.db_digest = Some(hex::encode(file_data_hash));
+
+        if file_data_hash != journal.db_hash {
+            info!(
+                proved = %hex::encode(journal.db_hash),
+                current = %hex::encode(file_data_hash),
+                "Proof is against a different credential database"
+            );
+            return Err(reject(KdcError::DbHashMismatch, "hash verification failed"));
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(journal.id_hash);
+        hasher.update(journal.pass_hash);
+        let comb_hash: [u8; 32] = hasher.finalize().into();
+        record.comb_hash = Some(hex::encode(comb_hash));
+
+        let response = tgs::issue(comb_hash, self.tickets.tgt_lifetime)?;
+        info!(expires_at = response.expires_at, "Issued ticket-granting ticket");
+
+        let plain = Secret::new(zkk_protocol::encode(&response).map_err(|e| reject(KdcError::Internal, e))?);
+        let encrypted = crypto::seal_to(reply_key, plain.expose()).map_err(|e| reject(KdcError::BadFraming, e))?;
+
+        Ok(encrypted)
+    }
+
+    /// Answers the body of a `TgsReq`, trading a TGT for a signed ticket to
+    /// one service, with the `TgsRep` body. Runs on the verification pool,
+    /// since the audit entry is synced to disk before the ticket is returned.
+    pub async fn tgs_req(self: &Arc<Self>, body: Vec<u8>) -> Result<Vec<u8>, KdcError> {
+        let kdc = self.clone();
+        self.pool
+            .run(move || {
+                let mut record = Record::new(Event::TgsReq);
+                let result = kdc.issue_service_ticket(&body, &mut record);
+                kdc.audit(record, result)
+            })
+            .await
+    }
+
+    /// Any registered service may be asked for. The `service_id` inside the
+    /// proven credential names the user's KDC login, not a ticket audience,
+    /// so it is not checked here; services decide which users to let in.
+    fn issue_service_ticket(&self, body: &[u8], record: &mut Record) -> Result<Vec<u8>, KdcError> {
+        let data: TgsRequest = zkk_protocol::decode(body).map_err(|e| reject(KdcError::BadFraming, e))?;
+        if data.service.is_empty() || data.service.len() > MAX_SERVICE_LEN {
+            return Err(reject(KdcError::BadFraming, format!("service name of {} bytes", data.service.len())));
+        }
+        let service_key = keys::service_key(&data.service)
+            .ok_or_else(|| reject(KdcError::UnknownService, format!("{:?}", data.service)))?;
+        // Only registered names are recorded; anything else is client-chosen text.
+        record.service = Some(data.service.clone());
+        let tgt = tgs::open(&data.tgt, self.clock_skew)?;
+        record.comb_hash = Some(hex::encode(tgt.comb_hash));
+        tgs::check_authenticator(&data.authenticator, &tgt, &data.tgt, &data.service, self.clock_skew)?;
+        self.replays.insert(&data.authenticator, self.clock_skew)?;
+        debug!(service = %data.service, "TGT accepted");
+
+        let timestamp = now();
+        let lifetime = self.tickets.lifetime(&data.service);
+        let session_key = tgs::session_key();
+        let signer = keys::signer();
+        record.key_id = Some(signer.key_id);
+        let bundle = SignBundle {
+            key_id: signer.key_id,
+            sealed_session_key: crypto::seal(service_key, session_key.expose()),
+            pseudonym: tgs::pseudonym(service_key, &tgt.comb_hash),
+            service: data.service,
+            timestamp,
+            not_before: timestamp,
+            // A service ticket never outlives the TGT it came from.
+            expires_at: (timestamp + lifetime.as_secs()).min(tgt.expires_at),
+        };
+        info!(
+            service = %bundle.service,
+            key_id = bundle.key_id,
+            expires_at = bundle.expires_at,
+            "Issued service ticket"
+        );
+
+        let encoded = bundle.signing_bytes().map_err(|e| reject(KdcError::Internal, e))?;
+        let response = TgsReply {
+            ticket: MessageSent {
+                signature: signer.sign(&encoded),
+                signature_data: bundle,
+            },
+            session_key: *session_key.expose(),
+        };
+        let plain = Secret::new(zkk_protocol::encode(&response).map_err(|e| reject(KdcError::Internal, e))?);
+        Ok(crypto::seal(tgt.session_key.expose(), plain.expose()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::load_keys;
+    use crate::tgs::TgtBody;
+
+    /// A TGT for `comb_hash` valid from now for `lifetime` seconds, with its session key.
+    fn tgt(comb_hash: [u8; 32], lifetime: u64) -> (Vec<u8>, [u8; 32]) {
+        let session_key = tgs::session_key();
+        let body = TgtBody {
+            session_key: session_key.clone(),
+            comb_hash,
+            not_before: now(),
+            expires_at: now() + lifetime,
+        };
+        (tgs::seal(&body).unwrap(), *session_key.expose())
+    }
+
+    /// Trades `tgt` for a ticket to `service` and opens the reply.
+    fn service_ticket(kdc: &Kdc, (tgt, session_key): &(Vec<u8>, [u8; 32]), service: &str) -> SignBundle {
+        let request = TgsRequest {
+            tgt: tgt.clone(),
+            service: service.into(),
+            authenticator: zkk_protocol::Authenticator::new(session_key, tgt, service, now()),
+        };
+        let mut record = Record::new(Event::TgsReq);
+        let sealed = kdc
+            .issue_service_ticket(&zkk_protocol::encode(&request).unwrap(), &mut record)
+            .unwrap();
+        let reply: TgsReply = zkk_protocol::decode(&crypto::open(session_key, &sealed).unwrap()).unwrap();
+        reply.ticket.signature_data
+    }
+
+    /// A journal for the fixture's empty database that [`Kdc::grant`] accepts
+    /// with `nonce` and the public half of `reply_key`.
+    fn journal(nonce: [u8; 32], reply_key: &crypto::ReplyKey) -> AuthJournal {
+        AuthJournal {
+            existence: 1,
+            db_hash: Sha256::digest(b"").into(),
+            id_hash: [1; 32],
+            pass_hash: [2; 32],
+            nonce,
+            reply_key_hash: crypto::reply_key_hash(&reply_key.public_key()),
+        }
+    }
+
+    fn image(id: u32, sunset_at: Option<u64>) -> AcceptedImage {
+        AcceptedImage {
+            id: risc0_zkvm::sha::Digest::from([id; 8]),
+            label: format!("image-{id}"),
+            journal: zkk_protocol::JournalSchema::CURRENT,
+            sunset_at,
+        }
+    }
+
+    /// An unverified receipt that only carries `journal`.
+    fn receipt(journal: Vec<u8>) -> Receipt {
+        use risc0_zkvm::{FakeReceipt, MaybePruned};
+        let claim = MaybePruned::Pruned(risc0_zkvm::sha::Digest::ZERO);
+        Receipt::new(InnerReceipt::Fake(FakeReceipt::new(claim)), journal)
+    }
+
+    #[test]
+    fn only_listed_images_before_their_sunset_are_accepted() {
+        let images = [image(1, None), image(2, Some(1000))];
+        assert_eq!(accepted_image(&images, images[0].id, 5000).unwrap().label, "image-1");
+        assert_eq!(accepted_image(&images, images[1].id, 999).unwrap().label, "image-2");
+        assert_eq!(accepted_image(&images, images[1].id, 1000).unwrap_err(), KdcError::WrongImageId);
+        assert_eq!(accepted_image(&images, image(3, None).id, 0).unwrap_err(), KdcError::WrongImageId);
+    }
+
+    #[test]
+    fn journal_outside_the_image_schema_is_refused() {
+        let expected = journal([7; 32], &crypto::ReplyKey::generate());
+        let words = risc0_zkvm::serde::to_vec(&expected).unwrap();
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let image = image(1, None);
+        assert_eq!(decode_journal(&image, &receipt(bytes.clone())), Ok(expected));
+        // Short by a field, and not whole words.
+        assert_eq!(decode_journal(&image, &receipt(bytes[..bytes.len() - 4].to_vec())), Err(KdcError::BadJournal));
+        assert_eq!(decode_journal(&image, &receipt(vec![1, 2, 3])), Err(KdcError::BadJournal));
+    }
+
+    #[tokio::test]
+    async fn verified_journal_is_granted_a_tgt() {
+        load_keys();
+        let dir = tempfile::tempdir().unwrap();
+        let kdc = crate::tests::kdc(dir.path());
+        let reply_key = crypto::ReplyKey::generate();
+        let mut record = Record::new(Event::AsReq);
+        let sealed = kdc.grant(&journal([7; 32], &reply_key), &[7; 32], &reply_key.public_key(), &mut record).unwrap();
+        let reply: zkk_protocol::AsReply = zkk_protocol::decode(&reply_key.open(&sealed).unwrap()).unwrap();
+        let tgt = tgs::open(&reply.tgt, kdc.clock_skew).unwrap();
+        assert_eq!(tgt.session_key.expose(), &reply.session_key);
+        assert_eq!(record.comb_hash, Some(hex::encode(tgt.comb_hash)));
+    }
+
+    #[tokio::test]
+    async fn reply_key_not_in_the_journal_is_refused_before_issuing() {
+        load_keys();
+        let dir = tempfile::tempdir().unwrap();
+        let kdc = crate::tests::kdc(dir.path());
+        let proved = crypto::ReplyKey::generate();
+        let swapped = crypto::ReplyKey::generate();
+        let mut record = Record::new(Event::AsReq);
+        let result = kdc.grant(&journal([7; 32], &proved), &[7; 32], &swapped.public_key(), &mut record);
+        assert_eq!(result, Err(KdcError::ReplyKeyMismatch));
+        // Neither the database nor `tgs::issue` was reached.
+        assert_eq!((record.db_digest, record.comb_hash), (None, None));
+
+        let mut record = Record::new(Event::AsReq);
+        let result = kdc.grant(&journal([7; 32], &proved), &[8; 32], &proved.public_key(), &mut record);
+        assert_eq!(result, Err(KdcError::NonceUnknown));
+        assert_eq!((record.db_digest, record.comb_hash), (None, None));
+    }
+
+    #[tokio::test]
+    async fn service_ticket_never_outlives_its_tgt() {
+        load_keys();
+        let dir = tempfile::tempdir().unwrap();
+        let kdc = crate::tests::kdc(dir.path());
+        let lifetime = kdc.tickets.lifetime("webmail").as_secs();
+
+        let ticket = service_ticket(&kdc, &tgt([1; 32], lifetime + 3600), "webmail");
+        assert_eq!(ticket.expires_at, ticket.timestamp + lifetime);
+        let short = tgt([1; 32], 60);
+        let ticket = service_ticket(&kdc, &short, "webmail");
+        assert_eq!(ticket.expires_at, tgs::open(&short.0, kdc.clock_skew).unwrap().expires_at);
+    }
+
+    #[tokio::test]
+    async fn service_tickets_carry_a_pseudonym_per_service() {
+        load_keys();
+        let dir = tempfile::tempdir().unwrap();
+        let kdc = crate::tests::kdc(dir.path());
+        let first = tgt([1; 32], 3600);
+        let webmail = service_ticket(&kdc, &first, "webmail").pseudonym;
+        let wifi = service_ticket(&kdc, &first, "wifi").pseudonym;
+        assert_ne!(webmail, wifi);
+        assert_ne!(webmail, [1; 32]);
+        // The same user with a new TGT, and another user.
+        assert_eq!(service_ticket(&kdc, &tgt([1; 32], 3600), "webmail").pseudonym, webmail);
+        assert_ne!(service_ticket(&kdc, &tgt([2; 32], 3600), "webmail").pseudonym, webmail);
+    }
+}
@@ -0,0 +1,293 @@
+//! The KDC's Ed25519 ticket signing keys.
+//!
+//! A keyring is a directory: `keyring.toml` lists the keys and each secret is
+//! in `<id>.key` (32 base64-encoded bytes). IDs only grow. Of the keys that
+//! are not retired, the one activated last signs. Scheduled rotations add the
+//! next key ahead of its activation, so verifiers learn it before the first
+//! ticket signed with it. A key superseded by a newer one keeps verifying for
+//! the rotation overlap, so tickets it signed stay checkable until they
+//! expire. A retired key is never used or published again.
+//!
+//! Changes go through [`modify`], which holds `keyring.lock` so the running
+//! server and `zkk_server keys ...` do not overwrite each other.
+
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use ed25519_dalek::{Signer as _, SigningKey};
+use serde::{Deserialize, Serialize};
+use zkk_protocol::{KeyId, PublishedKey};
+
+use crate::keys::{read_key_file, write_key_file};
+
+const INDEX: &str = "keyring.toml";
+const LOCK: &str = "keyring.lock";
+const HEADER: &str = "# Managed by zkk_server, see `zkk_server keys --help`. Secrets are in <id>.key.\n\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStatus {
+    /// Published, signs from its activation time on.
+    Upcoming,
+    /// Signs new tickets.
+    Active,
+    /// Superseded, but tickets it signed may still be valid.
+    Verifying,
+    /// Superseded for longer than any ticket lives.
+    Expired,
+    Retired,
+}
+
+impl std::fmt::Display for KeyStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            KeyStatus::Upcoming => "upcoming",
+            KeyStatus::Active => "active",
+            KeyStatus::Verifying => "verifying",
+            KeyStatus::Expired => "expired",
+            KeyStatus::Retired => "retired",
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileKeyring {
+    #[serde(default, rename = "key")]
+    keys: Vec<KeyRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyRecord {
+    pub id: KeyId,
+    /// Unix seconds.
+    pub created_at: u64,
+    /// When it starts signing, Unix seconds.
+    pub activates_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retired_at: Option<u64>,
+}
+
+pub struct KeyEntry {
+    pub record: KeyRecord,
+    pub key: SigningKey,
+}
+
+/// The key new tickets are signed with.
+pub struct Signer {
+    pub key_id: KeyId,
+    pub key: SigningKey,
+}
+
+impl Signer {
+    pub fn sign(&self, data: &[u8]) -> [u8; 64] {
+        self.key.sign(data).to_bytes()
+    }
+}
+
+pub struct Keyring {
+    dir: PathBuf,
+    /// Sorted by ID.
+    keys: Vec<KeyEntry>,
+}
+
+impl Keyring {
+    /// Reads the keyring in `dir`. A directory without `keyring.toml` is an empty keyring.
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let index = dir.join(INDEX);
+        let file: FileKeyring = match std::fs::read_to_string(&index) {
+            Ok(text) => toml::from_str(&text).with_context(|| format!("failed to parse {}", index.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => FileKeyring::default(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", index.display())),
+        };
+        let mut keys = file
+            .keys
+            .into_iter()
+            .map(|record| {
+                let key = SigningKey::from_bytes(&read_key_file(&dir.join(format!("{}.key", record.id)))?);
+                Ok(KeyEntry { record, key })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        keys.sort_by_key(|entry| entry.record.id);
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0].record.id == pair[1].record.id) {
+            bail!("{} lists key {} twice", index.display(), pair[0].record.id);
+        }
+        Ok(Keyring {
+            dir: dir.to_owned(),
+            keys,
+        })
+    }
+
+    pub fn keys(&self) -> &[KeyEntry] {
+        &self.keys
+    }
+
+    /// The key signing at `now`: of those activated and not retired, the last to activate.
+    pub fn active(&self, now: u64) -> Option<&KeyEntry> {
+        self.keys
+            .iter()
+            .filter(|entry| entry.record.retired_at.is_none() && entry.record.activates_at <= now)
+            .max_by_key(|entry| schedule(entry))
+    }
+
+    pub fn signer(&self, now: u64) -> Option<Signer> {
+        self.active(now).map(|entry| Signer {
+            key_id: entry.record.id,
+            key: entry.key.clone(),
+        })
+    }
+
+    /// When the next key took over from `entry`, if one has.
+    fn superseded_at(&self, entry: &KeyEntry, now: u64) -> Option<u64> {
+        self.keys
+            .iter()
+            .filter(|newer| {
+                newer.record.retired_at.is_none() && newer.record.activates_at <= now && schedule(newer) > schedule(entry)
+            })
+            .map(|newer| newer.record.activates_at)
+            .min()
+    }
+
+    pub fn status(&self, entry: &KeyEntry, now: u64, overlap: Duration) -> KeyStatus {
+        if entry.record.retired_at.is_some() {
+            return KeyStatus::Retired;
+        }
+        if entry.record.activates_at > now {
+            return KeyStatus::Upcoming;
+        }
+        match self.superseded_at(entry, now) {
+            None => KeyStatus::Active,
+            Some(at) if now < at.saturating_add(overlap.as_secs()) => KeyStatus::Verifying,
+            Some(_) => KeyStatus::Expired,
+        }
+    }
+
+    /// Keys verifiers should accept tickets from: upcoming, active, and those
+    /// still in their overlap.
+    pub fn published(&self, now: u64, overlap: Duration) -> Vec<PublishedKey> {
+        self.keys
+            .iter()
+            .filter(|entry| {
+                matches!(
+                    self.status(entry, now, overlap),
+                    KeyStatus::Upcoming | KeyStatus::Active | KeyStatus::Verifying
+                )
+            })
+            .map(|entry| PublishedKey {
+                key_id: entry.record.id,
+                public_key: entry.key.verifying_key().to_bytes(),
+                not_before: entry.record.activates_at,
+                not_after: self
+                    .superseded_at(entry, now)
+                    .map(|at| at.saturating_add(overlap.as_secs())),
+            })
+            .collect()
+    }
+
+    /// When the next scheduled key should activate, if it is time to add it:
+    /// `rotate_after` past the latest activation, announced `announce_ahead`
+    /// before that. Now, if no key is usable.
+    pub fn rotation_due(&self, now: u64, rotate_after: Duration, announce_ahead: Duration) -> Option<u64> {
+        let Some(latest) = self
+            .keys
+            .iter()
+            .filter(|entry| entry.record.retired_at.is_none())
+            .max_by_key(|entry| schedule(entry))
+        else {
+            return Some(now);
+        };
+        let next = latest.record.activates_at.saturating_add(rotate_after.as_secs());
+        if now.saturating_add(announce_ahead.as_secs()) >= next {
+            Some(next.max(now))
+        } else {
+            None
+        }
+    }
+
+    /// Generates a key that signs from `activates_at` on, once the keyring is saved.
+    pub fn add(&mut self, now: u64, activates_at: u64) -> anyhow::Result<KeyId> {
+        let id = self.keys.last().map_or(1, |entry| entry.record.id + 1);
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        write_key_file(&self.dir.join(format!("{}.key", id)), &key.to_bytes())?;
+        self.keys.push(KeyEntry {
+            record: KeyRecord {
+                id,
+                created_at: now,
+                activates_at,
+                retired_at: None,
+            },
+            key,
+        });
+        Ok(id)
+    }
+
+    pub fn retire(&mut self, id: KeyId, now: u64) -> anyhow::Result<()> {
+        let entry = self
+            .keys
+            .iter_mut()
+            .find(|entry| entry.record.id == id)
+            .ok_or_else(|| anyhow!("no key {} in {}", id, self.dir.display()))?;
+        if entry.record.retired_at.is_some() {
+            bail!("key {} is already retired", id);
+        }
+        entry.record.retired_at = Some(now);
+        Ok(())
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let file = FileKeyring {
+            keys: self.keys.iter().map(|entry| entry.record.clone()).collect(),
+        };
+        let text = format!("{}{}", HEADER, toml::to_string(&file)?);
+        let path = self.dir.join(INDEX);
+        let tmp = self.dir.join(format!(".{}.{}.tmp", INDEX, std::process::id()));
+        std::fs::write(&tmp, text).with_context(|| format!("failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("failed to rename {}", tmp.display()))?;
+        Ok(())
+    }
+}
+
+/// Order in which keys take over from each other.
+fn schedule(entry: &KeyEntry) -> (u64, KeyId) {
+    (entry.record.activates_at, entry.record.id)
+}
+
+/// Loads the keyring in `dir`, applies `change` and saves it, holding the keyring lock throughout.
+pub fn modify<T>(dir: &Path, change: impl FnOnce(&mut Keyring) -> anyhow::Result<T>) -> anyhow::Result<T> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let _lock = DirLock::acquire(dir)?;
+    let mut ring = Keyring::load(dir)?;
+    let value = change(&mut ring)?;
+    ring.save()?;
+    Ok(value)
+}
+
+struct DirLock(PathBuf);
+
+impl DirLock {
+    const ATTEMPTS: u32 = 50;
+    const WAIT: Duration = Duration::from_millis(100);
+
+    fn acquire(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(LOCK);
+        for _ in 0..Self::ATTEMPTS {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(DirLock(path)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => std::thread::sleep(Self::WAIT),
+                Err(e) => return Err(e).with_context(|| format!("failed to create {}", path.display())),
+            }
+        }
+        bail!(
+            "{} is held by another process; remove it if no zkk_server is changing the keyring",
+            path.display()
+        )
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -1,45 +1,341 @@
-//! Session key derivation (compact): HKDF-SHA256( salt = ts||encrypted_identity, ikm = empty, info = label ) -> 32 bytes.
+//! Long-term key material: the ticket signing keyring, the root key the
+//! published key set is signed with, the TGT key and each service's key.
+//! Session keys are not derived from any of these; every TGT and ticket gets
+//! a fresh random one.
 
-use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-
-use ed25519_dalek::{Signer, SigningKey};
-use once_cell::sync::Lazy;
-use std::env;
+use anyhow::{anyhow, bail, Context};
 use base64::Engine;
+use ed25519_dalek::{Signer as _, SigningKey};
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use tracing::{error, info, warn};
+use zkk_protocol::{KeySetBundle, KeySetReply};
 
+use crate::config::{KeyFiles, KeyRotation, KeysCommand};
+use crate::kdc::now;
+use crate::keyring::{self, Keyring, Signer};
+use crate::secret::Secret;
 
-static KEY: Lazy<SigningKey> = Lazy::new(|| {
-    let env_key = env::var("SERVER_SIGNING_KEY_B64").unwrap();
-    println!("Decoded signing key: {}", &env_key);
-    let b64: [u8; 32] = base64::engine::general_purpose::STANDARD.decode(env_key).expect("base64 decode").try_into().expect("32 bytes");
-    SigningKey::from_bytes(&b64)
-});
+static SIGNING: OnceCell<SigningKeys> = OnceCell::new();
+static TGT_KEY: OnceCell<Secret<[u8; 32]>> = OnceCell::new();
+static SERVICE_KEYS: OnceCell<BTreeMap<String, Secret<[u8; 32]>>> = OnceCell::new();
 
+/// How often the keyring directory is re-read, picking up keys retired or
+/// added with `zkk_server keys`, and rotation is checked.
+const KEYRING_POLL: Duration = Duration::from_secs(30);
 
-pub fn private_sign(data: &[u8]) -> [u8; 64] {
-    KEY.sign(data).to_bytes()
+struct SigningKeys {
+    dir: PathBuf,
+    rotation: KeyRotation,
+    /// Long-term key the published key set is signed with. Verifiers pin its public half.
+    root: SigningKey,
+    ring: RwLock<Arc<Keyring>>,
+    /// Active key last logged.
+    announced: AtomicU32,
 }
 
-use sha2::Digest;
+/// Reads a file holding 32 base64-encoded bytes.
+pub fn read_key_file(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(text.trim())
+        .with_context(|| format!("{} is not valid base64", path.display()))?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("{} must hold exactly 32 bytes", path.display()))
+}
 
-pub fn gen_session_key() -> String {
+/// Writes 32 base64-encoded bytes to a new file only the owner can read.
+/// Fails if `path` exists, so a key is never overwritten.
+pub fn write_key_file(path: &Path, key: &[u8; 32]) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    writeln!(file, "{}", base64::engine::general_purpose::STANDARD.encode(key))
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
 
-    let key_bytes = KEY.to_bytes();
-    let sess_key_bytes = get_session_key();
+/// Loads the signing keyring (adding a key if rotation is due), root key,
+/// TGT key and service keys. Must run once before any ticket is issued.
+pub fn load(files: &KeyFiles, rotation: KeyRotation) -> anyhow::Result<()> {
+    let ring = current_keyring(&files.signing_keyring, rotation)?;
+    // Checked here, since `signer` has nothing to fall back on.
+    let Some(active) = ring.active(now()).map(|entry| entry.record.id) else {
+        bail!(
+            "signing keyring {} has no key active yet; add one with `zkk_server keys rotate`",
+            files.signing_keyring.display()
+        );
+    };
+    let root = read_key_file(&files.root_key)?;
+    let tgt = Secret::new(read_key_file(&files.tgt_key)?);
+    let services = files
+        .services
+        .iter()
+        .map(|(service, path)| Ok((service.clone(), Secret::new(read_key_file(path)?))))
+        .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+    info!(key_id = active, "Signing tickets");
+    SIGNING
+        .set(SigningKeys {
+            dir: files.signing_keyring.clone(),
+            rotation,
+            root: SigningKey::from_bytes(&root),
+            ring: RwLock::new(Arc::new(ring)),
+            announced: AtomicU32::new(active),
+        })
+        .map_err(|_| anyhow!("keys already loaded"))?;
+    TGT_KEY.set(tgt).map_err(|_| anyhow!("keys already loaded"))?;
+    SERVICE_KEYS.set(services).map_err(|_| anyhow!("keys already loaded"))?;
+    Ok(())
+}
 
-    let mut hasher = Sha256::new();
-    hasher.update(&sess_key_bytes);
-    hasher.update(&key_bytes);
-    let out = hasher.finalize();
+/// The keyring in `dir`, with the next key added first if rotation is due.
+fn current_keyring(dir: &Path, rotation: KeyRotation) -> anyhow::Result<Keyring> {
+    let ring = Keyring::load(dir)?;
+    if ring.rotation_due(now(), rotation.rotate_after, rotation.announce_ahead).is_none() {
+        return Ok(ring);
+    }
+    keyring::modify(dir, |ring| {
+        // Someone else may have rotated while we waited for the lock.
+        if let Some(activates_at) = ring.rotation_due(now(), rotation.rotate_after, rotation.announce_ahead) {
+            let id = ring.add(now(), activates_at)?;
+            info!(key_id = id, keyring = %dir.display(), activates_at, "Added signing key");
+        }
+        Ok(())
+    })?;
+    Keyring::load(dir)
+}
+
+/// Re-reads the keyring every [`KEYRING_POLL`] and rotates on schedule.
+/// Failures keep the keyring already in use.
+pub fn spawn_rotation() {
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(KEYRING_POLL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let signing = signing();
+            match tokio::task::spawn_blocking(|| current_keyring(&signing.dir, signing.rotation)).await {
+                Ok(Ok(ring)) => install(ring),
+                Ok(Err(e)) => error!(keyring = %signing.dir.display(), "Failed to reload signing keyring: {:#}", e),
+                Err(e) => error!("Signing keyring reload task failed: {}", e),
+            }
+        }
+    });
+}
+
+fn install(ring: Keyring) {
+    let signing = signing();
+    let Some(active) = ring.active(now()).map(|entry| entry.record.id) else {
+        warn!(keyring = %signing.dir.display(), "Signing keyring has no usable key, keeping the previous one");
+        return;
+    };
+    if signing.announced.swap(active, Ordering::Relaxed) != active {
+        info!(key_id = active, "Signing tickets");
+    }
+    *signing.ring.write().expect("signing keyring poisoned") = Arc::new(ring);
+}
+
+fn signing() -> &'static SigningKeys {
+    SIGNING.get().expect("keys::load must run before signing")
+}
+
+/// The key to sign the next ticket or error reply with.
+pub fn signer() -> Signer {
+    signing()
+        .ring
+        .read()
+        .expect("signing keyring poisoned")
+        .signer(now())
+        .expect("keyrings without an active key are refused by load and install")
+}
 
-    let mut next = [0u8; 32];
-    next.copy_from_slice(&out);
-    let k = base64::engine::general_purpose::STANDARD.encode(&next);
-    env::set_var("SESSION_KEY", &k);
-    k
+/// The published signing keys, signed with the root key. Valid until the
+/// earliest a key announced after it could activate.
+pub fn key_set() -> Result<KeySetReply, bincode::error::EncodeError> {
+    let signing = signing();
+    let now = now();
+    let keys = signing
+        .ring
+        .read()
+        .expect("signing keyring poisoned")
+        .published(now, signing.rotation.overlap);
+    let bundle = KeySetBundle {
+        keys,
+        issued_at: now,
+        expires_at: now + signing.rotation.announce_ahead.as_secs(),
+    };
+    Ok(KeySetReply {
+        signature: signing.root.sign(&bundle.signing_bytes()?).to_bytes(),
+        signature_data: bundle,
+    })
 }
 
-fn get_session_key() -> String {
-    env::var("SESSION_KEY").unwrap()
+/// Generates every key `files` names: the TGT and root keys, the first
+/// signing key and each service's key. Refuses if any of them exists, before
+/// writing anything. Returns the root key.
+pub(crate) fn init(files: &KeyFiles, now: u64) -> anyhow::Result<SigningKey> {
+    let secrets: Vec<&Path> = [&files.tgt_key, &files.root_key]
+        .into_iter()
+        .chain(files.services.values())
+        .map(PathBuf::as_path)
+        .collect();
+    if let Some(existing) = secrets.iter().find(|path| path.exists()) {
+        bail!("{} already exists; keys init never overwrites keys", existing.display());
+    }
+    if !Keyring::load(&files.signing_keyring)?.keys().is_empty() {
+        bail!("signing keyring {} already has keys", files.signing_keyring.display());
+    }
+    for path in &secrets {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        let mut key = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        write_key_file(path, &key)?;
+        println!("Wrote {}", path.display());
+    }
+    let id = keyring::modify(&files.signing_keyring, |ring| ring.add(now, now))?;
+    println!("Added signing key {} to {}", id, files.signing_keyring.display());
+    Ok(SigningKey::from_bytes(&read_key_file(&files.root_key)?))
+}
+
+/// Key ticket-granting tickets are sealed under. Only this KDC ever holds it.
+pub fn tgt_key() -> &'static [u8; 32] {
+    TGT_KEY.get().expect("keys::load must run before issuing TGTs").expose()
+}
+
+/// Long-term key shared with `service`, if it is registered. Ticket session
+/// keys are sealed under it.
+pub fn service_key(service: &str) -> Option<&'static [u8; 32]> {
+    SERVICE_KEYS
+        .get()
+        .expect("keys::load must run before issuing tickets")
+        .get(service)
+        .map(Secret::expose)
+}
+
+/// Runs a `zkk_server keys` subcommand.
+pub fn admin(command: &KeysCommand, files: &KeyFiles, rotation: KeyRotation) -> anyhow::Result<()> {
+    let dir = &files.signing_keyring;
+    let now = now();
+    let base64 = |bytes: &[u8; 32]| base64::engine::general_purpose::STANDARD.encode(bytes);
+    match command {
+        KeysCommand::Init => {
+            let root = init(files, now)?;
+            println!("Root public key for verifiers to pin: {}", base64(root.verifying_key().as_bytes()));
+        }
+        KeysCommand::List => {
+            let ring = Keyring::load(dir)?;
+            println!("{:>4}  {:<9}  {:>10}  {:>10}  public key", "id", "status", "created", "activates");
+            for entry in ring.keys() {
+                println!(
+                    "{:>4}  {:<9}  {:>10}  {:>10}  {}",
+                    entry.record.id,
+                    ring.status(entry, now, rotation.overlap).to_string(),
+                    entry.record.created_at,
+                    entry.record.activates_at,
+                    base64(entry.key.verifying_key().as_bytes()),
+                );
+            }
+        }
+        KeysCommand::Public => {
+            for key in Keyring::load(dir)?.published(now, rotation.overlap) {
+                println!("{} {}", key.key_id, base64(&key.public_key));
+            }
+        }
+        KeysCommand::Root => {
+            let root = SigningKey::from_bytes(&read_key_file(&files.root_key)?);
+            println!("{}", base64(root.verifying_key().as_bytes()));
+        }
+        KeysCommand::Rotate => {
+            let id = keyring::modify(dir, |ring| ring.add(now, now))?;
+            println!("Added signing key {}; a running server switches to it within {}s", id, KEYRING_POLL.as_secs());
+        }
+        KeysCommand::Retire { id } => {
+            let replacement = keyring::modify(dir, |ring| {
+                let was_active = ring.active(now).is_some_and(|active| active.record.id == *id);
+                ring.retire(*id, now)?;
+                if was_active {
+                    return ring.add(now, now).map(Some);
+                }
+                Ok(None)
+            })
+            .with_context(|| format!("failed to retire key {}", id))?;
+            println!(
+                "Retired key {}; a running server stops signing with and publishing it within {}s",
+                id,
+                KEYRING_POLL.as_secs()
+            );
+            if let Some(replacement) = replacement {
+                println!(
+                    "Added signing key {} to replace it; a running server switches within {}s",
+                    replacement,
+                    KEYRING_POLL.as_secs()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(dir: &Path) -> KeyFiles {
+        KeyFiles {
+            signing_keyring: dir.join("signing"),
+            root_key: dir.join("root.key"),
+            tgt_key: dir.join("tgt.key"),
+            services: BTreeMap::from([("webmail".to_owned(), dir.join("services/webmail.key"))]),
+        }
+    }
+
+    #[test]
+    fn init_writes_every_key_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = files(dir.path());
+        let root = init(&files, now()).unwrap();
+        assert_eq!(root.to_bytes(), read_key_file(&files.root_key).unwrap());
+        let tgt = read_key_file(&files.tgt_key).unwrap();
+        assert_ne!(tgt, read_key_file(&files.services["webmail"]).unwrap());
+        assert!(Keyring::load(&files.signing_keyring).unwrap().active(now()).is_some());
+
+        let error = init(&files, now()).unwrap_err();
+        assert!(error.to_string().contains("never overwrites"), "{:#}", error);
+        assert_eq!(read_key_file(&files.tgt_key).unwrap(), tgt);
+    }
+
+    #[test]
+    fn keyring_with_only_an_upcoming_key_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let signing = dir.path().join("signing");
+        keyring::modify(&signing, |ring| ring.add(now(), now() + 3600)).unwrap();
+        let files = KeyFiles {
+            signing_keyring: signing,
+            ..files(dir.path())
+        };
+        let rotation = KeyRotation {
+            rotate_after: Duration::from_secs(86_400),
+            overlap: Duration::from_secs(3600),
+            announce_ahead: Duration::from_secs(600),
+        };
+        let error = load(&files, rotation).unwrap_err();
+        assert!(error.to_string().contains("no key active yet"), "{:#}", error);
+        // Other tests may have loaded keys of their own, see `crate::tests::load_keys`.
+        assert!(SIGNING.get().is_none_or(|signing| signing.dir != files.signing_keyring));
+    }
 }
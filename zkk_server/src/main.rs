@@ -1,218 +1,360 @@
-use std::net::{TcpListener, TcpStream};
-use std::thread;
-use bincode;
-use bincode::config::{Configuration};
-use serde::{Serialize, Deserialize};
-use risc0_zkvm::Receipt;
-use rsa::{RsaPublicKey, Pkcs1v15Encrypt};
-use sha2::{Sha256, Digest};
-use dotenv;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use clap::Parser;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, info_span, Instrument};
+use zkk_protocol::KdcError;
+
+use config::{Cli, Command, ServerConfig};
+use connection::{Connection, Limits};
+use kdc::Kdc;
+
+pub mod audit;
+pub mod challenge;
+pub mod config;
+pub mod connection;
+pub mod db;
+pub mod http;
+pub mod kdc;
+pub mod keyring;
 pub mod keys;
+pub mod logging;
+pub mod ratelimit;
+pub mod secret;
+pub mod stats;
+pub mod tgs;
+pub mod tls;
+pub mod workers;
 
-use reqwest;
-use std::fs::File;
-use std::io::copy;
-
-pub fn download() {
-    println!("[DEBUG] Starting file download process");
-    let url = "https://gateway.lighthouse.storage/ipfs/bafkreic6cytux6kvw2dhjbeketjxuaskwh62iv4rs5gioija4mtazetvne";
-    println!("[DEBUG] Download URL: {}", url);
-    
-    println!("[DEBUG] Making HTTP request to download file");
-    let mut response = reqwest::blocking::get(url).unwrap();
-    println!("[DEBUG] HTTP request successful, response received");
-
-    let file_path = "New Document.txt";
-    println!("[DEBUG] Creating local file: {}", file_path);
-    let mut file = File::create(file_path).unwrap();
-    println!("[DEBUG] Local file created successfully");
-
-    println!("[DEBUG] Copying response data to local file");
-    copy(&mut response, &mut file).unwrap();
-    println!("[DEBUG] File download completed successfully");
+/// Serves one accepted connection, holding its `permit` until it closes.
+async fn handle_client(
+    tls: Option<TlsAcceptor>,
+    stream: TcpStream,
+    peer: IpAddr,
+    limits: Limits,
+    kdc: Arc<Kdc>,
+    permit: OwnedSemaphorePermit,
+) {
+    let open = stats::open_connection();
+    if let Some(stream) = handshake(tls.as_ref(), stream, limits).await {
+        let reason = Connection::new(stream, peer, limits, kdc).run().await;
+        debug!(?reason, "Connection closed");
+    }
+    drop(open);
+    drop(permit);
 }
 
+/// Connections past `max_connections` that may be refused at once with
+/// `Busy`. Each costs a TLS handshake, so past this many a refused socket is
+/// closed without a reply.
+const MAX_REFUSALS: usize = 8;
 
+/// Answers a connection past `max_connections` with `Busy`, holding its
+/// refusal `slot` until done.
+async fn refuse(
+    tls: Option<TlsAcceptor>,
+    stream: TcpStream,
+    peer: IpAddr,
+    limits: Limits,
+    kdc: Arc<Kdc>,
+    slot: OwnedSemaphorePermit,
+) {
+    if let Some(stream) = handshake(tls.as_ref(), stream, limits).await {
+        Connection::new(stream, peer, limits, kdc).refuse(KdcError::Busy).await;
+    }
+    drop(slot);
+}
 
-const METHOD_ID: [u32;8] = [1234675461, 1912861477, 2915228056, 335807080, 4028670944, 3144587984, 3967663459, 2473194626];
+async fn handshake(tls: Option<&TlsAcceptor>, stream: TcpStream, limits: Limits) -> Option<tls::Stream> {
+    tls::accept(tls, stream, limits.read_timeout)
+        .await
+        .inspect_err(|e| debug!("TLS handshake failed: {}", e))
+        .ok()
+}
 
-fn handle_client(mut stream: TcpStream) {
-    println!("[DEBUG] Starting client handler for connection: {:?}", stream.peer_addr().unwrap_or("unknown".parse().unwrap()));
-    loop {
-        println!("[DEBUG] Entering client processing loop");
-        client(&mut stream);
-        println!("[DEBUG] Client processing iteration completed");
+#[tokio::main]
+async fn main() {
+    let config = match ServerConfig::load(Cli::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    logging::init(config.log_level, config.log_format);
+    debug!(?config, "Starting ZK Kerberos server");
+
+    if let Some(command) = &config.command {
+        let result = match command {
+            Command::Keys(command) => keys::admin(command, &config.keys, config.key_rotation),
+            Command::Audit(command) => audit::admin(command, config.audit_log.as_deref()),
+            Command::Tls(command) => tls::admin(command),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
     }
-}
 
-fn main() {
-    println!("[DEBUG] Starting ZK Kerberos server");
-    dotenv::dotenv().ok(); 
-    println!("[DEBUG] Environment variables loaded");
-    
-    println!("[DEBUG] Attempting to bind to address 127.0.0.1:7878");
-    let listener = TcpListener::bind("127.0.0.1:7878").expect("Failed to bind to address");
-    println!("Server listening on 127.0.0.1:7878");
-    println!("[DEBUG] TCP listener successfully bound and listening");
-
-    println!("[DEBUG] Starting to accept incoming connections");
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let peer_addr = stream.peer_addr().unwrap();
-                println!("New connection: {}", peer_addr);
-                println!("[DEBUG] Spawning new thread for client: {}", peer_addr);
-                thread::spawn(move || {
-                    handle_client(stream);
-                });
-                println!("[DEBUG] Thread spawned successfully for client: {}", peer_addr);
-            }
+    if let Err(e) = keys::load(&config.keys, config.key_rotation) {
+        eprintln!("Invalid configuration: {:#}", e);
+        std::process::exit(2);
+    }
+    keys::spawn_rotation();
+
+    // Installed before anything records, so the cache warm-up is counted.
+    if let Some(listen) = config.metrics_listen {
+        let handle = match stats::install() {
+            Ok(handle) => handle,
             Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
-                eprintln!("[DEBUG] Connection acceptance failed with error: {:?}", e);
+                eprintln!("Failed to install metrics recorder: {:#}", e);
+                std::process::exit(2);
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = stats::serve(listen, handle).await {
+                error!(%listen, "Metrics listener failed: {}", e);
+            }
+        });
+    }
+
+    let tls = match config.tls.as_ref().map(tls::acceptor).transpose() {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let listener = TcpListener::bind(config.listen).await.expect("Failed to bind to address");
+    info!(listen = %config.listen, "Server listening");
+
+    let limits = config.limits;
+    debug!(?limits, "Connection limits");
+    let kdc = match Kdc::new(&config) {
+        Ok(kdc) => Arc::new(kdc),
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // Shared with the HTTP listener, so `max_connections` caps both.
+    let connections = Arc::new(Semaphore::new(limits.max_connections));
+
+    if let Some(listen) = config.http_listen {
+        let kdc = kdc.clone();
+        let connections = connections.clone();
+        let cors_origins = config.cors_origins.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(listen, tls, kdc, limits, connections, &cors_origins).await {
+                error!(%listen, "HTTP listener failed: {}", e);
+            }
+        });
+    }
+
+    let refusals = Arc::new(Semaphore::new(MAX_REFUSALS));
+    let mut next_id: u64 = 0;
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                next_id += 1;
+                let span = info_span!("connection", id = next_id, %peer);
+                info!(parent: &span, "New connection");
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    stats::connection(false);
+                    match refusals.clone().try_acquire_owned() {
+                        Ok(slot) => {
+                            debug!(parent: &span, "Connection limit reached, refusing");
+                            tokio::spawn(refuse(tls.clone(), stream, peer.ip(), limits, kdc.clone(), slot).instrument(span));
+                        }
+                        Err(_) => debug!(parent: &span, "Connection limit reached, closing"),
+                    }
+                    continue;
+                };
+                stats::connection(true);
+                tokio::spawn(handle_client(tls.clone(), stream, peer.ip(), limits, kdc.clone(), permit).instrument(span));
             }
+            Err(e) => error!("Error accepting connection: {}", e),
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
-struct MessageReceived {
-    #[bincode(with_serde)]
-    u_pk: RsaPublicKey,
-    #[bincode(with_serde)]
-    proof: Receipt,
-}
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use zkk_protocol::frame::{FrameHeader, Hello, HEADER_LEN, MAX_FRAME_LEN};
+    use zkk_protocol::{Frame, MessageKind, PROTOCOL_VERSION};
 
-#[derive(Debug, bincode::Encode, bincode::Decode)]
-struct MessageSent {
-    signature: [u8; 64],
-    signature_data: SignBundle,
-}
+    use super::*;
 
-#[derive(Debug, bincode::Encode, bincode::Decode)]
-struct SignBundle {
-    ssk: String,
-    pass_hash: [u8; 32],
-    comb_hash: [u8; 32],
-    timestamp: u64,
-}
+    pub(crate) const LIMITS: Limits = Limits {
+        idle_timeout: Duration::from_millis(200),
+        read_timeout: Duration::from_millis(300),
+        write_timeout: Duration::from_millis(200),
+        max_requests: 3,
+        max_connections: 1,
+        verify_workers: 1,
+        verify_queue_timeout: Duration::from_millis(100),
+        max_pending_challenges: 16,
+        max_replay_cache: 16,
+        pow_min_bits: 0,
+        pow_max_bits: 0,
+        rate_limit_per_minute: 60,
+        rate_limit_burst: 10,
+        max_rate_limited_sources: 16,
+        max_message_bytes: 1024,
+        max_receipt_bytes: 1024,
+    };
 
-fn client(stream: &mut TcpStream) {
-    println!("[DEBUG] Starting client processing");
-    
-    println!("[DEBUG] Attempting to deserialize incoming message");
-    let data = bincode::decode_from_std_read::<MessageReceived, Configuration, TcpStream>(stream, bincode::config::standard()).expect("failed to deserialize");
-    println!("[DEBUG] Message successfully deserialized");
-    
-    println!("[DEBUG] Decoding proof journal");
-    let (existence, file_hash, id_hash, pass_hash) : (u8, [u8;32], [u8; 32], [u8; 32]) = data.proof.journal.decode().expect("failed to decode journal");
-    println!("[DEBUG] Journal decoded - existence: {}, file_hash: {:?}, id_hash: {:?}", existence, file_hash, id_hash);
-    
-    println!("Received data: {:?}", data);
-
-    println!("[DEBUG] Starting proof verification with METHOD_ID: {:?}", METHOD_ID);
-    data.proof.verify(METHOD_ID).expect("failed to verify proof");
-
-    println!("Proof verified!");
-    println!("[DEBUG] Zero-knowledge proof verification successful");
-    
-    if existence != 1 {
-        println!("False proof. Why would you prove that?");
-        println!("[DEBUG] Proof indicates non-existence (existence = {}), terminating processing", existence);
-        return;
+    /// A KDC over a throwaway database; nothing here reaches the keys.
+    pub(crate) fn kdc(dir: &std::path::Path) -> Arc<Kdc> {
+        std::fs::write(dir.join("db.txt"), "").unwrap();
+        std::fs::write(
+            dir.join("kdc.toml"),
+            r#"
+image_ids = [[1, 2, 3, 4, 5, 6, 7, 8]]
+[credential_db]
+source = "file"
+path = "db.txt"
+[keys]
+tgt_key = "tgt.key"
+root_key = "root.key"
+signing_keyring = "signing"
+"#,
+        )
+        .unwrap();
+        let cli = Cli::parse_from(["zkk_server", "--config", dir.join("kdc.toml").to_str().unwrap()]);
+        Arc::new(Kdc::new(&ServerConfig::load(cli).unwrap()).unwrap())
     }
-    println!("[DEBUG] Existence proof validated (existence = {})", existence);
-
-    println!("[DEBUG] Starting file download process");
-    download();
-    println!("[DEBUG] File download completed, reading file data");
-    
-    let file_data = std::fs::read("New Document.txt").expect("failed to read file");
-    println!("[DEBUG] File data read successfully, length: {} bytes", file_data.len());
-    
-    println!("[DEBUG] Computing SHA256 hash of file data");
-    let mut hasher = Sha256::new();
-    hasher.update(&file_data);
-    let file_data_hash: [u8; 32] = hasher.finalize().into();
-    println!("[DEBUG] File data hash computed: {:?}", file_data_hash);
-
-    if file_data_hash != file_hash {
-        println!("File hash mismatch! Provided: {:?}, Actual: {:?}", file_hash, file_data_hash);
-        println!("[DEBUG] Hash verification failed, terminating processing");
-        return;
+
+    /// Generates and loads keys, with the services `webmail` and `wifi`, the
+    /// first time any test in this process needs them.
+    pub(crate) fn load_keys() {
+        static LOADED: std::sync::Once = std::sync::Once::new();
+        LOADED.call_once(|| {
+            let dir = tempfile::tempdir().unwrap().keep();
+            let files = config::KeyFiles {
+                signing_keyring: dir.join("signing"),
+                root_key: dir.join("root.key"),
+                tgt_key: dir.join("tgt.key"),
+                services: ["webmail", "wifi"].map(|name| (name.to_owned(), dir.join(format!("{name}.key")))).into(),
+            };
+            let rotation = config::KeyRotation {
+                rotate_after: Duration::from_secs(30 * 86_400),
+                overlap: Duration::from_secs(86_400),
+                announce_ahead: Duration::from_secs(86_400),
+            };
+            keys::init(&files, kdc::now()).unwrap();
+            keys::load(&files, rotation).unwrap();
+        });
     }
-    println!("[DEBUG] File hash verification successful");
-
-    let mut hasher = Sha256::new();
-    hasher.update(&id_hash);
-    hasher.update(&pass_hash);
-
-    println!("[DEBUG] Generating session key");
-    let session_key = keys::gen_session_key();
-    println!("[DEBUG] Session key generated successfully");
-    
-    println!("[DEBUG] Getting current timestamp");
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs();
-    println!("[DEBUG] Current timestamp: {}", timestamp);
-    
-    println!("[DEBUG] Creating SignBundle");
-    let bundle = SignBundle {
-        ssk: session_key,
-        pass_hash: pass_hash.try_into().expect("slice with incorrect length"),
-        comb_hash: hasher.finalize().into(),
-        timestamp,
-    };
-    
-    println!("[DEBUG] SignBundle created: {:?}", bundle);
-
-    println!("[DEBUG] Serializing SignBundle for signing");
-    let encoded: Vec<u8> = bincode::encode_to_vec(&bundle, bincode::config::standard()).expect("failed to serialize");
-    println!("[DEBUG] SignBundle serialized, length: {} bytes", encoded.len());
-    
-    println!("[DEBUG] Generating private signature");
-    let signature = keys::private_sign(&encoded);
-    println!("[DEBUG] Private signature generated: {:?}", signature);
-    
-    println!("[DEBUG] Creating response message");
-    let response = MessageSent {
-        signature,
-        signature_data: bundle,
-    };
-    println!("[DEBUG] Response message created: {:?}", response);
-
-    println!("[DEBUG] Serializing response message for encryption");
-    let plain = bincode::encode_to_vec(&response, bincode::config::standard())
-        .expect("failed to serialize response for encryption");
-    println!("[DEBUG] Response serialized, length: {} bytes", plain.len());
-
-    println!("[DEBUG] Initializing RNG for encryption");
-    let mut rng = rand::rngs::OsRng;
-    println!("[DEBUG] Starting RSA encryption with client's public key");
-    let encrypted = data.u_pk.encrypt(
-        &mut rng,
-        Pkcs1v15Encrypt,
-        &plain,
-    ).expect("failed to encrypt response");
-    println!("[DEBUG] RSA encryption completed, encrypted length: {} bytes", encrypted.len());
-
-    // shadow the original `response` so the subsequent write sends the ciphertext
-    let response = encrypted;
-
-    println!("[DEBUG] Sending encrypted response to client");
-    bincode::encode_into_std_write(&response, stream, bincode::config::standard()).expect("failed to send response");
-    println!("Response sent: {:?}", response);
-    println!("[DEBUG] Response sent successfully");
-
-    println!("[DEBUG] Shutting down connection");
-    if let Err(e) = stream.shutdown(std::net::Shutdown::Both) {
-        eprintln!("Failed to shutdown connection: {}", e);
-        eprintln!("[DEBUG] Connection shutdown failed: {:?}", e);
-    } else {
-        println!("[DEBUG] Connection shutdown successful");
-    }
-    println!("[DEBUG] Client processing completed");
-}
\ No newline at end of file
+
+    /// Accepts one client into a single-slot semaphore and returns the
+    /// client side along with the semaphore.
+    async fn connect() -> (TcpStream, Arc<Semaphore>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let kdc = kdc(dir.path());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let connections = Arc::new(Semaphore::new(1));
+        let permit = connections.clone().try_acquire_owned().unwrap();
+        tokio::spawn(handle_client(None, stream, peer.ip(), LIMITS, kdc, permit));
+        (client, connections, dir)
+    }
+
+    /// Reads until the server closes, failing if it takes longer than the
+    /// longest timeout could allow. Returns what was read.
+    pub(crate) async fn until_closed(client: &mut TcpStream) -> Vec<u8> {
+        let mut read = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut read))
+            .await
+            .expect("server kept the connection open")
+            .ok();
+        read
+    }
+
+    /// The permit goes back once the connection task ends.
+    pub(crate) async fn assert_released(connections: &Semaphore) {
+        let permit = tokio::time::timeout(Duration::from_secs(1), connections.acquire()).await;
+        drop(permit.expect("permit still held").unwrap());
+    }
+
+    #[tokio::test]
+    async fn silent_client_is_dropped() {
+        let (mut client, connections, _dir) = connect().await;
+        assert!(until_closed(&mut client).await.is_empty());
+        assert_released(&connections).await;
+    }
+
+    #[tokio::test]
+    async fn trickling_client_is_dropped() {
+        let (mut client, connections, _dir) = connect().await;
+        let frame = Frame::new(PROTOCOL_VERSION, MessageKind::Hello, &Hello::supported()).unwrap().to_bytes();
+        // Each byte well inside the idle timeout, the frame as a whole past the read timeout.
+        for byte in frame {
+            if client.write_all(&[byte]).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(LIMITS.read_timeout / 4).await;
+        }
+        assert!(until_closed(&mut client).await.is_empty());
+        assert_released(&connections).await;
+    }
+
+    /// Reads one frame's header and payload.
+    async fn read_frame(client: &mut TcpStream) -> Frame {
+        let mut header = [0u8; HEADER_LEN];
+        client.read_exact(&mut header).await.unwrap();
+        let header = FrameHeader::parse(&header, MAX_FRAME_LEN).unwrap();
+        let mut payload = vec![0u8; header.len as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        Frame {
+            version: header.version,
+            kind: header.kind,
+            payload,
+        }
+    }
+
+    #[tokio::test]
+    async fn old_client_hello_gets_version_rejected() {
+        let (mut client, connections, _dir) = connect().await;
+        let old = Hello { min_version: 3, max_version: 3 };
+        client.write_all(&Frame::new(3, MessageKind::Hello, &old).unwrap().to_bytes()).await.unwrap();
+        let reply = read_frame(&mut client).await;
+        assert_eq!((reply.kind, reply.version), (MessageKind::VersionRejected, 3));
+        assert_eq!(reply.body::<Hello>().unwrap(), Hello::supported());
+        drop(client);
+        assert_released(&connections).await;
+    }
+
+    #[tokio::test]
+    async fn looping_hello_is_dropped() {
+        let (mut client, connections, _dir) = connect().await;
+        let hello = Frame::new(PROTOCOL_VERSION, MessageKind::Hello, &Hello::supported()).unwrap().to_bytes();
+        let mut acks = 0;
+        loop {
+            if client.write_all(&hello).await.is_err() {
+                break;
+            }
+            let mut first = [0u8; 1];
+            if client.peek(&mut first).await.unwrap_or(0) == 0 {
+                break;
+            }
+            assert_eq!(read_frame(&mut client).await.kind, MessageKind::HelloAck);
+            acks += 1;
+            assert!(acks <= LIMITS.max_requests, "more Hellos answered than the request limit");
+        }
+        assert_eq!(acks, LIMITS.max_requests);
+        assert!(until_closed(&mut client).await.is_empty());
+        assert_released(&connections).await;
+    }
+}
@@ -0,0 +1,158 @@
+//! Per-source token buckets for requests that cost the KDC work.
+//!
+//! Clients are anonymous, so a source is only an address: IPv4 addresses
+//! count on their own and IPv6 ones by /64, the block one host usually gets.
+//! That makes this a backstop against a single noisy host, not a limit per
+//! user; the proof-of-work stamp on each `AsReq` is what makes verification
+//! cost the client.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use zkk_protocol::KdcError;
+
+use crate::kdc;
+
+pub struct RateLimiter {
+    /// Tokens added per second.
+    rate: f64,
+    burst: f64,
+    capacity: usize,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    /// `per_minute` tokens a minute per source, up to `burst` saved up. At
+    /// most `capacity` sources are tracked at once.
+    pub fn new(per_minute: u32, burst: u32, capacity: usize) -> Self {
+        RateLimiter {
+            rate: f64::from(per_minute) / 60.0,
+            burst: f64::from(burst),
+            capacity,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `peer`, or refuses with `RateLimited`. When the table
+    /// is full of sources that are all still limited, new ones get `Busy`.
+    pub fn check(&self, peer: IpAddr) -> Result<(), KdcError> {
+        self.check_at(peer, Instant::now())
+    }
+
+    fn check_at(&self, peer: IpAddr, now: Instant) -> Result<(), KdcError> {
+        let source = source(peer);
+        let mut buckets = self.buckets.lock().expect("rate limiter poisoned");
+        if buckets.len() >= self.capacity && !buckets.contains_key(&source) {
+            // A full bucket is the same as no bucket.
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, self.rate, self.burst);
+                bucket.tokens < self.burst
+            });
+            if buckets.len() >= self.capacity {
+                return Err(kdc::reject(KdcError::Busy, "too many rate-limited sources"));
+            }
+        }
+        let bucket = buckets.entry(source).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.refill(now, self.rate, self.burst);
+        if bucket.tokens < 1.0 {
+            return Err(kdc::reject(KdcError::RateLimited, source));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+fn source(peer: IpAddr) -> IpAddr {
+    match peer {
+        IpAddr::V4(_) => peer,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(v6.to_bits() & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bucket_drains_and_refills() {
+        let limiter = RateLimiter::new(60, 3, 16);
+        let (peer, start) = (ip("192.0.2.1"), Instant::now());
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(peer, start), Ok(()));
+        }
+        assert_eq!(limiter.check_at(peer, start), Err(KdcError::RateLimited));
+        // Another source has its own bucket.
+        assert_eq!(limiter.check_at(ip("192.0.2.2"), start), Ok(()));
+
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(limiter.check_at(peer, later), Ok(()));
+        assert_eq!(limiter.check_at(peer, later), Err(KdcError::RateLimited));
+
+        // Saving up stops at the burst.
+        let much_later = later + Duration::from_secs(600);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(peer, much_later), Ok(()));
+        }
+        assert_eq!(limiter.check_at(peer, much_later), Err(KdcError::RateLimited));
+    }
+
+    #[test]
+    fn ipv6_sources_are_grouped_by_64() {
+        assert_eq!(source(ip("2001:db8:1:2::1")), ip("2001:db8:1:2::"));
+        assert_eq!(source(ip("2001:db8:1:2:ffff:ffff:ffff:ffff")), ip("2001:db8:1:2::"));
+        assert_eq!(source(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(source(ip("192.0.2.1")), ip("192.0.2.1"));
+
+        let limiter = RateLimiter::new(60, 2, 16);
+        let start = Instant::now();
+        assert_eq!(limiter.check_at(ip("2001:db8:1:2::1"), start), Ok(()));
+        assert_eq!(limiter.check_at(ip("2001:db8:1:2:aaaa::9"), start), Ok(()));
+        assert_eq!(limiter.check_at(ip("2001:db8:1:2::7"), start), Err(KdcError::RateLimited));
+        assert_eq!(limiter.check_at(ip("2001:db8:1:3::1"), start), Ok(()));
+    }
+
+    #[test]
+    fn full_table_evicts_only_sources_back_at_the_burst() {
+        let limiter = RateLimiter::new(60, 2, 2);
+        let start = Instant::now();
+        assert_eq!(limiter.check_at(ip("192.0.2.1"), start), Ok(()));
+        assert_eq!(limiter.check_at(ip("192.0.2.2"), start), Ok(()));
+        assert_eq!(limiter.check_at(ip("192.0.2.3"), start), Err(KdcError::Busy));
+        // Sources already tracked are still served.
+        assert_eq!(limiter.check_at(ip("192.0.2.1"), start), Ok(()));
+
+        // A second later 192.0.2.2 is back at the burst and makes room; 192.0.2.1 is not.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(ip("192.0.2.3"), later), Ok(()));
+        assert_eq!(limiter.check_at(ip("192.0.2.4"), later), Err(KdcError::Busy));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        assert!(limiter.buckets.lock().unwrap().contains_key(&ip("192.0.2.1")));
+    }
+}
@@ -0,0 +1,106 @@
+//! Holder for key material and credential hashes.
+//!
+//! A [`Secret`] prints as `[redacted]` under both `{}` and `{:?}`, so
+//! neither a log line nor a `Debug` dump of a struct holding one can reveal
+//! it. The value is zeroed when dropped.
+
+use std::fmt;
+
+use zeroize::Zeroize;
+
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    /// The value itself, for use as a key. Never pass it to a log macro.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Secret(self.0.clone())
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl<T: Zeroize> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize + bincode::Encode> bincode::Encode for Secret<T> {
+    fn encode<E: bincode::enc::Encoder>(&self, encoder: &mut E) -> Result<(), bincode::error::EncodeError> {
+        self.0.encode(encoder)
+    }
+}
+
+impl<Context, T: Zeroize + bincode::Decode<Context>> bincode::Decode<Context> for Secret<T> {
+    fn decode<D: bincode::de::Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, bincode::error::DecodeError> {
+        T::decode(decoder).map(Secret)
+    }
+}
+
+impl<'de, Context, T: Zeroize + bincode::Decode<Context>> bincode::BorrowDecode<'de, Context> for Secret<T> {
+    fn borrow_decode<D: bincode::de::BorrowDecoder<'de, Context = Context>>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        T::decode(decoder).map(Secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tgs::TgtBody;
+
+    #[test]
+    fn secret_prints_redacted() {
+        let secret = Secret::new([171u8; 32]);
+        let printed = [format!("{}", secret), format!("{:?}", secret), format!("{:#?}", secret)];
+        assert_eq!(printed, ["[redacted]"; 3]);
+        let secret = Secret::new(String::from("hunter2"));
+        assert!(!format!("{} {:?}", secret, Some(&secret)).contains("hunter2"));
+    }
+
+    #[test]
+    fn tgt_body_debug_hides_the_session_key() {
+        let body = TgtBody {
+            session_key: Secret::new([171; 32]),
+            comb_hash: [3; 32],
+            not_before: 10_000,
+            expires_at: 20_000,
+        };
+        for printed in [format!("{:?}", body), format!("{:#?}", body)] {
+            assert!(printed.contains("session_key: [redacted]"), "{printed}");
+            assert!(!printed.contains("171"), "{printed}");
+            assert!(!printed.contains("pass_hash"), "{printed}");
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Prometheus metrics, served as text at `GET /metrics` on `metrics_listen`.
+//!
+//! The port is separate from the KDC and HTTP ports so it can stay bound to
+//! localhost while those are public. Without `metrics_listen` no recorder is
+//! installed and the functions here cost next to nothing.
+//!
+//! Labels only ever take values from a fixed set (error names, key IDs,
+//! registered services), never anything a client chooses.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::http::header;
+use axum::routing::get;
+use axum::Router;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tokio::net::TcpListener;
+use tracing::info;
+use zkk_protocol::{KdcError, KeyId};
+
+use crate::audit::Event;
+
+const CONNECTIONS: &str = "zkk_connections_total";
+const CONNECTIONS_OPEN: &str = "zkk_connections_open";
+const REQUESTS: &str = "zkk_requests_total";
+const REFUSED: &str = "zkk_refused_total";
+const TICKETS: &str = "zkk_tickets_issued_total";
+const PROOF_VERIFY: &str = "zkk_proof_verify_seconds";
+const DB_FETCH: &str = "zkk_db_fetch_seconds";
+const DB_CACHE: &str = "zkk_db_cache_total";
+
+/// Proof verification takes seconds; fetching the database over IPFS can take longer.
+const SECONDS_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// Histograms are folded into the output at least this often.
+const UPKEEP: Duration = Duration::from_secs(5);
+
+/// Installs the global recorder. Call once, before serving.
+pub fn install() -> anyhow::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), SECONDS_BUCKETS)?
+        .install_recorder()?;
+    describe_counter!(CONNECTIONS, "TCP connections, by whether they were accepted or refused as busy.");
+    describe_gauge!(CONNECTIONS_OPEN, "TCP connections being served.");
+    describe_counter!(REQUESTS, "AsReq and TgsReq answered, by outcome: `issued` or the error sent.");
+    describe_counter!(REFUSED, "Requests refused before any real work: rate limits and proof-of-work stamps.");
+    describe_counter!(TICKETS, "Service tickets issued, by signing key and service.");
+    describe_histogram!(PROOF_VERIFY, Unit::Seconds, "Time to verify one receipt, by result.");
+    describe_histogram!(DB_FETCH, Unit::Seconds, "Time to fetch the credential database from its source, by result.");
+    describe_counter!(DB_CACHE, "Credential database lookups by requests, by where the copy came from.");
+    Ok(handle)
+}
+
+/// Serves `GET /metrics` until the listener fails.
+pub async fn serve(listen: SocketAddr, handle: PrometheusHandle) -> std::io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    info!(%listen, "Metrics listening");
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(UPKEEP);
+        loop {
+            ticker.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+    let app = Router::new().route(
+        "/metrics",
+        get(move || async move { ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], handle.render()) }),
+    );
+    axum::serve(listener, app).await
+}
+
+pub fn connection(accepted: bool) {
+    let outcome = if accepted { "accepted" } else { "refused" };
+    counter!(CONNECTIONS, "outcome" => outcome).increment(1);
+}
+
+/// Counts a connection as open until dropped.
+pub struct OpenConnection(());
+
+pub fn open_connection() -> OpenConnection {
+    gauge!(CONNECTIONS_OPEN).increment(1.0);
+    OpenConnection(())
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        gauge!(CONNECTIONS_OPEN).decrement(1.0);
+    }
+}
+
+/// `outcome` is `issued` or a `KdcError` name, as in the audit log.
+pub fn request(event: Event, outcome: &str, key_id: Option<KeyId>, service: Option<&str>) {
+    let kind = match event {
+        Event::AsReq => "as_req",
+        Event::TgsReq => "tgs_req",
+    };
+    counter!(REQUESTS, "kind" => kind, "outcome" => outcome.to_owned()).increment(1);
+    if let (Event::TgsReq, "issued", Some(key_id), Some(service)) = (event, outcome, key_id, service) {
+        counter!(TICKETS, "key_id" => key_id.to_string(), "service" => service.to_owned()).increment(1);
+    }
+}
+
+/// Turned away by [`crate::ratelimit`] or the `AsReq` stamp check.
+pub fn refused(error: KdcError) {
+    counter!(REFUSED, "reason" => format!("{:?}", error)).increment(1);
+}
+
+/// `image` is the label of the accepted image the receipt claims.
+pub fn proof_verified(image: &str, elapsed: Duration, valid: bool) {
+    let result = if valid { "valid" } else { "invalid" };
+    histogram!(PROOF_VERIFY, "image" => image.to_owned(), "result" => result).record(elapsed);
+}
+
+pub fn db_fetched(elapsed: Duration, ok: bool) {
+    let result = if ok { "ok" } else { "error" };
+    histogram!(DB_FETCH, "result" => result).record(elapsed);
+}
+
+/// `source` is where a request's copy came from: `memory` for the one in
+/// use, `disk` for the disk cache, or `fetch` for the configured source.
+/// Background refreshes are not lookups; their fetches show in `db_fetched`.
+pub fn db_lookup(source: &'static str) {
+    counter!(DB_CACHE, "source" => source).increment(1);
+}
@@ -0,0 +1,251 @@
+//! Ticket-granting tickets.
+//!
+//! A TGT is a [`TgtBody`] sealed under the KDC's TGT key, so the client can
+//! hold it but neither read nor alter it. The session key inside is also
+//! handed to the client in the `AsReply`; `TgsReq` authenticators are MACed
+//! with it and `TgsRep`s are sealed under it.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::RngCore;
+use zkk_protocol::{crypto, AsReply, Authenticator, KdcError};
+
+use crate::kdc::{self, now};
+use crate::keys;
+use crate::secret::Secret;
+
+#[derive(Debug, bincode::Encode, bincode::Decode)]
+pub struct TgtBody {
+    pub session_key: Secret<[u8; 32]>,
+    /// SHA-256 of the proof's `id_hash || pass_hash`. Never leaves the KDC;
+    /// tickets carry a per-service [`pseudonym`] of it instead.
+    pub comb_hash: [u8; 32],
+    pub not_before: u64,
+    pub expires_at: u64,
+}
+
+impl TgtBody {
+    /// Checks `now` lies within `not_before..expires_at`, widened by `skew` on both ends.
+    pub fn check_validity(&self, now: u64, skew: Duration) -> Result<(), KdcError> {
+        if now + skew.as_secs() < self.not_before {
+            return Err(kdc::reject(KdcError::TicketInvalid, format!("TGT not valid before {}", self.not_before)));
+        }
+        if now.saturating_sub(skew.as_secs()) >= self.expires_at {
+            return Err(kdc::reject(KdcError::TicketExpired, format!("TGT expired at {}", self.expires_at)));
+        }
+        Ok(())
+    }
+}
+
+/// A fresh session key straight from the OS CSPRNG, for one TGT or ticket.
+pub fn session_key() -> Secret<[u8; 32]> {
+    let mut key = Secret::new([0u8; 32]);
+    rand::rngs::OsRng.fill_bytes(key.expose_mut());
+    key
+}
+
+/// Seals a fresh TGT for the user a proof established.
+pub fn issue(comb_hash: [u8; 32], lifetime: Duration) -> Result<AsReply, KdcError> {
+    let session_key = session_key();
+    let not_before = now();
+    let body = TgtBody {
+        session_key: session_key.clone(),
+        comb_hash,
+        not_before,
+        expires_at: not_before + lifetime.as_secs(),
+    };
+    Ok(AsReply {
+        tgt: seal(&body)?,
+        session_key: *session_key.expose(),
+        expires_at: body.expires_at,
+    })
+}
+
+/// Seals `body` under the TGT key; [`open`] reverses it.
+pub fn seal(body: &TgtBody) -> Result<Vec<u8>, KdcError> {
+    let plain = Secret::new(zkk_protocol::encode(body).map_err(|e| kdc::reject(KdcError::Internal, e))?);
+    Ok(crypto::seal(keys::tgt_key(), plain.expose()))
+}
+
+/// What a ticket for the service holding `service_key` calls the user with
+/// `comb_hash`. Services cannot compute each other's, so they cannot match
+/// up their users.
+pub fn pseudonym(service_key: &[u8; 32], comb_hash: &[u8; 32]) -> [u8; 32] {
+    crypto::mac(service_key, "zkk service pseudonym v1", &[comb_hash])
+}
+
+/// Opens a TGT and checks it is currently valid.
+pub fn open(tgt: &[u8], skew: Duration) -> Result<TgtBody, KdcError> {
+    let plain = Secret::new(crypto::open(keys::tgt_key(), tgt).map_err(|e| kdc::reject(KdcError::TicketInvalid, e))?);
+    let body: TgtBody = zkk_protocol::decode(plain.expose()).map_err(|e| kdc::reject(KdcError::TicketInvalid, e))?;
+    body.check_validity(now(), skew)?;
+    Ok(body)
+}
+
+/// Checks a `TgsReq` authenticator's MAC and freshness.
+pub fn check_authenticator(
+    authenticator: &Authenticator,
+    body: &TgtBody,
+    tgt: &[u8],
+    service: &str,
+    skew: Duration,
+) -> Result<(), KdcError> {
+    if !authenticator.verify(body.session_key.expose(), tgt, service) {
+        return Err(kdc::reject(KdcError::AuthenticatorInvalid, "MAC mismatch"));
+    }
+    if now().abs_diff(authenticator.timestamp) > skew.as_secs() {
+        return Err(kdc::reject(
+            KdcError::AuthenticatorInvalid,
+            format!("timestamp {} outside clock skew", authenticator.timestamp),
+        ));
+    }
+    Ok(())
+}
+
+/// Authenticators seen within the clock skew window. A fresh timestamp is all
+/// the KDC checks otherwise, so this is what stops a captured `TgsReq` being
+/// sent again.
+pub struct ReplayCache {
+    capacity: usize,
+    seen: Mutex<HashMap<[u8; 32], u64>>,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> Self {
+        ReplayCache {
+            capacity,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records `authenticator`, which is remembered until `timestamp + skew`.
+    pub fn insert(&self, authenticator: &Authenticator, skew: Duration) -> Result<(), KdcError> {
+        let now = now();
+        let mut seen = self.seen.lock().expect("replay cache poisoned");
+        if seen.contains_key(&authenticator.mac) {
+            return Err(kdc::reject(KdcError::AuthenticatorReplayed, authenticator.timestamp));
+        }
+        if seen.len() >= self.capacity {
+            seen.retain(|_, forget_at| *forget_at >= now);
+            if seen.len() >= self.capacity {
+                return Err(kdc::reject(KdcError::Busy, "replay cache full"));
+            }
+        }
+        seen.insert(authenticator.mac, authenticator.timestamp + skew.as_secs());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::load_keys;
+
+    const SKEW: Duration = Duration::from_secs(300);
+
+    fn body(not_before: u64, expires_at: u64) -> TgtBody {
+        TgtBody {
+            session_key: Secret::new([1; 32]),
+            comb_hash: [3; 32],
+            not_before,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn validity_allows_the_clock_skew_on_both_ends() {
+        let tgt = body(10_000, 20_000);
+        assert_eq!(tgt.check_validity(10_000 - 300 - 1, SKEW), Err(KdcError::TicketInvalid));
+        assert_eq!(tgt.check_validity(10_000 - 300, SKEW), Ok(()));
+        assert_eq!(tgt.check_validity(15_000, SKEW), Ok(()));
+        assert_eq!(tgt.check_validity(20_000 + 300 - 1, SKEW), Ok(()));
+        assert_eq!(tgt.check_validity(20_000 + 300, SKEW), Err(KdcError::TicketExpired));
+    }
+
+    #[test]
+    fn validity_without_skew_is_half_open() {
+        let tgt = body(10_000, 20_000);
+        assert_eq!(tgt.check_validity(9_999, Duration::ZERO), Err(KdcError::TicketInvalid));
+        assert_eq!(tgt.check_validity(10_000, Duration::ZERO), Ok(()));
+        assert_eq!(tgt.check_validity(19_999, Duration::ZERO), Ok(()));
+        assert_eq!(tgt.check_validity(20_000, Duration::ZERO), Err(KdcError::TicketExpired));
+        // A skew wider than the clock reading does not wrap around.
+        assert_eq!(body(0, 100).check_validity(50, Duration::from_secs(u32::MAX.into())), Ok(()));
+    }
+
+    #[test]
+    fn tgt_opens_to_what_was_sealed() {
+        load_keys();
+        let reply = issue([3; 32], Duration::from_secs(3600)).unwrap();
+        let tgt = open(&reply.tgt, SKEW).unwrap();
+        assert_eq!(tgt.session_key.expose(), &reply.session_key);
+        assert_eq!(tgt.comb_hash, [3; 32]);
+        assert_eq!(tgt.expires_at, reply.expires_at);
+        assert_eq!(tgt.expires_at - tgt.not_before, 3600);
+
+        for i in [0, reply.tgt.len() / 2, reply.tgt.len() - 1] {
+            let mut altered = reply.tgt.clone();
+            altered[i] ^= 1;
+            assert!(matches!(open(&altered, SKEW), Err(KdcError::TicketInvalid)));
+        }
+        // Sealed under another key.
+        let forged = crypto::seal(&[7; 32], &zkk_protocol::encode(&body(now(), now() + 3600)).unwrap());
+        assert!(matches!(open(&forged, SKEW), Err(KdcError::TicketInvalid)));
+    }
+
+    #[test]
+    fn expired_tgt_is_refused() {
+        load_keys();
+        let tgt = seal(&body(now() - 7200, now() - SKEW.as_secs() - 1)).unwrap();
+        assert!(matches!(open(&tgt, SKEW), Err(KdcError::TicketExpired)));
+        let tgt = seal(&body(now() + SKEW.as_secs() + 60, now() + 7200)).unwrap();
+        assert!(matches!(open(&tgt, SKEW), Err(KdcError::TicketInvalid)));
+    }
+
+    #[test]
+    fn authenticator_must_match_the_tgt_and_service() {
+        let tgt = body(now(), now() + 3600);
+        let sealed = b"sealed tgt";
+        let good = Authenticator::new(tgt.session_key.expose(), sealed, "webmail", now());
+        assert_eq!(check_authenticator(&good, &tgt, sealed, "webmail", SKEW), Ok(()));
+
+        let mut altered = good;
+        altered.mac[0] ^= 1;
+        let cases = [
+            (Authenticator::new(&[9; 32], sealed, "webmail", now()), &sealed[..], "webmail"),
+            (good, b"another tgt", "webmail"),
+            (good, sealed, "wifi"),
+            (altered, sealed, "webmail"),
+            (Authenticator::new(tgt.session_key.expose(), sealed, "webmail", now() - 301), sealed, "webmail"),
+            (Authenticator::new(tgt.session_key.expose(), sealed, "webmail", now() + 301), sealed, "webmail"),
+        ];
+        for (authenticator, tgt_bytes, service) in cases {
+            assert_eq!(
+                check_authenticator(&authenticator, &tgt, tgt_bytes, service, SKEW),
+                Err(KdcError::AuthenticatorInvalid)
+            );
+        }
+    }
+
+    #[test]
+    fn replay_cache_refuses_an_authenticator_twice() {
+        let cache = ReplayCache::new(2);
+        let first = Authenticator { timestamp: now(), mac: [1; 32] };
+        assert_eq!(cache.insert(&first, SKEW), Ok(()));
+        assert_eq!(cache.insert(&first, SKEW), Err(KdcError::AuthenticatorReplayed));
+
+        // Full of authenticators that are still fresh: refused rather than forgotten.
+        let second = Authenticator { timestamp: now(), mac: [2; 32] };
+        assert_eq!(cache.insert(&second, SKEW), Ok(()));
+        assert_eq!(cache.insert(&Authenticator { timestamp: now(), mac: [3; 32] }, SKEW), Err(KdcError::Busy));
+        assert_eq!(cache.insert(&first, SKEW), Err(KdcError::AuthenticatorReplayed));
+
+        // Stale ones make room.
+        let cache = ReplayCache::new(1);
+        let stale = Authenticator { timestamp: now() - 2 * SKEW.as_secs(), mac: [4; 32] };
+        assert_eq!(cache.insert(&stale, SKEW), Ok(()));
+        assert_eq!(cache.insert(&first, SKEW), Ok(()));
+    }
+}
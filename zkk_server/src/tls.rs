@@ -0,0 +1,172 @@
+//! Optional TLS for the KDC and HTTP listeners.
+//!
+//! Without it only replies are sealed: receipts, their journals and the
+//! services asked for cross the network in the clear. Both listeners share
+//! one certificate; the metrics port stays plaintext and should stay local.
+//!
+//! `zkk_server tls dev-certs` makes a throwaway CA and a server certificate
+//! signed by it, for clients to trust or pin while testing.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair, KeyUsagePurpose};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+
+use crate::config::{TlsCommand, TlsFiles};
+
+/// Builds the acceptor both listeners use. Logs the certificate's pin.
+pub fn acceptor(files: &TlsFiles) -> anyhow::Result<TlsAcceptor> {
+    let chain = CertificateDer::pem_file_iter(&files.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates from {}", files.cert.display()))?;
+    let Some(leaf) = chain.first() else {
+        anyhow::bail!("no certificate in {}", files.cert.display());
+    };
+    info!(pin = %pin(leaf), "TLS enabled");
+    let key = PrivateKeyDer::from_pem_file(&files.key)
+        .with_context(|| format!("failed to read private key from {}", files.key.display()))?;
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .context("certificate does not match its key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// What clients pin: the hex sha256 of the DER certificate.
+pub fn pin(cert: &CertificateDer) -> String {
+    hex::encode(Sha256::digest(cert))
+}
+
+/// An accepted connection, with or without TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()> {
+        self.tcp().set_nodelay(nodelay)
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Runs the handshake if TLS is on, giving up after `limit`.
+pub async fn accept(tls: Option<&TlsAcceptor>, stream: TcpStream, limit: Duration) -> std::io::Result<Stream> {
+    let Some(tls) = tls else {
+        return Ok(Stream::Plain(stream));
+    };
+    match timeout(limit, tls.accept(stream)).await {
+        Ok(stream) => Ok(Stream::Tls(Box::new(stream?))),
+        Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+    }
+}
+
+pub fn admin(command: &TlsCommand) -> anyhow::Result<()> {
+    match command {
+        TlsCommand::DevCerts { out, names } => {
+            let server = dev_certs(out, names)?;
+            println!("Wrote ca.pem, server.pem and server.key to {}", out.display());
+            println!("Server certificate pin: {}", pin(&server));
+        }
+    }
+    Ok(())
+}
+
+/// Writes a new CA and a server certificate for `names` it signed. The CA
+/// key is thrown away, so the CA can never sign anything else.
+fn dev_certs(dir: &Path, names: &[String]) -> anyhow::Result<CertificateDer<'static>> {
+    let mut ca_params = CertificateParams::default();
+    ca_params.distinguished_name.push(DnType::CommonName, "zk-kerberos dev CA");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate()?)?;
+
+    let mut params = CertificateParams::new(names.to_vec())?;
+    params.distinguished_name.push(DnType::CommonName, "zk-kerberos KDC");
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth];
+    let key = KeyPair::generate()?;
+    let cert = params.signed_by(&key, &ca)?;
+
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    for name in ["ca.pem", "server.pem", "server.key"] {
+        if dir.join(name).exists() {
+            anyhow::bail!("{} already exists", dir.join(name).display());
+        }
+    }
+    write_new(&dir.join("ca.pem"), &ca.pem(), false)?;
+    write_new(&dir.join("server.pem"), &cert.pem(), false)?;
+    write_new(&dir.join("server.key"), &key.serialize_pem(), true)?;
+    Ok(cert.der().clone())
+}
+
+/// Refuses to overwrite, so an existing CA is not replaced behind the back
+/// of clients that trust it.
+fn write_new(path: &Path, contents: &str, secret: bool) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if secret {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
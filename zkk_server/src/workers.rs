@@ -0,0 +1,163 @@
+//! Bounded pool for the blocking half of a request (proof verification, DB fetch, signing).
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+use tracing::Span;
+use zkk_protocol::KdcError;
+
+use crate::kdc;
+
+pub struct VerifierPool {
+    workers: usize,
+    permits: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    queue_timeout: Duration,
+}
+
+impl VerifierPool {
+    pub fn new(workers: usize, queue_timeout: Duration) -> Self {
+        VerifierPool {
+            workers,
+            permits: Arc::new(Semaphore::new(workers)),
+            waiting: AtomicUsize::new(0),
+            queue_timeout,
+        }
+    }
+
+    /// Jobs running or waiting, per worker: 0 when idle, 1 when every worker
+    /// is busy, 2 when as many jobs wait as there are workers.
+    pub fn load(&self) -> f64 {
+        let running = self.workers - self.permits.available_permits();
+        (running + self.waiting.load(Ordering::Relaxed)) as f64 / self.workers as f64
+    }
+
+    /// Runs `job` on a blocking thread once a worker is free, inside the
+    /// caller's span. Jobs that wait longer than the queue timeout are refused
+    /// with `KdcError::Busy`.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, KdcError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, KdcError> + Send + 'static,
+    {
+        let waiting = Waiting::new(&self.waiting);
+        let acquired = tokio::time::timeout(self.queue_timeout, self.permits.clone().acquire_owned()).await;
+        drop(waiting);
+        let permit = match acquired {
+            Ok(Ok(permit)) => permit,
+            Ok(Err(_)) => return Err(kdc::reject(KdcError::Internal, "verifier pool closed")),
+            Err(_) => return Err(kdc::reject(KdcError::Busy, "no verification worker free")),
+        };
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _span = span.enter();
+            job()
+        })
+        .await
+        .map_err(|e| kdc::reject(KdcError::Internal, e))?
+    }
+}
+
+/// Counts a job as waiting until dropped, including when the caller gives up.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Waiting(count)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    /// Occupies the pool's only worker until the returned sender is dropped.
+    async fn occupy(pool: &Arc<VerifierPool>) -> (mpsc::Sender<()>, tokio::task::JoinHandle<Result<(), KdcError>>) {
+        let (release, wait) = mpsc::channel::<()>();
+        let job = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.run(move || {
+                    wait.recv().ok();
+                    Ok(())
+                })
+                .await
+            }
+        });
+        while pool.load() < 1.0 {
+            tokio::task::yield_now().await;
+        }
+        (release, job)
+    }
+
+    #[tokio::test]
+    async fn job_past_the_queue_timeout_is_busy() {
+        let pool = Arc::new(VerifierPool::new(1, Duration::from_millis(50)));
+        let (release, job) = occupy(&pool).await;
+        assert_eq!(pool.run(|| Ok(())).await, Err(KdcError::Busy));
+        drop(release);
+        job.await.unwrap().unwrap();
+        assert_eq!(pool.run(|| Ok(7)).await, Ok(7));
+    }
+
+    #[tokio::test]
+    async fn load_counts_running_and_waiting_jobs() {
+        let pool = Arc::new(VerifierPool::new(1, Duration::from_secs(5)));
+        assert_eq!(pool.load(), 0.0);
+        let (release, job) = occupy(&pool).await;
+        assert_eq!(pool.load(), 1.0);
+
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| Ok(())).await }
+        });
+        while pool.load() < 2.0 {
+            tokio::task::yield_now().await;
+        }
+        drop(release);
+        job.await.unwrap().unwrap();
+        waiting.await.unwrap().unwrap();
+        assert_eq!(pool.load(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn challenge_difficulty_follows_pool_load() {
+        let challenges = crate::challenge::Challenges::new(Duration::from_secs(60), 16, (8, 20));
+        let pool = Arc::new(VerifierPool::new(1, Duration::from_secs(5)));
+        let difficulty = || challenges.issue(pool.load()).unwrap().difficulty;
+        assert_eq!(difficulty(), 8);
+
+        let (release, job) = occupy(&pool).await;
+        assert_eq!(difficulty(), 14);
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| Ok(())).await }
+        });
+        while pool.load() < 2.0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(difficulty(), 20);
+
+        drop(release);
+        job.await.unwrap().unwrap();
+        waiting.await.unwrap().unwrap();
+        assert_eq!(difficulty(), 8);
+
+        // Past twice the workers the difficulty stays at the maximum.
+        for (load, bits) in [(0.5, 11), (1.5, 17), (4.0, 20), (100.0, 20)] {
+            assert_eq!(challenges.issue(load).unwrap().difficulty, bits, "load {}", load);
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! Ticket verification for services that accept zk-kerberos tickets.
+//!
+//! A client presents the bincode encoding of the [`MessageSent`] it got from
+//! the KDC's `TgsRep`. The service checks it with a [`Verifier`] configured
+//! with the KDC's Ed25519 public keys and its own service name. The KDC
+//! rotates its signing key, so keys are held in a [`KeySet`] by key ID. The
+//! KDC publishes its key set signed with a long-term root key (`KeysReq` or
+//! `GET /v1/keys`); pin the root key and load each set with
+//! [`KeySet::from_published`]:
+//!
+//! ```no_run
+//! use zkk_verifier::{KeySet, Verifier};
+//!
+//! # fn handle(published: &[u8], ticket: &[u8]) -> Result<(), zkk_verifier::VerifyError> {
+//! let root = zkk_verifier::parse_public_key("<KDC root public key>")?;
+//! let service_key = zkk_verifier::parse_service_key("<key shared with the KDC>")?;
+//! let keys = KeySet::from_published(published, &root)?;
+//! let mut verifier = Verifier::new(keys, "webmail", service_key);
+//! // ...and before the set's `expires_at`, after which tickets are refused:
+//! verifier.update_keys(KeySet::from_published(published, &root)?)?;
+//! let ticket = verifier.verify(ticket)?;
+//! println!("session with {:02x?} until {}", ticket.pseudonym, ticket.expires_at);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+use zkk_protocol::{ErrorReply, KdcError, KeySetReply, MessageSent, TicketError, DEFAULT_CLOCK_SKEW};
+
+pub use zkk_protocol::{KeyId, SignBundle};
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("invalid KDC public key: {0}")]
+    BadKey(String),
+    #[error("malformed ticket: {0}")]
+    Decode(#[from] bincode::error::DecodeError),
+    #[error("failed to re-encode ticket: {0}")]
+    Encode(#[from] bincode::error::EncodeError),
+    #[error("ticket is signed with key {0}, which is not in the key set")]
+    UnknownKey(KeyId),
+    #[error("key {0} is not valid at this time")]
+    KeyNotValid(KeyId),
+    #[error("key set signature does not verify under the root key")]
+    BadKeySetSignature,
+    #[error("key set expired at {expires_at} (now {now})")]
+    KeySetExpired { expires_at: u64, now: u64 },
+    #[error("key set issued at {offered} is older than the one in use, issued at {current}")]
+    StaleKeySet { current: u64, offered: u64 },
+    #[error("signature does not verify under the KDC key")]
+    BadSignature,
+    #[error("ticket session key is not sealed under this service's key")]
+    SessionKey,
+    #[error("ticket is for {found:?}, not {expected:?}")]
+    WrongAudience { expected: String, found: String },
+    #[error(transparent)]
+    Validity(#[from] TicketError),
+}
+
+/// A ticket whose signature, audience and validity window have been checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedTicket {
+    pub service: String,
+    /// Key shared between the client and this service for the ticket's lifetime.
+    pub session_key: [u8; 32],
+    /// Stable per user for this service and unrelated to what other services
+    /// see, so it can key per-user state without revealing who the user is.
+    pub pseudonym: [u8; 32],
+    pub issued_at: u64,
+    pub not_before: u64,
+    pub expires_at: u64,
+}
+
+/// The KDC public keys tickets are accepted from, by key ID. A key missing
+/// from the set (e.g. one the KDC retired) fails with
+/// [`VerifyError::UnknownKey`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeySet {
+    keys: BTreeMap<KeyId, TrustedKey>,
+    issued_at: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TrustedKey {
+    key: VerifyingKey,
+    not_before: Option<u64>,
+    not_after: Option<u64>,
+}
+
+impl KeySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks an encoded [`KeySetReply`] against the pinned root key and the system clock.
+    pub fn from_published(reply: &[u8], root: &VerifyingKey) -> Result<Self, VerifyError> {
+        Self::from_published_at(&zkk_protocol::decode(reply)?, root, unix_now())
+    }
+
+    /// Checks `reply` as of `now`. For the JSON form served on `GET /v1/keys`,
+    /// decode `signed` as a `KeySetBundle` and pair it with `signature`.
+    pub fn from_published_at(reply: &KeySetReply, root: &VerifyingKey, now: u64) -> Result<Self, VerifyError> {
+        let bundle = &reply.signature_data;
+        root.verify_strict(&bundle.signing_bytes()?, &Signature::from_bytes(&reply.signature))
+            .map_err(|_| VerifyError::BadKeySetSignature)?;
+        if now >= bundle.expires_at {
+            return Err(VerifyError::KeySetExpired {
+                expires_at: bundle.expires_at,
+                now,
+            });
+        }
+        let mut set = KeySet {
+            issued_at: Some(bundle.issued_at),
+            expires_at: Some(bundle.expires_at),
+            ..KeySet::default()
+        };
+        for published in &bundle.keys {
+            let key = VerifyingKey::from_bytes(&published.public_key).map_err(|e| VerifyError::BadKey(e.to_string()))?;
+            set.keys.insert(
+                published.key_id,
+                TrustedKey {
+                    key,
+                    not_before: Some(published.not_before),
+                    not_after: published.not_after,
+                },
+            );
+        }
+        Ok(set)
+    }
+
+    /// When the KDC published this set, for sets from [`KeySet::from_published`].
+    pub fn issued_at(&self) -> Option<u64> {
+        self.issued_at
+    }
+
+    /// Fetch a new set before this, for sets from [`KeySet::from_published`].
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Reads `<id> <base64 public key>` lines, as printed by
+    /// `zkk_server keys public`. Blank lines and `#` comments are skipped.
+    pub fn parse(text: &str) -> Result<Self, VerifyError> {
+        let mut set = KeySet::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, key) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| VerifyError::BadKey(format!("expected `<id> <key>`, got {:?}", line)))?;
+            let id = id
+                .parse()
+                .map_err(|_| VerifyError::BadKey(format!("invalid key ID {:?}", id)))?;
+            set.insert(id, parse_public_key(key)?);
+        }
+        Ok(set)
+    }
+
+    /// Adds a key that is valid at any time.
+    pub fn insert(&mut self, id: KeyId, key: VerifyingKey) {
+        self.keys.insert(
+            id,
+            TrustedKey {
+                key,
+                not_before: None,
+                not_after: None,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, id: KeyId) -> Option<VerifyingKey> {
+        self.keys.remove(&id).map(|trusted| trusted.key)
+    }
+
+    pub fn get(&self, id: KeyId) -> Option<&VerifyingKey> {
+        self.keys.get(&id).map(|trusted| &trusted.key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Decodes a base64 Ed25519 public key, e.g. the root key printed by `zkk_server keys root`.
+pub fn parse_public_key(base64: &str) -> Result<VerifyingKey, VerifyError> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64.trim())
+        .map_err(|e| VerifyError::BadKey(e.to_string()))?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| VerifyError::BadKey(format!("expected 32 bytes, got {}", b.len())))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| VerifyError::BadKey(e.to_string()))
+}
+
+/// Decodes the base64 long-term key the service shares with the KDC
+/// (`key` under its `[services.<name>]` entry).
+pub fn parse_service_key(base64: &str) -> Result<[u8; 32], VerifyError> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64.trim())
+        .map_err(|e| VerifyError::BadKey(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| VerifyError::BadKey(format!("expected 32 bytes, got {}", b.len())))
+}
+
+/// Kept out of `Debug` output.
+#[derive(Clone)]
+struct ServiceKey([u8; 32]);
+
+impl std::fmt::Debug for ServiceKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ServiceKey(..)")
+    }
+}
+
+/// Checks tickets for one service against the KDC's key set.
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    keys: KeySet,
+    audience: String,
+    service_key: ServiceKey,
+    clock_skew: u64,
+}
+
+impl Verifier {
+    /// `service_key` is the long-term key `audience` shares with the KDC;
+    /// ticket session keys are sealed under it.
+    pub fn new(keys: KeySet, audience: impl Into<String>, service_key: [u8; 32]) -> Self {
+        Verifier {
+            keys,
+            audience: audience.into(),
+            service_key: ServiceKey(service_key),
+            clock_skew: DEFAULT_CLOCK_SKEW,
+        }
+    }
+
+    /// Replaces the key set, e.g. after the KDC rotated or retired a key.
+    pub fn set_keys(&mut self, keys: KeySet) {
+        self.keys = keys;
+    }
+
+    /// Replaces the key set with a newer published one. Sets issued before
+    /// the one in use are refused, so replaying an old set cannot bring back
+    /// a retired key.
+    pub fn update_keys(&mut self, keys: KeySet) -> Result<(), VerifyError> {
+        if let (Some(current), Some(offered)) = (self.keys.issued_at, keys.issued_at) {
+            if offered < current {
+                return Err(VerifyError::StaleKeySet { current, offered });
+            }
+        }
+        self.keys = keys;
+        Ok(())
+    }
+
+    /// Seconds the service's clock may differ from the KDC's. Defaults to
+    /// [`DEFAULT_CLOCK_SKEW`].
+    pub fn with_clock_skew(mut self, seconds: u64) -> Self {
+        self.clock_skew = seconds;
+        self
+    }
+
+    /// Decodes and checks an encoded [`MessageSent`] against the system clock.
+    pub fn verify(&self, ticket: &[u8]) -> Result<VerifiedTicket, VerifyError> {
+        let message: MessageSent = zkk_protocol::decode(ticket)?;
+        self.verify_at(&message, unix_now())
+    }
+
+    /// Checks `message` as of `now` (Unix seconds). Fails with
+    /// [`VerifyError::KeySetExpired`] once a published key set is past its
+    /// `expires_at`, so a service that stops refreshing it stops trusting it.
+    pub fn verify_at(&self, message: &MessageSent, now: u64) -> Result<VerifiedTicket, VerifyError> {
+        if let Some(expires_at) = self.keys.expires_at.filter(|&expires_at| now >= expires_at) {
+            return Err(VerifyError::KeySetExpired { expires_at, now });
+        }
+        let bundle = &message.signature_data;
+        let trusted = self.keys.keys.get(&bundle.key_id).ok_or(VerifyError::UnknownKey(bundle.key_id))?;
+        let early = trusted.not_before.is_some_and(|not_before| now.saturating_add(self.clock_skew) < not_before);
+        let late = trusted.not_after.is_some_and(|not_after| now.saturating_sub(self.clock_skew) >= not_after);
+        if early || late {
+            return Err(VerifyError::KeyNotValid(bundle.key_id));
+        }
+        let signed = bundle.signing_bytes()?;
+        trusted
+            .key
+            .verify_strict(&signed, &Signature::from_bytes(&message.signature))
+            .map_err(|_| VerifyError::BadSignature)?;
+        if bundle.service != self.audience {
+            return Err(VerifyError::WrongAudience {
+                expected: self.audience.clone(),
+                found: bundle.service.clone(),
+            });
+        }
+        bundle.check_validity(now, self.clock_skew)?;
+        let session_key = zkk_protocol::crypto::open(&self.service_key.0, &bundle.sealed_session_key)
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or(VerifyError::SessionKey)?;
+        Ok(VerifiedTicket {
+            service: bundle.service.clone(),
+            session_key,
+            pseudonym: bundle.pseudonym,
+            issued_at: bundle.timestamp,
+            not_before: bundle.not_before,
+            expires_at: bundle.expires_at,
+        })
+    }
+
+    /// Checks the signature on an `ErrorReply` from the KDC and returns the
+    /// error it reports, so a refusal relayed by a client can be trusted.
+    pub fn verify_error(&self, reply: &ErrorReply) -> Result<KdcError, VerifyError> {
+        let bundle = &reply.signature_data;
+        let trusted = self.keys.keys.get(&bundle.key_id).ok_or(VerifyError::UnknownKey(bundle.key_id))?;
+        trusted
+            .key
+            .verify_strict(&bundle.signing_bytes()?, &Signature::from_bytes(&reply.signature))
+            .map_err(|_| VerifyError::BadSignature)?;
+        Ok(bundle.error)
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+    use zkk_protocol::error::ErrorBundle;
+    use zkk_protocol::{KeySetBundle, PublishedKey};
+
+    use super::*;
+
+    const NOW: u64 = 1_792_281_600;
+    const SERVICE_KEY: [u8; 32] = [3; 32];
+
+    fn root() -> SigningKey {
+        SigningKey::from_bytes(&[1; 32])
+    }
+
+    fn kdc_key() -> SigningKey {
+        SigningKey::from_bytes(&[2; 32])
+    }
+
+    /// The KDC key as key 7, published until a day from [`NOW`].
+    fn key_set() -> KeySet {
+        let bundle = KeySetBundle {
+            keys: vec![PublishedKey {
+                key_id: 7,
+                public_key: kdc_key().verifying_key().to_bytes(),
+                not_before: NOW - 3600,
+                not_after: None,
+            }],
+            issued_at: NOW,
+            expires_at: NOW + 86_400,
+        };
+        let signature = root().sign(&bundle.signing_bytes().unwrap()).to_bytes();
+        let reply = KeySetReply { signature, signature_data: bundle };
+        KeySet::from_published_at(&reply, &root().verifying_key(), NOW).unwrap()
+    }
+
+    fn verifier() -> Verifier {
+        Verifier::new(key_set(), "webmail", SERVICE_KEY)
+    }
+
+    /// A webmail ticket signed with key 7, valid for an hour from [`NOW`].
+    fn ticket() -> MessageSent {
+        sign(SignBundle {
+            key_id: 7,
+            sealed_session_key: zkk_protocol::crypto::seal(&SERVICE_KEY, &[4; 32]),
+            pseudonym: [6; 32],
+            service: "webmail".into(),
+            timestamp: NOW,
+            not_before: NOW,
+            expires_at: NOW + 3600,
+        })
+    }
+
+    fn sign(bundle: SignBundle) -> MessageSent {
+        let signature = kdc_key().sign(&bundle.signing_bytes().unwrap()).to_bytes();
+        MessageSent { signature, signature_data: bundle }
+    }
+
+    #[test]
+    fn valid_ticket_is_accepted() {
+        let verified = verifier().verify_at(&ticket(), NOW + 60).unwrap();
+        assert_eq!(
+            verified,
+            VerifiedTicket {
+                service: "webmail".into(),
+                session_key: [4; 32],
+                pseudonym: [6; 32],
+                issued_at: NOW,
+                not_before: NOW,
+                expires_at: NOW + 3600,
+            }
+        );
+    }
+
+    #[test]
+    fn ticket_for_another_service_is_refused() {
+        let verifier = Verifier::new(key_set(), "wifi", SERVICE_KEY);
+        assert!(matches!(
+            verifier.verify_at(&ticket(), NOW),
+            Err(VerifyError::WrongAudience { expected, found }) if expected == "wifi" && found == "webmail"
+        ));
+    }
+
+    #[test]
+    fn ticket_is_checked_against_its_validity_window() {
+        let verifier = verifier().with_clock_skew(60);
+        assert!(verifier.verify_at(&ticket(), NOW - 60).is_ok());
+        assert!(matches!(
+            verifier.verify_at(&ticket(), NOW - 61),
+            Err(VerifyError::Validity(TicketError::NotYetValid { .. }))
+        ));
+        assert!(verifier.verify_at(&ticket(), NOW + 3600 + 59).is_ok());
+        assert!(matches!(
+            verifier.verify_at(&ticket(), NOW + 3600 + 60),
+            Err(VerifyError::Validity(TicketError::Expired { .. }))
+        ));
+    }
+
+    #[test]
+    fn altered_ticket_is_refused() {
+        let mut message = ticket();
+        message.signature_data.pseudonym[0] ^= 1;
+        assert!(matches!(verifier().verify_at(&message, NOW), Err(VerifyError::BadSignature)));
+
+        let forged = SigningKey::from_bytes(&[9; 32]);
+        let mut message = ticket();
+        message.signature = forged.sign(&message.signature_data.signing_bytes().unwrap()).to_bytes();
+        assert!(matches!(verifier().verify_at(&message, NOW), Err(VerifyError::BadSignature)));
+    }
+
+    #[test]
+    fn ticket_under_an_unknown_key_is_refused() {
+        let mut bundle = ticket().signature_data;
+        bundle.key_id = 8;
+        assert!(matches!(verifier().verify_at(&sign(bundle), NOW), Err(VerifyError::UnknownKey(8))));
+
+        let mut keys = key_set();
+        keys.remove(7);
+        let verifier = Verifier::new(keys, "webmail", SERVICE_KEY);
+        assert!(matches!(verifier.verify_at(&ticket(), NOW), Err(VerifyError::UnknownKey(7))));
+    }
+
+    #[test]
+    fn session_key_must_open_under_the_service_key() {
+        let mut bundle = ticket().signature_data;
+        let last = bundle.sealed_session_key.len() - 1;
+        bundle.sealed_session_key[last] ^= 1;
+        assert!(matches!(verifier().verify_at(&sign(bundle), NOW), Err(VerifyError::SessionKey)));
+
+        let verifier = Verifier::new(key_set(), "webmail", [10; 32]);
+        assert!(matches!(verifier.verify_at(&ticket(), NOW), Err(VerifyError::SessionKey)));
+    }
+
+    #[test]
+    fn signed_error_reply_is_checked() {
+        let bundle = ErrorBundle { key_id: 7, error: KdcError::NonceExpired, timestamp: NOW };
+        let signature = kdc_key().sign(&bundle.signing_bytes().unwrap()).to_bytes();
+        let reply = ErrorReply { signature, signature_data: bundle };
+        let decoded: ErrorReply = zkk_protocol::decode(&zkk_protocol::encode(&reply).unwrap()).unwrap();
+        assert_eq!(verifier().verify_error(&decoded).unwrap(), KdcError::NonceExpired);
+
+        let mut forged = decoded.clone();
+        forged.signature_data.error = KdcError::Busy;
+        assert!(matches!(verifier().verify_error(&forged), Err(VerifyError::BadSignature)));
+        let mut forged = decoded;
+        forged.signature[0] ^= 1;
+        assert!(matches!(verifier().verify_error(&forged), Err(VerifyError::BadSignature)));
+    }
+
+    #[test]
+    fn expired_key_set_is_refused() {
+        let verifier = verifier();
+        assert!(verifier.verify_at(&ticket(), NOW).is_ok());
+        assert!(matches!(
+            verifier.verify_at(&ticket(), NOW + 86_400),
+            Err(VerifyError::KeySetExpired { expires_at, .. }) if expires_at == NOW + 86_400
+        ));
+    }
+}